@@ -0,0 +1,457 @@
+//! Writing BCF 2.2, the binary encoding of VCF that bcftools works on
+//! natively.
+//!
+//! A BCF is BGZF-compressed. It starts with the VCF header as text, whose
+//! FILTER, INFO and FORMAT IDs (in order, after PASS) make up the dictionary
+//! of strings and whose contigs make up the dictionary of contigs, which the
+//! records refer to by index. Each record has a shared part, with the columns
+//! of the site and its INFO, and a per-sample part, with each FORMAT field
+//! for every sample in turn.
+//!
+//! Values are typed: a byte whose low nibble is the type and whose high
+//! nibble is the number of values (15 meaning the number follows as a typed
+//! integer), then the values. Each type reserves values for missing.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use crate::bgzf::BgzfWriter;
+use crate::pfile::{info_end, PgenError};
+use crate::pvar_parser::{attribute, structured_line};
+
+const TYPE_NULL: u8 = 0;
+const TYPE_INT8: u8 = 1;
+const TYPE_INT16: u8 = 2;
+const TYPE_INT32: u8 = 3;
+const TYPE_FLOAT: u8 = 5;
+const TYPE_CHAR: u8 = 7;
+
+/// The NaN that marks a missing float.
+const MISSING_FLOAT: u32 = 0x7F80_0001;
+
+const PASS_LINE: &str = "##FILTER=<ID=PASS,Description=\"All filters passed\">";
+
+/// The header of a BCF, with the dictionaries its records refer to.
+pub struct BcfHeader {
+    meta: Vec<String>,
+    /// Index of each FILTER, INFO and FORMAT ID.
+    strings: HashMap<String, usize>,
+    contigs: HashMap<String, usize>,
+    filters: HashSet<String>,
+    /// The declared Type of each INFO key.
+    info_types: HashMap<String, String>,
+}
+
+impl BcfHeader {
+    /// Builds the dictionaries from the `##` lines of a VCF header.
+    pub fn new(meta: &str) -> BcfHeader {
+        let mut header = BcfHeader {
+            meta: Vec::new(),
+            // PASS is always first, like htslib does
+            strings: HashMap::from([("PASS".to_string(), 0)]),
+            contigs: HashMap::new(),
+            filters: HashSet::new(),
+            info_types: HashMap::new(),
+        };
+        for line in meta.lines() {
+            header.push_line(line.to_string());
+        }
+        if !header.filters.contains("PASS") {
+            header.filters.insert("PASS".to_string());
+            let idx = header.meta.len().min(1);
+            header.meta.insert(idx, PASS_LINE.to_string());
+        }
+        header
+    }
+
+    fn push_line(&mut self, line: String) {
+        if let Some((kind, attributes)) = structured_line(&line) {
+            if let Some(id) = attribute(attributes, "ID") {
+                match kind {
+                    "contig" => {
+                        let contig_idx = self.contigs.len();
+                        self.contigs.entry(id.to_string()).or_insert(contig_idx);
+                    }
+                    "FILTER" | "INFO" | "FORMAT" => {
+                        let string_idx = self.strings.len();
+                        self.strings.entry(id.to_string()).or_insert(string_idx);
+                        if kind == "FILTER" {
+                            self.filters.insert(id.to_string());
+                        } else if kind == "INFO" {
+                            let info_type = attribute(attributes, "Type").unwrap_or("String");
+                            self.info_types
+                                .insert(id.to_string(), info_type.to_string());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.meta.push(line);
+    }
+
+    /// Declares the contig, filters and INFO keys of a site that the header
+    /// lacks, since a BCF can only refer to what its header declares.
+    pub fn declare(&mut self, site: &Site) {
+        if !self.contigs.contains_key(site.chrom) {
+            self.push_line(format!("##contig=<ID={}>", site.chrom));
+        }
+        for filter in filters(site.filter) {
+            if !self.filters.contains(filter) {
+                self.push_line(format!(
+                    "##FILTER=<ID={},Description=\"{}\">",
+                    filter, filter
+                ));
+            }
+        }
+        for (key, value) in info_entries(&site.info) {
+            if !self.info_types.contains_key(key) {
+                // like bcftools, keys without a value are taken to be flags
+                let (number, info_type) = match value {
+                    Some(_) => (".", "String"),
+                    None => ("0", "Flag"),
+                };
+                self.push_line(format!(
+                    "##INFO=<ID={},Number={},Type={},Description=\"{}\">",
+                    key, number, info_type, key
+                ));
+            }
+        }
+    }
+}
+
+/// The columns of a VCF line up to INFO, as text.
+pub struct Site<'a> {
+    pub chrom: &'a str,
+    pub pos: &'a str,
+    pub id: &'a str,
+    pub reference: &'a str,
+    pub alt: &'a str,
+    pub qual: &'a str,
+    pub filter: &'a str,
+    pub info: Cow<'a, str>,
+}
+
+/// The values of a FORMAT field for every sample.
+pub enum FormatValues {
+    /// The alleles of each sample's GT, if not missing, and whether it is
+    /// phased.
+    Genotypes(Vec<Option<(u32, u32, bool)>>),
+    /// `per_sample` floats for each sample.
+    Floats {
+        per_sample: usize,
+        values: Vec<Option<f32>>,
+    },
+}
+
+pub struct BcfWriter<W: Write> {
+    writer: BgzfWriter<W>,
+    header: BcfHeader,
+    num_samples: usize,
+    /// The shared and per-sample parts of the record being written, kept to
+    /// reuse their allocations.
+    shared: Vec<u8>,
+    indiv: Vec<u8>,
+}
+
+impl<W: Write> BcfWriter<W> {
+    /// Writes the header, with the standard columns and the given samples.
+    pub fn new(inner: W, header: BcfHeader, sample_ids: &[String]) -> Result<Self, PgenError> {
+        let mut text = header.meta.join("\n");
+        text.push_str("\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT");
+        for sample_id in sample_ids {
+            text.push('\t');
+            text.push_str(sample_id);
+        }
+        text.push_str("\n\0");
+
+        let mut writer = BgzfWriter::new(inner);
+        writer.write_all(b"BCF\x02\x02")?;
+        writer.write_all(&(text.len() as u32).to_le_bytes())?;
+        writer.write_all(text.as_bytes())?;
+        Ok(BcfWriter {
+            writer,
+            header,
+            num_samples: sample_ids.len(),
+            shared: Vec::new(),
+            indiv: Vec::new(),
+        })
+    }
+
+    /// Writes a record. The site must have been declared to the header, and
+    /// the FORMAT keys too.
+    pub fn write_record(
+        &mut self,
+        site: &Site,
+        format: &[(&str, FormatValues)],
+    ) -> Result<(), PgenError> {
+        let unencodable = PgenError::Unencodable;
+        let contig_idx = self
+            .header
+            .contigs
+            .get(site.chrom)
+            .copied()
+            .ok_or_else(|| unencodable(format!("{} is not a contig of the header", site.chrom)))?;
+        let pos = site
+            .pos
+            .parse::<i32>()
+            .ok()
+            .filter(|&pos| pos > 0)
+            .ok_or_else(|| unencodable(format!("invalid position {}", site.pos)))?;
+        // records span their REF allele, unless they give an END
+        let rlen = info_end(&site.info)
+            .and_then(|end| i32::try_from(end).ok())
+            .map_or(site.reference.len() as i32, |end| end - pos + 1);
+        let alleles = std::iter::once(site.reference)
+            .chain(site.alt.split(',').filter(|_| site.alt != "."))
+            .collect::<Vec<&str>>();
+        let info = info_entries(&site.info).collect::<Vec<(&str, Option<&str>)>>();
+
+        let shared = &mut self.shared;
+        shared.clear();
+        shared.extend_from_slice(&(contig_idx as i32).to_le_bytes());
+        shared.extend_from_slice(&(pos - 1).to_le_bytes());
+        shared.extend_from_slice(&rlen.to_le_bytes());
+        shared.extend_from_slice(
+            &parse_float(site.qual)
+                .map_or(MISSING_FLOAT, f32::to_bits)
+                .to_le_bytes(),
+        );
+        shared.extend_from_slice(&((alleles.len() as u32) << 16 | info.len() as u32).to_le_bytes());
+        shared.extend_from_slice(
+            &((format.len() as u32) << 24 | self.num_samples as u32).to_le_bytes(),
+        );
+        write_string(shared, if site.id == "." { "" } else { site.id });
+        for allele in alleles {
+            write_string(shared, allele);
+        }
+        let filter_idxs = filters(site.filter)
+            .map(|filter| self.header.strings.get(filter).map(|&idx| idx as i32))
+            .collect::<Vec<Option<i32>>>();
+        write_ints(shared, &filter_idxs);
+        for (key, value) in info {
+            write_ints(
+                shared,
+                &[self.header.strings.get(key).map(|&idx| idx as i32)],
+            );
+            let values = value.unwrap_or(".").split(',');
+            match self.header.info_types.get(key).map(String::as_str) {
+                Some("Flag") => shared.push(TYPE_NULL),
+                Some("Integer") => write_ints(shared, &values.map(parse_int).collect::<Vec<_>>()),
+                Some("Float") => write_floats(shared, &values.map(parse_float).collect::<Vec<_>>()),
+                _ => write_string(shared, value.unwrap_or_default()),
+            }
+        }
+
+        let indiv = &mut self.indiv;
+        indiv.clear();
+        for (key, values) in format {
+            write_ints(
+                indiv,
+                &[self.header.strings.get(*key).map(|&idx| idx as i32)],
+            );
+            match values {
+                FormatValues::Genotypes(genotypes) => {
+                    // each allele is stored as (allele + 1) << 1, 0 being
+                    // missing, with the low bit of the second allele set
+                    // if it is phased
+                    let values = genotypes
+                        .iter()
+                        .flat_map(|genotype| match *genotype {
+                            Some((first, second, phased)) => [
+                                Some((first as i32 + 1) << 1),
+                                Some((second as i32 + 1) << 1 | phased as i32),
+                            ],
+                            None => [Some(0), Some(0)],
+                        })
+                        .collect::<Vec<Option<i32>>>();
+                    let value_type = int_type(&values);
+                    write_type(indiv, 2, value_type);
+                    write_int_values(indiv, value_type, &values);
+                }
+                FormatValues::Floats { per_sample, values } => {
+                    write_type(indiv, *per_sample, TYPE_FLOAT);
+                    write_float_values(indiv, values);
+                }
+            }
+        }
+
+        self.writer
+            .write_all(&(self.shared.len() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&(self.indiv.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&self.shared)?;
+        self.writer.write_all(&self.indiv)?;
+        Ok(())
+    }
+
+    /// Writes the last block and the end-of-file marker.
+    pub fn finish(self) -> Result<W, PgenError> {
+        Ok(self.writer.finish()?)
+    }
+}
+
+fn filters(filter: &str) -> impl Iterator<Item = &str> {
+    filter
+        .split(';')
+        .filter(|filter| *filter != "." && !filter.is_empty())
+}
+
+/// The keys of an INFO column, with their values if they aren't flags.
+fn info_entries(info: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    info.split(';')
+        .filter(|entry| *entry != "." && !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (entry, None),
+        })
+}
+
+/// Parses an integer, treating those too close to i32::MIN as missing since
+/// they are reserved.
+fn parse_int(value: &str) -> Option<i32> {
+    value
+        .parse::<i32>()
+        .ok()
+        .filter(|&value| value > i32::MIN + 7)
+}
+
+fn parse_float(value: &str) -> Option<f32> {
+    value.parse::<f32>().ok()
+}
+
+fn write_type(buf: &mut Vec<u8>, len: usize, value_type: u8) {
+    if len < 15 {
+        buf.push((len as u8) << 4 | value_type);
+    } else {
+        buf.push(0xF0 | value_type);
+        write_ints(buf, &[Some(len as i32)]);
+    }
+}
+
+/// The smallest integer type that can hold the values, whose 8 lowest values
+/// are reserved.
+fn int_type(values: &[Option<i32>]) -> u8 {
+    let (min, max) = values.iter().flatten().fold((0, 0), |(min, max), &value| {
+        (value.min(min), value.max(max))
+    });
+    if min >= i8::MIN as i32 + 8 && max <= i8::MAX as i32 {
+        TYPE_INT8
+    } else if min >= i16::MIN as i32 + 8 && max <= i16::MAX as i32 {
+        TYPE_INT16
+    } else {
+        TYPE_INT32
+    }
+}
+
+/// Writes integers of the given type, the smallest value of the type being
+/// missing.
+fn write_int_values(buf: &mut Vec<u8>, value_type: u8, values: &[Option<i32>]) {
+    for value in values {
+        match value_type {
+            TYPE_INT8 => buf.push(value.map_or(i8::MIN, |value| value as i8) as u8),
+            TYPE_INT16 => {
+                buf.extend_from_slice(&value.map_or(i16::MIN, |value| value as i16).to_le_bytes())
+            }
+            _ => buf.extend_from_slice(&value.unwrap_or(i32::MIN).to_le_bytes()),
+        }
+    }
+}
+
+fn write_ints(buf: &mut Vec<u8>, values: &[Option<i32>]) {
+    if values.is_empty() {
+        buf.push(TYPE_NULL);
+        return;
+    }
+    let value_type = int_type(values);
+    write_type(buf, values.len(), value_type);
+    write_int_values(buf, value_type, values);
+}
+
+fn write_float_values(buf: &mut Vec<u8>, values: &[Option<f32>]) {
+    for value in values {
+        buf.extend_from_slice(&value.map_or(MISSING_FLOAT, f32::to_bits).to_le_bytes());
+    }
+}
+
+fn write_floats(buf: &mut Vec<u8>, values: &[Option<f32>]) {
+    write_type(buf, values.len(), TYPE_FLOAT);
+    write_float_values(buf, values);
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_type(buf, value.len(), TYPE_CHAR);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    fn read_u32(bytes: &[u8]) -> usize {
+        u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut header = BcfHeader::new(concat!(
+            "##fileformat=VCFv4.2\n",
+            "##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Depth\">\n",
+            "##INFO=<ID=AF,Number=A,Type=Float,Description=\"Frequency\">\n",
+            "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">",
+        ));
+        let site = Site {
+            chrom: "1",
+            pos: "100",
+            id: "rs1",
+            reference: "A",
+            alt: "G,T",
+            qual: "30.5",
+            filter: "q10",
+            info: Cow::Borrowed("DP=12;AF=0.25,.;DB"),
+        };
+        // the contig, the q10 filter and the DB flag get declared
+        header.declare(&site);
+        let sample_ids = ["a".to_string(), "b".to_string()];
+        let mut writer = BcfWriter::new(Vec::new(), header, &sample_ids).unwrap();
+        let genotypes = FormatValues::Genotypes(vec![Some((0, 1, true)), None]);
+        writer.write_record(&site, &[("GT", genotypes)]).unwrap();
+        let output = writer.finish().unwrap();
+
+        let mut bcf = Vec::new();
+        MultiGzDecoder::new(&output[..])
+            .read_to_end(&mut bcf)
+            .unwrap();
+        assert_eq!(bcf[..5], *b"BCF\x02\x02");
+        let text_len = read_u32(&bcf[5..]);
+        let text = std::str::from_utf8(&bcf[9..9 + text_len]).unwrap();
+        assert!(text.starts_with("##fileformat=VCFv4.2\n##FILTER=<ID=PASS,"));
+        assert!(text.contains("\n##contig=<ID=1>\n##FILTER=<ID=q10,"));
+        assert!(text.ends_with("\tINFO\tFORMAT\ta\tb\n\0"));
+
+        let record = &bcf[9 + text_len..];
+        let (shared_len, indiv_len) = (read_u32(record), read_u32(&record[4..]));
+        assert_eq!(record.len(), 8 + shared_len + indiv_len);
+        // the dictionary of strings is PASS, DP, AF, GT, q10 and DB
+        let mut shared = Vec::new();
+        for value in [0i32, 99, 1] {
+            shared.extend_from_slice(&value.to_le_bytes());
+        }
+        shared.extend_from_slice(&30.5f32.to_le_bytes());
+        shared.extend_from_slice(&(3u32 << 16 | 3).to_le_bytes());
+        shared.extend_from_slice(&(1u32 << 24 | 2).to_le_bytes());
+        shared.extend_from_slice(b"\x37rs1\x17A\x17G\x17T");
+        shared.extend_from_slice(&[0x11, 4]);
+        shared.extend_from_slice(&[0x11, 1, 0x11, 12]);
+        shared.extend_from_slice(&[0x11, 2, 0x25]);
+        shared.extend_from_slice(&0.25f32.to_le_bytes());
+        shared.extend_from_slice(&MISSING_FLOAT.to_le_bytes());
+        shared.extend_from_slice(&[0x11, 5, 0x00]);
+        assert_eq!(record[8..8 + shared_len], shared);
+        // 0|1 as (0 + 1) << 1 and (1 + 1) << 1 | 1, and a missing call
+        assert_eq!(record[8 + shared_len..], [0x11, 3, 0x21, 2, 5, 0, 0]);
+    }
+}
@@ -0,0 +1,80 @@
+//! Writing PLINK 1 .bed files.
+//!
+//! A .bed is the 3 bytes 0x6C 0x1B 0x01 (the last marking it SNP-major),
+//! then for each variant one 2-bit code per sample, packed low bits first
+//! like a .pgen's hardcalls, each variant starting on a new byte. Only the
+//! meaning of the codes differs, with A1 being the ALT allele of the .bim:
+//!
+//! ```text
+//! code  .pgen     .bed
+//! 0     hom ref   hom A1 (hom alt)
+//! 1     het       missing
+//! 2     hom alt   het
+//! 3     missing   hom A2 (hom ref)
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::pfile::PgenError;
+use crate::pgen_record::{clear_genovec_padding, genovec_get, genovec_set, Genotypes};
+
+const BED_MAGIC: [u8; 3] = [0x6C, 0x1B, 0x01];
+
+/// The .bed code of each .pgen hardcall code.
+const BED_CODES: [u8; 4] = [0b11, 0b10, 0b00, 0b01];
+
+/// Writes the hardcalls of a subset of a pfile's samples, one biallelic
+/// variant at a time, to a new .bed.
+pub struct BedWriter {
+    bed: BufWriter<File>,
+    /// The .bed byte of each .pgen byte of 4 hardcalls.
+    bed_bytes: [u8; 256],
+}
+
+impl BedWriter {
+    pub fn create(path: &str) -> Result<BedWriter, PgenError> {
+        let mut bed = BufWriter::new(File::create(path)?);
+        bed.write_all(&BED_MAGIC)?;
+        let mut bed_bytes = [0u8; 256];
+        for (byte, bed_byte) in bed_bytes.iter_mut().enumerate() {
+            for sam_idx in 0..4 {
+                let code = genovec_get(&[byte as u8], sam_idx);
+                genovec_set(
+                    std::slice::from_mut(bed_byte),
+                    sam_idx,
+                    BED_CODES[code as usize],
+                );
+            }
+        }
+        Ok(BedWriter { bed, bed_bytes })
+    }
+
+    /// Writes the hardcalls of the samples in `sam_idxs`, which must be
+    /// biallelic since a .bed can't hold the patches of multiallelic calls.
+    pub fn write_variant(
+        &mut self,
+        genotypes: &Genotypes,
+        sam_idxs: &[usize],
+    ) -> Result<(), PgenError> {
+        let mut row = vec![0u8; sam_idxs.len().div_ceil(4)];
+        for (new_idx, &sam_idx) in sam_idxs.iter().enumerate() {
+            genovec_set(
+                &mut row,
+                new_idx,
+                genovec_get(&genotypes.hardcalls, sam_idx),
+            );
+        }
+        for byte in &mut row {
+            *byte = self.bed_bytes[*byte as usize];
+        }
+        clear_genovec_padding(&mut row, sam_idxs.len() as u32);
+        self.bed.write_all(&row)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), PgenError> {
+        self.bed.flush()?;
+        Ok(())
+    }
+}
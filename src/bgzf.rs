@@ -0,0 +1,140 @@
+//! Writing BGZF, the blocked gzip of .vcf.gz files, which htslib can seek in.
+//!
+//! Each block is a gzip member of its own, holding at most `MAX_BLOCK_SIZE`
+//! bytes of input, with an extra header field giving its compressed size. A
+//! position in the output is a "virtual offset": the offset of the block it
+//! is in, shifted left by 16, plus the offset within the uncompressed block.
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io;
+use std::io::Write;
+
+/// Most input bytes in a block, small enough that even incompressible input
+/// fits the 64KiB limit on a block once compressed.
+const MAX_BLOCK_SIZE: usize = 0xFF00;
+
+/// The empty block marking the end of a BGZF file.
+const EOF_BLOCK: [u8; 28] = [
+    0x1F, 0x8B, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x06, 0x00, 0x42, 0x43, 0x02,
+    0x00, 0x1B, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    /// Input not yet compressed into a block.
+    block: Vec<u8>,
+    /// Offset in the output of the next block.
+    block_offset: u64,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner,
+            block: Vec::with_capacity(MAX_BLOCK_SIZE),
+            block_offset: 0,
+        }
+    }
+
+    /// The virtual offset of the next byte written.
+    pub fn virtual_offset(&self) -> u64 {
+        (self.block_offset << 16) | self.block.len() as u64
+    }
+
+    /// Compresses the pending input into a block.
+    fn write_block(&mut self) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.block)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.block);
+
+        // 18 bytes of header, then the deflated data, its CRC and its length
+        let block_size = 18 + compressed.len() + 8;
+        let mut header = [
+            0x1F, 0x8B, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00, 0x00, 0x00,
+        ];
+        header[16..].copy_from_slice(&(block_size as u16 - 1).to_le_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(self.block.len() as u32).to_le_bytes())?;
+
+        self.block_offset += block_size as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the last block and the end-of-file marker, giving back the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..len]);
+        if self.block.len() == MAX_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn blocks_round_trip() {
+        // enough poorly compressible input for three blocks
+        let input = (0..2 * MAX_BLOCK_SIZE as u32 + 100)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect::<Vec<u8>>();
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&input[..10]).unwrap();
+        assert_eq!(writer.virtual_offset(), 10);
+        writer.write_all(&input[10..]).unwrap();
+        let output = writer.finish().unwrap();
+
+        // each block gives its own size, and the last is the EOF marker
+        let mut block_sizes = Vec::new();
+        let mut block_start = 0;
+        while block_start < output.len() {
+            let block = &output[block_start..];
+            assert_eq!(block[..4], [0x1F, 0x8B, 0x08, 0x04]);
+            let block_size = u16::from_le_bytes([block[16], block[17]]) as usize + 1;
+            let input_size =
+                u32::from_le_bytes(block[block_size - 4..block_size].try_into().unwrap());
+            block_sizes.push(input_size as usize);
+            block_start += block_size;
+        }
+        assert_eq!(block_start, output.len());
+        assert_eq!(block_sizes, [MAX_BLOCK_SIZE, MAX_BLOCK_SIZE, 100, 0]);
+        assert_eq!(output[output.len() - EOF_BLOCK.len()..], EOF_BLOCK);
+
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&output[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, input);
+    }
+}
@@ -1,12 +1,14 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use pgen_rs::{IndexFormat, PgenError, Regions, SampleList, VariantList};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
-    pub command: Option<Commands>,
+    pub command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
@@ -15,17 +17,34 @@ pub enum Commands {
     ///
     /// All expressions have as variables the metadata being queried. For
     /// example, if querying the variants, CHROM and ID are variables which
-    /// contain their respective values. This applies both for the expressions
-    /// in the fstring and query.
+    /// contain their respective values, and INFO/KEY is the value of KEY in
+    /// the INFO column. This applies both for the query and for the fields of
+    /// the fstring, like %CHROM and %INFO/KEY. Variant expressions can also
+    /// use the genotypes: GT and DS are the GT and dosage of each sample
+    /// (GT[IID] and DS[IID] of the sample IID), and N_HET, N_HOMALT,
+    /// N_MISSING, AC, AN and AF are computed over all samples. See the README
+    /// for the expression language.
     Query {
         /// The prefix of the pgen file triples. There should be three files
-        /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar.
+        /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar, though
+        /// PFILE_PREFIX.pvar.zst or PFILE_PREFIX.bim are read in place of a
+        /// missing .pvar, and PFILE_PREFIX.psam.zst or PFILE_PREFIX.fam in
+        /// place of a missing .psam.
         pfile_prefix: String,
 
         #[arg(short = 'f', long = "fstring")]
-        /// An expression specifying what to output to stdout.
+        /// A format string specifying what to output to stdout for each kept
+        /// variant or sample, like '%CHROM\t%POS[\t%SAMPLE=%GT]\n' in
+        /// bcftools. Text is output as is, except for the escapes \t, \n and
+        /// \r, each %FIELD is replaced by its value, and [...] is repeated for
+        /// each sample, with %SAMPLE its IID.
         query_fstring: String,
 
+        #[arg(long = "expression")]
+        /// When passed, the fstring is an expression instead, like
+        /// 'CHROM + " " + POS', whose value is output on its own line.
+        fstring_expression: bool,
+
         #[arg(short = 'i', long = "include")]
         /// An expression specifying which variants (default) or samples (if -s
         /// is passed) to keep.
@@ -34,15 +53,26 @@ pub enum Commands {
         /// When passed, the query is over the samples. Otherwise it is over the
         /// variants. Defaults false.
         query_samples: bool,
+
+        #[command(flatten)]
+        regions: RegionArgs,
+
+        #[command(flatten)]
+        id_lists: IdListArgs,
     },
-    /// Filters the pgen, outputting to a VCF.
+    /// Filters the pgen, outputting to a VCF or a new pfile.
     ///
     /// All expressions have as variables the variant metadata. For example, if
     /// querying the variants, CHROM and ID are variables which contain their
-    /// respective values.
+    /// respective values. Like in `query`, the variant expression can also use
+    /// INFO/KEY and the genotype variables GT, DS, N_HET, N_HOMALT, N_MISSING,
+    /// AC, AN and AF.
     Filter {
         /// The prefix of the pgen file triples. There should be three files
-        /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar.
+        /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar, though
+        /// PFILE_PREFIX.pvar.zst or PFILE_PREFIX.bim are read in place of a
+        /// missing .pvar, and PFILE_PREFIX.psam.zst or PFILE_PREFIX.fam in
+        /// place of a missing .psam.
         pfile_prefix: String,
 
         #[arg(long = "include-var")]
@@ -55,8 +85,237 @@ pub enum Commands {
         /// keeps all samples.
         sam_query: Option<String>,
 
+        #[command(flatten)]
+        regions: RegionArgs,
+
+        #[command(flatten)]
+        id_lists: IdListArgs,
+
         #[arg(short = 'o', long = "out")]
-        /// The output file name (defaults to PFILE_PREFIX.pgen-rs.vcf)
+        /// The output file name (defaults to PFILE_PREFIX.pgen-rs.vcf, or
+        /// PFILE_PREFIX.pgen-rs.vcf.gz for vcf.gz output). For pgen and bed
+        /// output, the prefix of the output files (defaults to
+        /// PFILE_PREFIX.pgen-rs).
         out_file: Option<PathBuf>,
+
+        #[arg(long = "out-format", value_enum, default_value_t = OutFormat::Vcf)]
+        /// The format to output.
+        out_format: OutFormat,
+
+        #[arg(long = "index", value_enum)]
+        /// For vcf.gz output, also writes an index of this format next to
+        /// the output (OUT_FILE.tbi or OUT_FILE.csi).
+        index_format: Option<IndexFormatArg>,
+
+        #[arg(long = "dosage")]
+        /// When passed, also outputs the dosages of the first ALT allele as the
+        /// DS FORMAT field, and the phased dosages as HDS for variants that
+        /// have them. Pgen output always keeps the dosages.
+        dosage: bool,
+    },
+    /// Converts a VCF or a PLINK 1 fileset into a pfile.
+    ///
+    /// From a VCF, the .pvar gets the variant columns and the header lines,
+    /// and the .pgen the GT calls. From a .bed, .bim and .fam, the .pvar and
+    /// .psam get the columns of the .bim and .fam.
+    Import {
+        /// The VCF to import, plain or compressed with bgzip or gzip. For bed
+        /// input, the prefix of the .bed, .bim and .fam.
+        input: String,
+
+        #[arg(long = "in-format", value_enum, default_value_t = InFormat::Vcf)]
+        /// The format of the input.
+        in_format: InFormat,
+
+        #[arg(short = 'o', long = "out")]
+        /// The prefix of the output pfile (defaults to the VCF's path without
+        /// its .vcf or .vcf.gz extension, or to the prefix of the .bed).
+        out_prefix: Option<String>,
+    },
+    /// Indexes the .pvar of a pfile, for finding variants by region or ID.
+    ///
+    /// The index is written next to the .pvar, e.g. as
+    /// PFILE_PREFIX.pvar.pgrsi.
+    /// `query` and `filter` then use it for -r, -R and queries that only hold
+    /// for some IDs, like 'ID == "rs1" || ID == "rs2"', instead of reading
+    /// the whole .pvar. An index is ignored once the .pvar changes, until it
+    /// is rebuilt.
+    Index {
+        /// The prefix of the pgen file triples, as in `query`. A .pvar.zst
+        /// can't be indexed.
+        pfile_prefix: String,
     },
+    /// Starts the web interface.
+    ///
+    /// The paths submitted through the web interface, i.e. pfile prefixes,
+    /// output files and regions and ID list files, are resolved relative to
+    /// the data root, and rejected if they lead outside of it.
+    Serve {
+        #[arg(long = "address", default_value = "127.0.0.1")]
+        /// The address to bind the server to.
+        address: String,
+
+        #[arg(short = 'p', long = "port", default_value_t = 8080)]
+        /// The port to listen on.
+        port: u16,
+
+        #[arg(long = "data-root", default_value = ".")]
+        /// The directory that the paths of the web interface are resolved
+        /// against.
+        data_root: PathBuf,
+    },
+}
+
+/// The regions that `query` and `filter` restrict the variants to, like
+/// bcftools' -r and -R.
+#[derive(Args, Debug)]
+pub struct RegionArgs {
+    #[arg(short = 'r', long = "regions", conflicts_with = "regions_file")]
+    /// Only keeps the variants in these comma-separated regions, each CHROM,
+    /// CHROM:POS, CHROM:BEG-END or CHROM:BEG- with 1-based inclusive
+    /// positions, e.g. chr1:1000-2000,chr2. Contig names match with or
+    /// without a chr prefix.
+    pub regions: Option<String>,
+
+    #[arg(short = 'R', long = "regions-file")]
+    /// Like -r, with the regions read from a file, plain or gzipped, with a
+    /// region per line: CHROM, BEG and END 0-based and half-open in a .bed,
+    /// or otherwise CHROM and POS, or CHROM, BEG and END, tab-separated,
+    /// 1-based and inclusive.
+    pub regions_file: Option<String>,
+}
+
+impl RegionArgs {
+    /// The regions of -r or -R, if either was passed.
+    pub fn regions(&self) -> Result<Option<Regions>, PgenError> {
+        match (&self.regions, &self.regions_file) {
+            (Some(regions), _) => Regions::parse(regions).map(Some),
+            (None, Some(regions_file)) => Regions::from_file(regions_file).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// The sample and variant ID lists that `query` and `filter` restrict the
+/// samples and variants to, like plink2's --keep, --remove, --extract and
+/// --exclude.
+#[derive(Args, Debug)]
+pub struct IdListArgs {
+    #[arg(long = "keep")]
+    /// Only keeps the samples listed in this file, a sample per line: its
+    /// IID, or its FID and IID, or the columns named by a header line
+    /// starting with #FID or #IID. FIDs are ignored if the .psam has none.
+    /// When querying variants, restricts the samples of [...] blocks.
+    pub keep: Option<String>,
+
+    #[arg(long = "remove")]
+    /// Like --keep, but removes the listed samples.
+    pub remove: Option<String>,
+
+    #[arg(long = "extract")]
+    /// Only keeps the variants whose ID is listed in this file, with the IDs
+    /// separated by any whitespace.
+    pub extract: Option<String>,
+
+    #[arg(long = "exclude")]
+    /// Like --extract, but removes the listed variants.
+    pub exclude: Option<String>,
+}
+
+impl IdListArgs {
+    /// The lists of --keep and --remove, if passed.
+    pub fn sample_lists(&self) -> Result<(Option<SampleList>, Option<SampleList>), PgenError> {
+        let read = |path: &Option<String>| path.as_deref().map(SampleList::from_file).transpose();
+        Ok((read(&self.keep)?, read(&self.remove)?))
+    }
+
+    /// The lists of --extract and --exclude, if passed.
+    pub fn variant_lists(&self) -> Result<(Option<VariantList>, Option<VariantList>), PgenError> {
+        let read = |path: &Option<String>| path.as_deref().map(VariantList::from_file).transpose();
+        Ok((read(&self.extract)?, read(&self.exclude)?))
+    }
+}
+
+/// The formats `filter` can output.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutFormat {
+    /// A plain text VCF.
+    Vcf,
+    /// A BGZF-compressed VCF, like bgzip produces.
+    #[value(name = "vcf.gz")]
+    VcfGz,
+    /// A BCF 2.2, the binary VCF that bcftools works on natively.
+    Bcf,
+    /// A .pgen, .pvar and .psam triple.
+    Pgen,
+    /// A PLINK 1 .bed, .bim and .fam triple, with only the hardcalls of
+    /// biallelic variants.
+    Bed,
+}
+
+/// The kinds of index `filter` can write next to a .vcf.gz.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFormatArg {
+    /// A tabix index, for positions below 2^29.
+    Tbi,
+    /// A CSI index, for larger positions.
+    Csi,
+}
+
+impl From<IndexFormatArg> for IndexFormat {
+    fn from(index_format: IndexFormatArg) -> IndexFormat {
+        match index_format {
+            IndexFormatArg::Tbi => IndexFormat::Tbi,
+            IndexFormatArg::Csi => IndexFormat::Csi,
+        }
+    }
+}
+
+/// The formats `import` can read.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InFormat {
+    /// A VCF, plain or compressed.
+    Vcf,
+    /// A PLINK 1 .bed, .bim and .fam triple.
+    Bed,
+}
+
+impl Commands {
+    /// Checks the combinations of arguments that clap can't.
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        if let Commands::Query {
+            query_samples: true,
+            regions,
+            id_lists,
+            ..
+        } = self
+        {
+            if regions.regions.is_some() || regions.regions_file.is_some() {
+                return Err(Cli::command().error(
+                    ErrorKind::ArgumentConflict,
+                    "-r and -R can only be used when querying variants",
+                ));
+            }
+            if id_lists.extract.is_some() || id_lists.exclude.is_some() {
+                return Err(Cli::command().error(
+                    ErrorKind::ArgumentConflict,
+                    "--extract and --exclude can only be used when querying variants",
+                ));
+            }
+        }
+        if let Commands::Filter {
+            out_format,
+            index_format: Some(_),
+            ..
+        } = self
+        {
+            if *out_format != OutFormat::VcfGz {
+                return Err(Cli::command().error(
+                    ErrorKind::ArgumentConflict,
+                    "--index can only be used with --out-format vcf.gz",
+                ));
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,810 @@
+//! Evaluating the expressions of `query` and `filter`.
+//!
+//! Values are typed: numbers compare as numbers, strings as strings, and a
+//! string compared with a number is parsed as one. Fields holding several
+//! values, like a multiallelic ALT, are lists, and a comparison holds for a
+//! list if it holds for any of its values. Per-sample values, like `GT`,
+//! are compared sample by sample, and like bcftools
+//!
+//! ```text
+//! GT = "het" & DS > 0.5     holds if one sample satisfies both
+//! GT = "het" && DS > 0.5    holds if some samples satisfy each
+//! ```
+//!
+//! while a row passes a filter if it holds for any sample.
+
+use std::fmt;
+
+use crate::filter_parser::{BinaryOp, Expr, ExprKind, FilterParser, Function, Span, Variable};
+
+/// A parsed expression, along with its source.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    source: String,
+    tree: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A `.` field, or one that isn't there.
+    Missing,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    /// The values of a field holding several, e.g. the alleles of ALT.
+    List(Vec<Value>),
+    /// One value for each sample.
+    Samples(Vec<Value>),
+    /// A sample's hardcall, missing if `alleles` is None.
+    Genotype {
+        alleles: Option<(u32, u32)>,
+        phased: bool,
+    },
+}
+
+/// A syntax error, or an error evaluating part of an expression.
+#[derive(Debug, Clone)]
+pub struct ExpressionError {
+    pub message: String,
+    /// The part of the expression at fault.
+    pub span: Span,
+}
+
+/// The variables an expression is evaluated with.
+pub trait Scope {
+    /// The value of a variable, or why it has none.
+    fn value(&self, variable: &Variable) -> Result<Value, String>;
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, ExpressionError> {
+        Ok(Expression {
+            source: source.to_string(),
+            tree: FilterParser::parse_expression(source)?,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The variables the expression refers to, in order of appearance.
+    pub fn variables(&self) -> Vec<&Variable> {
+        fn collect<'a>(expr: &'a Expr, variables: &mut Vec<&'a Variable>) {
+            match &expr.kind {
+                ExprKind::Literal(_) => {}
+                ExprKind::Variable(variable) => variables.push(variable),
+                ExprKind::Call { args, .. } => {
+                    for arg in args {
+                        collect(arg, variables);
+                    }
+                }
+                ExprKind::Not(operand) | ExprKind::Negate(operand) => collect(operand, variables),
+                ExprKind::Binary { lhs, rhs, .. } => {
+                    collect(lhs, variables);
+                    collect(rhs, variables);
+                }
+                ExprKind::Match { lhs, .. } => collect(lhs, variables),
+            }
+        }
+        let mut variables = Vec::new();
+        collect(&self.tree, &mut variables);
+        variables
+    }
+
+    /// The values the column `column` must have for the expression to hold,
+    /// if it only holds for some, like the IDs of `ID == "rs1" || ID ==
+    /// "rs2"`. An index can then find the rows without reading the others.
+    pub fn required_values(&self, column: &str) -> Option<Vec<&str>> {
+        fn required<'a>(expr: &'a Expr, column: &str) -> Option<Vec<&'a str>> {
+            match &expr.kind {
+                ExprKind::Binary {
+                    op: BinaryOp::Eq,
+                    lhs,
+                    rhs,
+                } => match (&lhs.kind, &rhs.kind) {
+                    (ExprKind::Variable(variable), ExprKind::Literal(Value::Str(value)))
+                    | (ExprKind::Literal(Value::Str(value)), ExprKind::Variable(variable))
+                        if variable.name == column
+                            && variable.subscript.is_none()
+                            // "." also matches a missing value
+                            && value != "." =>
+                    {
+                        Some(vec![value.as_str()])
+                    }
+                    _ => None,
+                },
+                ExprKind::Binary {
+                    op: BinaryOp::SiteOr | BinaryOp::SampleOr,
+                    lhs,
+                    rhs,
+                } => {
+                    let mut values = required(lhs, column)?;
+                    values.extend(required(rhs, column)?);
+                    Some(values)
+                }
+                ExprKind::Binary {
+                    op: BinaryOp::SiteAnd | BinaryOp::SampleAnd,
+                    lhs,
+                    rhs,
+                } => required(lhs, column).or_else(|| required(rhs, column)),
+                _ => None,
+            }
+        }
+        required(&self.tree, column)
+    }
+
+    pub fn eval(&self, scope: &impl Scope) -> Result<Value, ExpressionError> {
+        eval(&self.tree, scope)
+    }
+
+    /// Whether a row passes the expression as a filter.
+    pub fn eval_filter(&self, scope: &impl Scope) -> Result<bool, ExpressionError> {
+        let value = self.eval(scope)?;
+        truthy(&value).ok_or_else(|| {
+            ExpressionError::new(
+                format!("expected true or false, found {}", value.describe()),
+                self.tree.span.clone(),
+            )
+        })
+    }
+}
+
+impl ExpressionError {
+    pub fn new(message: String, span: Span) -> ExpressionError {
+        ExpressionError { message, span }
+    }
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+impl Value {
+    /// The value of a field holding values separated by `separator`, each
+    /// possibly `.`.
+    pub fn from_field(field: &str, separator: char) -> Value {
+        let item = |item: &str| match item {
+            "." => Value::Missing,
+            item => Value::Str(item.to_string()),
+        };
+        if field.contains(separator) {
+            Value::List(field.split(separator).map(item).collect())
+        } else {
+            item(field)
+        }
+    }
+
+    /// For error messages.
+    fn describe(&self) -> String {
+        match self {
+            Value::Missing => "a missing value".to_string(),
+            Value::Bool(_) => format!("the boolean {}", self),
+            Value::Int(_) | Value::Float(_) => format!("the number {}", self),
+            Value::Str(s) => format!("the string \"{}\"", s),
+            Value::List(_) => format!("the list {}", self),
+            Value::Samples(_) => "per-sample values".to_string(),
+            Value::Genotype { .. } => format!("the genotype {}", self),
+        }
+    }
+
+    /// The value as a number, if it is one or is a string holding one.
+    fn as_number(&self) -> Option<Number> {
+        match self {
+            Value::Int(i) => Some(Number::Int(*i)),
+            Value::Float(x) => Some(Number::Float(*x)),
+            Value::Str(s) => s
+                .parse()
+                .map(Number::Int)
+                .ok()
+                .or_else(|| s.parse().map(Number::Float).ok()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |f: &mut fmt::Formatter<'_>, values: &[Value]| {
+            for (idx, value) in values.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{}", value)?;
+            }
+            Ok(())
+        };
+        match self {
+            Value::Missing => write!(f, "."),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(values) | Value::Samples(values) => join(f, values),
+            Value::Genotype { alleles, phased } => {
+                let separator = if *phased { '|' } else { '/' };
+                match alleles {
+                    Some((first, second)) => write!(f, "{}{}{}", first, separator, second),
+                    None => write!(f, ".{}.", separator),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(x) => x,
+        }
+    }
+
+    fn value(self) -> Value {
+        match self {
+            Number::Int(i) => Value::Int(i),
+            Number::Float(x) => Value::Float(x),
+        }
+    }
+}
+
+/// Whether a value passes as a filter, or None if it isn't a boolean.
+fn truthy(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        Value::Missing => Some(false),
+        Value::Samples(values) => {
+            let mut any = false;
+            for value in values {
+                any |= truthy(value)?;
+            }
+            Some(any)
+        }
+        _ => None,
+    }
+}
+
+fn eval(expr: &Expr, scope: &impl Scope) -> Result<Value, ExpressionError> {
+    let error = |message: String| ExpressionError::new(message, expr.span.clone());
+    match &expr.kind {
+        ExprKind::Literal(value) => Ok(value.clone()),
+        ExprKind::Variable(variable) => scope
+            .value(variable)
+            .map_err(|message| ExpressionError::new(message, variable.span.clone())),
+        ExprKind::Not(operand) => {
+            map_samples(eval(operand, scope)?, &|value| match truthy(&value) {
+                Some(b) => Ok(Value::Bool(!b)),
+                None => Err(error(format!("cannot negate {}", value.describe()))),
+            })
+        }
+        ExprKind::Negate(operand) => {
+            map_values(eval(operand, scope)?, &|value| match value.as_number() {
+                Some(Number::Int(i)) => Ok(Value::Int(-i)),
+                Some(Number::Float(x)) => Ok(Value::Float(-x)),
+                None if value == Value::Missing => Ok(Value::Missing),
+                None => Err(error(format!("cannot negate {}", value.describe()))),
+            })
+        }
+        ExprKind::Binary {
+            op: op @ (BinaryOp::SiteAnd | BinaryOp::SiteOr),
+            lhs,
+            rhs,
+        } => {
+            let as_bool = |side: &Expr| {
+                let value = eval(side, scope)?;
+                truthy(&value).ok_or_else(|| {
+                    ExpressionError::new(
+                        format!("expected true or false, found {}", value.describe()),
+                        side.span.clone(),
+                    )
+                })
+            };
+            // short-circuit like `&&` and `||` usually do
+            let lhs = as_bool(lhs)?;
+            Ok(Value::Bool(match op {
+                BinaryOp::SiteAnd => lhs && as_bool(rhs)?,
+                _ => lhs || as_bool(rhs)?,
+            }))
+        }
+        ExprKind::Binary { op, lhs, rhs } => {
+            let lhs = eval(lhs, scope)?;
+            let rhs = eval(rhs, scope)?;
+            zip_samples(lhs, rhs, &|lhs, rhs| binary(*op, lhs, rhs).map_err(error))
+        }
+        ExprKind::Match {
+            lhs,
+            regex,
+            negated,
+        } => map_samples(eval(lhs, scope)?, &|value| {
+            let matches = any_value(&value, &|value| {
+                *value != Value::Missing && regex.is_match(&value.to_string())
+            });
+            Ok(Value::Bool(matches != *negated))
+        }),
+        ExprKind::Call { function, args } => call(*function, eval(&args[0], scope)?).map_err(error),
+    }
+}
+
+/// Applies `f` to each sample's value, or to the value itself if it isn't
+/// per-sample.
+fn map_samples(
+    value: Value,
+    f: &impl Fn(Value) -> Result<Value, ExpressionError>,
+) -> Result<Value, ExpressionError> {
+    match value {
+        Value::Samples(values) => Ok(Value::Samples(
+            values.into_iter().map(f).collect::<Result<_, _>>()?,
+        )),
+        value => f(value),
+    }
+}
+
+/// Like `map_samples`, but also applies `f` to each value of a list.
+fn map_values(
+    value: Value,
+    f: &impl Fn(Value) -> Result<Value, ExpressionError>,
+) -> Result<Value, ExpressionError> {
+    map_samples(value, &|value| match value {
+        Value::List(values) => Ok(Value::List(
+            values.into_iter().map(f).collect::<Result<_, _>>()?,
+        )),
+        value => f(value),
+    })
+}
+
+/// Applies `f` sample by sample if either side is per-sample.
+fn zip_samples(
+    lhs: Value,
+    rhs: Value,
+    f: &impl Fn(Value, Value) -> Result<Value, ExpressionError>,
+) -> Result<Value, ExpressionError> {
+    match (lhs, rhs) {
+        (Value::Samples(lhs), Value::Samples(rhs)) => Ok(Value::Samples(
+            std::iter::zip(lhs, rhs)
+                .map(|(lhs, rhs)| f(lhs, rhs))
+                .collect::<Result<_, _>>()?,
+        )),
+        (Value::Samples(lhs), rhs) => Ok(Value::Samples(
+            lhs.into_iter()
+                .map(|lhs| f(lhs, rhs.clone()))
+                .collect::<Result<_, _>>()?,
+        )),
+        (lhs, Value::Samples(rhs)) => Ok(Value::Samples(
+            rhs.into_iter()
+                .map(|rhs| f(lhs.clone(), rhs))
+                .collect::<Result<_, _>>()?,
+        )),
+        (lhs, rhs) => f(lhs, rhs),
+    }
+}
+
+/// Whether `f` holds for the value, or for any value of a list.
+fn any_value(value: &Value, f: &impl Fn(&Value) -> bool) -> bool {
+    match value {
+        Value::List(values) => values.iter().any(f),
+        value => f(value),
+    }
+}
+
+/// A binary operator other than `&&` and `||`, on values of a single
+/// sample.
+fn binary(op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    match op {
+        BinaryOp::SampleAnd | BinaryOp::SampleOr => {
+            let as_bool = |value: &Value| {
+                truthy(value)
+                    .ok_or_else(|| format!("expected true or false, found {}", value.describe()))
+            };
+            let (lhs, rhs) = (as_bool(&lhs)?, as_bool(&rhs)?);
+            Ok(Value::Bool(match op {
+                BinaryOp::SampleAnd => lhs && rhs,
+                _ => lhs || rhs,
+            }))
+        }
+        BinaryOp::Eq => Ok(Value::Bool(any_equal(&lhs, &rhs)?)),
+        BinaryOp::Ne => Ok(Value::Bool(!any_equal(&lhs, &rhs)?)),
+        BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => {
+            let holds = |ordering: std::cmp::Ordering| match op {
+                BinaryOp::Lt => ordering.is_lt(),
+                BinaryOp::Le => ordering.is_le(),
+                BinaryOp::Gt => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            };
+            let mut result = false;
+            for lhs in list_items(&lhs) {
+                for rhs in list_items(&rhs) {
+                    result |= compare(lhs, rhs)?.is_some_and(holds);
+                }
+            }
+            Ok(Value::Bool(result))
+        }
+        BinaryOp::Add if is_text(&lhs) || is_text(&rhs) => {
+            Ok(Value::Str(format!("{}{}", lhs, rhs)))
+        }
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => arithmetic(op, lhs, rhs),
+        BinaryOp::SiteAnd | BinaryOp::SiteOr => unreachable!("evaluated by `eval`"),
+    }
+}
+
+fn list_items(value: &Value) -> &[Value] {
+    match value {
+        Value::List(values) => values,
+        value => std::slice::from_ref(value),
+    }
+}
+
+/// Whether `+` concatenates rather than adds.
+fn is_text(value: &Value) -> bool {
+    match value {
+        Value::Str(_) | Value::Genotype { .. } => true,
+        Value::List(values) => values.iter().any(is_text),
+        _ => false,
+    }
+}
+
+/// Whether any value of `lhs` equals any value of `rhs`.
+fn any_equal(lhs: &Value, rhs: &Value) -> Result<bool, String> {
+    for lhs in list_items(lhs) {
+        for rhs in list_items(rhs) {
+            if equal(lhs, rhs)? {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn equal(lhs: &Value, rhs: &Value) -> Result<bool, String> {
+    Ok(match (lhs, rhs) {
+        // "." stands for a missing value, like in the files
+        (Value::Missing, Value::Missing) => true,
+        (Value::Missing, Value::Str(s)) | (Value::Str(s), Value::Missing) => s == ".",
+        (Value::Missing, _) | (_, Value::Missing) => false,
+        (Value::Genotype { alleles, phased }, Value::Str(s))
+        | (Value::Str(s), Value::Genotype { alleles, phased }) => {
+            genotype_matches(*alleles, *phased, s)
+        }
+        (Value::Genotype { alleles: lhs, .. }, Value::Genotype { alleles: rhs, .. }) => lhs == rhs,
+        (Value::Bool(lhs), Value::Bool(rhs)) => lhs == rhs,
+        (Value::Str(lhs), Value::Str(rhs)) => lhs == rhs,
+        (lhs, rhs) => match compare(lhs, rhs)? {
+            Some(ordering) => ordering.is_eq(),
+            None => false,
+        },
+    })
+}
+
+/// Orders two values, or None if a string that isn't a number is compared
+/// with a number, or a value is missing.
+fn compare(lhs: &Value, rhs: &Value) -> Result<Option<std::cmp::Ordering>, String> {
+    match (lhs, rhs) {
+        (Value::Missing, _) | (_, Value::Missing) => Ok(None),
+        (Value::Str(lhs), Value::Str(rhs)) => Ok(Some(lhs.cmp(rhs))),
+        (Value::Int(_) | Value::Float(_), Value::Str(_))
+        | (Value::Str(_) | Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+            Ok(match (lhs.as_number(), rhs.as_number()) {
+                (Some(Number::Int(lhs)), Some(Number::Int(rhs))) => Some(lhs.cmp(&rhs)),
+                (Some(lhs), Some(rhs)) => lhs.as_f64().partial_cmp(&rhs.as_f64()),
+                _ => None,
+            })
+        }
+        (lhs, rhs) => Err(format!(
+            "cannot compare {} with {}",
+            lhs.describe(),
+            rhs.describe()
+        )),
+    }
+}
+
+/// Whether a genotype matches a GT like `0/1` or one of bcftools' genotype
+/// classes: `ref` (or `RR`), `alt`, `het`, `hom`, `AA`, `RA` (or `AR`),
+/// `Aa` and `miss` (or `mis` and `.`).
+fn genotype_matches(alleles: Option<(u32, u32)>, phased: bool, gt: &str) -> bool {
+    let Some((first, second)) = alleles else {
+        return matches!(gt, "miss" | "mis" | "." | "./." | ".|.");
+    };
+    match gt {
+        "ref" | "RR" => first == 0 && second == 0,
+        "alt" => first != 0 || second != 0,
+        "het" => first != second,
+        "hom" => first == second,
+        "AA" => first == second && first != 0,
+        "RA" | "AR" => (first == 0) != (second == 0),
+        "Aa" => first != second && first != 0 && second != 0,
+        _ => {
+            let Some((gt_first, gt_second, gt_phased)) = gt
+                .split_once('/')
+                .map(|(first, second)| (first, second, false))
+                .or_else(|| {
+                    gt.split_once('|')
+                        .map(|(first, second)| (first, second, true))
+                })
+            else {
+                return false;
+            };
+            let (Ok(gt_first), Ok(gt_second)) = (gt_first.parse(), gt_second.parse()) else {
+                return false;
+            };
+            if gt_phased {
+                phased && (first, second) == (gt_first, gt_second)
+            } else {
+                // an unphased GT doesn't order its alleles
+                (first.min(second), first.max(second))
+                    == (gt_first.min(gt_second), gt_first.max(gt_second))
+            }
+        }
+    }
+}
+
+fn arithmetic(op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    if let (Value::List(_), _) | (_, Value::List(_)) = (&lhs, &rhs) {
+        let lhs_items = list_items(&lhs);
+        let rhs_items = list_items(&rhs);
+        if lhs_items.len() != rhs_items.len() && lhs_items.len() != 1 && rhs_items.len() != 1 {
+            return Err(format!(
+                "cannot combine {} with {}",
+                lhs.describe(),
+                rhs.describe()
+            ));
+        }
+        let len = lhs_items.len().max(rhs_items.len());
+        let item =
+            |items: &[Value], idx: usize| items[if items.len() == 1 { 0 } else { idx }].clone();
+        return Ok(Value::List(
+            (0..len)
+                .map(|idx| arithmetic(op, item(lhs_items, idx), item(rhs_items, idx)))
+                .collect::<Result<_, _>>()?,
+        ));
+    }
+    if lhs == Value::Missing || rhs == Value::Missing {
+        return Ok(Value::Missing);
+    }
+    let as_number = |value: &Value| {
+        value
+            .as_number()
+            .ok_or_else(|| format!("expected a number, found {}", value.describe()))
+    };
+    let (lhs, rhs) = (as_number(&lhs)?, as_number(&rhs)?);
+    if let (Number::Int(lhs), Number::Int(rhs), false) = (lhs, rhs, op == BinaryOp::Div) {
+        let result = match op {
+            BinaryOp::Add => lhs.checked_add(rhs),
+            BinaryOp::Sub => lhs.checked_sub(rhs),
+            _ => lhs.checked_mul(rhs),
+        };
+        if let Some(result) = result {
+            return Ok(Value::Int(result));
+        }
+    }
+    let (lhs, rhs) = (lhs.as_f64(), rhs.as_f64());
+    Ok(match op {
+        BinaryOp::Add => Value::Float(lhs + rhs),
+        BinaryOp::Sub => Value::Float(lhs - rhs),
+        BinaryOp::Mul => Value::Float(lhs * rhs),
+        _ if rhs == 0.0 => Value::Missing,
+        _ => Value::Float(lhs / rhs),
+    })
+}
+
+fn call(function: Function, arg: Value) -> Result<Value, String> {
+    let number = |value: &Value| {
+        value
+            .as_number()
+            .ok_or_else(|| format!("expected a number, found {}", value.describe()))
+    };
+    match function {
+        Function::Strlen => map_each(arg, &|value| match value {
+            Value::Missing => Ok(Value::Missing),
+            value => Ok(Value::Int(value.to_string().chars().count() as i64)),
+        }),
+        Function::Abs => map_each(arg, &|value| match value {
+            Value::Missing => Ok(Value::Missing),
+            value => Ok(match number(&value)? {
+                Number::Int(i) => Value::Int(i.abs()),
+                Number::Float(x) => Value::Float(x.abs()),
+            }),
+        }),
+        Function::Str => Ok(Value::Str(arg.to_string())),
+        Function::Int => map_each(arg, &|value| match value {
+            Value::Missing => Ok(Value::Missing),
+            value => Ok(Value::Int(number(&value)?.as_f64() as i64)),
+        }),
+        Function::Float => map_each(arg, &|value| match value {
+            Value::Missing => Ok(Value::Missing),
+            value => Ok(Value::Float(number(&value)?.as_f64())),
+        }),
+        Function::NPass => {
+            let passes = |value: &Value| {
+                truthy(value)
+                    .ok_or_else(|| format!("expected true or false, found {}", value.describe()))
+            };
+            let count = match &arg {
+                Value::Samples(values) => {
+                    let mut count = 0;
+                    for value in values {
+                        count += passes(value)? as i64;
+                    }
+                    count
+                }
+                value => passes(value)? as i64,
+            };
+            Ok(Value::Int(count))
+        }
+        Function::Min | Function::Max | Function::Sum | Function::Avg => {
+            let values = match arg {
+                Value::List(values) | Value::Samples(values) => values,
+                value => vec![value],
+            };
+            let mut numbers = Vec::new();
+            for value in values.iter().filter(|value| **value != Value::Missing) {
+                numbers.push(number(value)?);
+            }
+            if numbers.is_empty() {
+                return Ok(Value::Missing);
+            }
+            let all_ints = numbers
+                .iter()
+                .all(|number| matches!(number, Number::Int(_)));
+            let floats = numbers.iter().map(|number| number.as_f64());
+            Ok(match function {
+                Function::Min | Function::Max => {
+                    let mut best = numbers[0];
+                    for number in &numbers[1..] {
+                        let better = if function == Function::Min {
+                            number.as_f64() < best.as_f64()
+                        } else {
+                            number.as_f64() > best.as_f64()
+                        };
+                        if better {
+                            best = *number;
+                        }
+                    }
+                    best.value()
+                }
+                Function::Sum if all_ints => Value::Int(
+                    numbers
+                        .iter()
+                        .map(|number| match number {
+                            Number::Int(i) => *i,
+                            Number::Float(x) => *x as i64,
+                        })
+                        .sum(),
+                ),
+                Function::Sum => Value::Float(floats.sum()),
+                _ => Value::Float(floats.sum::<f64>() / numbers.len() as f64),
+            })
+        }
+    }
+}
+
+/// Applies `f` to each value of a list or of each sample.
+fn map_each(value: Value, f: &impl Fn(Value) -> Result<Value, String>) -> Result<Value, String> {
+    match value {
+        Value::List(values) => Ok(Value::List(
+            values.into_iter().map(f).collect::<Result<_, _>>()?,
+        )),
+        Value::Samples(values) => Ok(Value::Samples(
+            values
+                .into_iter()
+                .map(|value| map_each(value, f))
+                .collect::<Result<_, _>>()?,
+        )),
+        value => f(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Variables by name, ignoring subscripts.
+    struct Vars(HashMap<&'static str, Value>);
+
+    impl Scope for Vars {
+        fn value(&self, variable: &Variable) -> Result<Value, String> {
+            self.0
+                .get(variable.name.as_str())
+                .cloned()
+                .ok_or_else(|| format!("unknown variable {}", variable.name))
+        }
+    }
+
+    fn vars() -> Vars {
+        let str_list = |values: &[&str]| {
+            Value::List(
+                values
+                    .iter()
+                    .map(|value| Value::Str(value.to_string()))
+                    .collect(),
+            )
+        };
+        Vars(HashMap::from([
+            ("POS", Value::Int(100)),
+            ("ID", Value::Str("rs12".to_string())),
+            ("ALT", str_list(&["G", "T"])),
+            ("QUAL", Value::Missing),
+            // two samples, each carrying one of the two
+            ("A", Value::Samples(vec![Value::Int(1), Value::Int(0)])),
+            ("B", Value::Samples(vec![Value::Int(0), Value::Int(1)])),
+        ]))
+    }
+
+    fn eval(source: &str) -> Value {
+        Expression::parse(source).unwrap().eval(&vars()).unwrap()
+    }
+
+    fn holds(source: &str) -> bool {
+        Expression::parse(source)
+            .unwrap()
+            .eval_filter(&vars())
+            .unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Value::Int(7));
+        assert_eq!(eval("(1 + 2) * 3"), Value::Int(9));
+        assert_eq!(eval("10 - 4 - 3"), Value::Int(3));
+        assert_eq!(eval("-2 * 3"), Value::Int(-6));
+        assert_eq!(eval("7 / 2"), Value::Float(3.5));
+        // && binds tighter than ||, and comparisons tighter than both
+        assert!(holds("true || false && false"));
+        assert!(!holds("(true || false) && false"));
+        assert!(holds("POS > 50 && POS < 200 || POS == 1"));
+        assert!(holds("!(POS < 50) & !false"));
+    }
+
+    #[test]
+    fn typed_comparisons() {
+        // strings compared with numbers are compared as numbers
+        assert!(holds(r#"POS == "100" && POS > "99""#));
+        assert!(holds("POS + 1 == 101"));
+        assert!(holds(r#"ID == "rs12" && ID < "rs2""#));
+        assert_eq!(eval(r#"ID + "x""#), Value::Str("rs12x".to_string()));
+        // a list matches if any of its values does
+        assert!(holds(r#"ALT == "T""#));
+        assert!(!holds(r#"ALT == "C""#));
+        assert!(holds(r#"ID ~ "^rs[0-9]+$" && ALT !~ "C""#));
+        // missing values equal "." and nothing else
+        assert!(holds(r#"QUAL == "." && !(QUAL > 0)"#));
+    }
+
+    #[test]
+    fn sample_and_site_operators() {
+        // & needs one sample to satisfy both sides, && only each side
+        assert!(!holds("A == 1 & B == 1"));
+        assert!(holds("A == 1 && B == 1"));
+        assert_eq!(
+            eval("A == 1 | B == 1"),
+            Value::Samples(vec![Value::Bool(true), Value::Bool(true)])
+        );
+    }
+
+    #[test]
+    fn errors_point_at_their_cause() {
+        let expression = Expression::parse("POS > 1 && DP > 10").unwrap();
+        let error = expression.eval(&vars()).unwrap_err();
+        assert_eq!(error.message, "unknown variable DP");
+        assert_eq!(error.span, 11..13);
+        let error = Expression::parse("POS")
+            .unwrap()
+            .eval_filter(&vars())
+            .unwrap_err();
+        assert_eq!(
+            error.message,
+            "expected true or false, found the number 100"
+        );
+        assert!(Expression::parse("POS >").is_err());
+    }
+}
@@ -1,67 +1,326 @@
-use std::{io, iter::Filter};
+//! Parsing the expressions of `query` and `filter` (see
+//! `filter_parser.pest` for their grammar) into syntax trees.
 
+use pest::error::{ErrorVariant, InputLocation};
+use pest::iterators::Pair;
 use pest::Parser;
 use pest_derive::Parser;
+use regex::Regex;
+
+use crate::expression::{ExpressionError, Value};
 
 #[derive(Parser)]
 #[grammar = "filter_parser.pest"]
 pub struct FilterParser;
 
+/// A byte range of an expression's source.
+pub type Span = std::ops::Range<usize>;
+
+/// A node of the syntax tree, with the part of the source it was parsed
+/// from.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Literal(Value),
+    Variable(Variable),
+    Call {
+        function: Function,
+        args: Vec<Expr>,
+    },
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// `~` or, if negated, `!~`, whose right-hand side must be a string.
+    Match {
+        lhs: Box<Expr>,
+        regex: Regex,
+        negated: bool,
+    },
+}
+
+/// A variable, e.g. `POS`, `INFO/AF`, `INFO[AF]` or `GT[IID]`.
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    /// What is between the brackets, without surrounding whitespace.
+    pub subscript: Option<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    /// `||`: either side holds, for any sample.
+    SiteOr,
+    /// `|`: either side holds, sample by sample.
+    SampleOr,
+    /// `&&`: both sides hold, not necessarily for the same sample.
+    SiteAnd,
+    /// `&`: both sides hold for the same sample.
+    SampleAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Strlen,
+    Abs,
+    Min,
+    Max,
+    Sum,
+    Avg,
+    Str,
+    Int,
+    Float,
+    /// The number of samples for which its argument holds.
+    NPass,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "strlen" => Function::Strlen,
+            "abs" => Function::Abs,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "sum" => Function::Sum,
+            "avg" | "mean" => Function::Avg,
+            // str::from is what evalexpr called it
+            "str" | "str::from" => Function::Str,
+            "int" => Function::Int,
+            "float" => Function::Float,
+            "N_PASS" => Function::NPass,
+            _ => return None,
+        })
+    }
+}
 
 impl FilterParser {
-    pub fn get_info_query(input: &str) -> Option<Vec<Vec<String>>> {
-        let mut infokeys= Vec::new();
-        let mut eqneq = String::new();
-        let mut valstr = String::new();
-        let mut found = false;
-        let linepairs = FilterParser::parse(Rule::filterline, input).ok()?;
-        for linepair in linepairs {
-            for entrypair in linepair.into_inner() {
-                if entrypair.as_rule() == Rule::filterentry {
-                    for innerpair in entrypair.into_inner() {
-                        match innerpair.as_rule() {
-                            Rule::INFOQ => {
-                                for keypair in innerpair.into_inner() {
-                                    if keypair.as_rule() == Rule::key_only {
-                                        infokeys.push(keypair.as_str().to_string());
-                                    }
-                                }
-                                found = true;
-                            },
-                            Rule::eqneq => eqneq = innerpair.as_str().to_string(),
-                            Rule::valid_entry => valstr = innerpair.as_str().to_string(),
-                            _ => {}
-                        }
-                    }
-                    if found {
-                        let mut info_vals = Vec::new();
-                        info_vals.push(infokeys);
-                        let mut filter_vals = Vec::new();
-                        filter_vals.push(eqneq);
-                        filter_vals.push(valstr
-                            .trim_start_matches(|c| c == '\'' || c == '"')
-                            .trim_end_matches(|c| c == '\'' || c == '"')
-                            .to_string());
-                        
-                        info_vals.push(filter_vals);
-                        return Some(info_vals);
+    pub fn parse_expression(source: &str) -> Result<Expr, ExpressionError> {
+        let filter = FilterParser::parse(Rule::filter, source)
+            .map_err(|error| syntax_error(source, error))?
+            .next()
+            .expect("the filter rule always matches once");
+        let expr = filter
+            .into_inner()
+            .next()
+            .expect("a filter always has an expression");
+        build(expr)
+    }
+}
+
+fn build(pair: Pair<Rule>) -> Result<Expr, ExpressionError> {
+    let span = pair.as_span().start()..pair.as_span().end();
+    let kind = match pair.as_rule() {
+        Rule::expr | Rule::and_expr | Rule::sum | Rule::product => {
+            // left-associative chains of operators of the same precedence
+            let mut inner = pair.into_inner();
+            let mut lhs = build(inner.next().expect("chains start with an operand"))?;
+            while let Some(op) = inner.next() {
+                let rhs = build(inner.next().expect("operators are followed by an operand"))?;
+                lhs = Expr {
+                    span: lhs.span.start..rhs.span.end,
+                    kind: ExprKind::Binary {
+                        op: binary_op(op.as_str()),
+                        lhs: Box::new(lhs),
+                        rhs: Box::new(rhs),
+                    },
+                };
+            }
+            return Ok(lhs);
+        }
+        Rule::comparison => {
+            let mut inner = pair.into_inner();
+            let lhs = build(inner.next().expect("comparisons start with an operand"))?;
+            let Some(op) = inner.next() else {
+                return Ok(lhs);
+            };
+            let rhs = build(inner.next().expect("operators are followed by an operand"))?;
+            match op.as_str() {
+                op @ ("~" | "!~") => {
+                    let ExprKind::Literal(Value::Str(pattern)) = &rhs.kind else {
+                        return Err(ExpressionError::new(
+                            format!("the right-hand side of `{}` must be a string", op),
+                            rhs.span,
+                        ));
+                    };
+                    let regex = Regex::new(pattern).map_err(|error| {
+                        ExpressionError::new(format!("invalid regex: {}", error), rhs.span.clone())
+                    })?;
+                    ExprKind::Match {
+                        lhs: Box::new(lhs),
+                        regex,
+                        negated: op == "!~",
                     }
                 }
+                op => ExprKind::Binary {
+                    op: binary_op(op),
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            }
+        }
+        Rule::unary => {
+            let mut inner = pair.into_inner().collect::<Vec<_>>();
+            let mut operand = build(inner.pop().expect("unary operators apply to an operand"))?;
+            for op in inner.into_iter().rev() {
+                let span = op.as_span().start()..operand.span.end;
+                let operand_box = Box::new(operand);
+                let kind = match op.as_str() {
+                    "!" => ExprKind::Not(operand_box),
+                    _ => ExprKind::Negate(operand_box),
+                };
+                operand = Expr { kind, span };
+            }
+            return Ok(operand);
+        }
+        Rule::number => {
+            let number = pair.as_str();
+            let value = if number.contains(['.', 'e', 'E']) {
+                number.parse().map(Value::Float).ok()
+            } else {
+                number.parse().map(Value::Int).ok()
+            };
+            let value = value.ok_or_else(|| {
+                ExpressionError::new(format!("`{}` is too large a number", number), span.clone())
+            })?;
+            ExprKind::Literal(value)
+        }
+        Rule::string => {
+            let quoted = pair.into_inner().next().expect("strings have contents");
+            ExprKind::Literal(Value::Str(unescape(quoted.as_str())))
+        }
+        Rule::boolean => ExprKind::Literal(Value::Bool(pair.as_str() == "true")),
+        Rule::call => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().expect("calls start with a function name");
+            let function = Function::from_name(name.as_str()).ok_or_else(|| {
+                ExpressionError::new(
+                    format!("unknown function `{}`", name.as_str()),
+                    name.as_span().start()..name.as_span().end(),
+                )
+            })?;
+            let args = inner
+                .map(build)
+                .collect::<Result<Vec<Expr>, ExpressionError>>()?;
+            if args.len() != 1 {
+                return Err(ExpressionError::new(
+                    format!("`{}` takes one argument", name.as_str()),
+                    span,
+                ));
             }
+            ExprKind::Call { function, args }
+        }
+        Rule::variable => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().expect("variables start with a name").as_str();
+            ExprKind::Variable(Variable {
+                name: name.to_string(),
+                subscript: inner
+                    .next()
+                    .map(|subscript| subscript.as_str().trim().to_string()),
+                span: span.clone(),
+            })
         }
+        rule => unreachable!("{:?} is not an expression", rule),
+    };
+    Ok(Expr { kind, span })
+}
 
-        None
+fn binary_op(op: &str) -> BinaryOp {
+    match op {
+        "||" => BinaryOp::SiteOr,
+        "|" => BinaryOp::SampleOr,
+        "&&" => BinaryOp::SiteAnd,
+        "&" => BinaryOp::SampleAnd,
+        "=" | "==" => BinaryOp::Eq,
+        "!=" => BinaryOp::Ne,
+        "<" => BinaryOp::Lt,
+        "<=" => BinaryOp::Le,
+        ">" => BinaryOp::Gt,
+        ">=" => BinaryOp::Ge,
+        "+" => BinaryOp::Add,
+        "-" => BinaryOp::Sub,
+        "*" => BinaryOp::Mul,
+        "/" => BinaryOp::Div,
+        op => unreachable!("`{}` is not a binary operator", op),
     }
 }
 
-fn main() -> io::Result<()> {
-
-    if let Some(info) = FilterParser::get_info_query("POS == G and POS == 2 and INFO[u, v ] != '3'") {
-        println!("{:?}", info);
-    } else {
-        println!("sad");
+/// Replaces the escapes `\t`, `\n`, `\r`, `\\` and of quotes. Other
+/// backslashes are kept, since they are probably part of a regex.
+fn unescape(quoted: &str) -> String {
+    let mut unescaped = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c @ ('\\' | '"' | '\'')) => unescaped.push(c),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
     }
+    unescaped
+}
 
-
-    Ok(())
+/// Turns pest's list of expected rules into a message about what was
+/// expected where.
+fn syntax_error(source: &str, error: pest::error::Error<Rule>) -> ExpressionError {
+    let pos = match error.location {
+        InputLocation::Pos(pos) => pos,
+        InputLocation::Span((start, _)) => start,
+    };
+    let mut expected: Vec<&str> = Vec::new();
+    if let ErrorVariant::ParsingError { positives, .. } = &error.variant {
+        for rule in positives {
+            let description = match rule {
+                Rule::EOI => "the end of the expression",
+                Rule::or_op | Rule::and_op | Rule::compare_op | Rule::sum_op | Rule::product_op => {
+                    "an operator"
+                }
+                Rule::subscript => "a sample, INFO key or index",
+                Rule::double_quoted | Rule::single_quoted | Rule::escape => "a closing quote",
+                _ => "a value",
+            };
+            if !expected.contains(&description) {
+                expected.push(description);
+            }
+        }
+    }
+    let expected = match expected.split_last() {
+        None => "unexpected input".to_string(),
+        Some((last, [])) => format!("expected {}", last),
+        Some((last, rest)) => format!("expected {} or {}", rest.join(", "), last),
+    };
+    let (found, len) = match source[pos..].chars().next() {
+        Some(c) => (format!("found `{}`", c), c.len_utf8()),
+        None => ("but the expression ends".to_string(), 0),
+    };
+    ExpressionError::new(format!("{}, {}", expected, found), pos..pos + len)
 }
@@ -0,0 +1,281 @@
+//! bcftools-style format strings for `query -f`, like
+//! `%CHROM\t%POS[\t%SAMPLE=%GT]\n` (see `format_string.pest` for their
+//! grammar): text in which each `%FIELD` is replaced by its value, and whose
+//! `[...]` blocks are repeated for each sample.
+//!
+//! Fields are the variables of expressions, with `%INFO/AC{1}` standing for
+//! `INFO/AC[1]`. Inside a block, `%SAMPLE` is the sample's IID and the
+//! per-sample fields like `%GT` are the sample's value.
+
+use pest::error::InputLocation;
+use pest::iterators::Pair;
+use pest::Parser;
+use pest_derive::Parser;
+
+use crate::expression::{ExpressionError, Scope, Value};
+use crate::filter_parser::{Span, Variable};
+
+#[derive(Parser)]
+#[grammar = "format_string.pest"]
+struct FormatStringParser;
+
+/// A parsed format string, along with its source.
+#[derive(Debug, Clone)]
+pub struct FormatString {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Field(Variable),
+    /// A `[...]` block, repeated for each sample.
+    Samples {
+        parts: Vec<Part>,
+        span: Span,
+    },
+}
+
+/// The variables of a format string's rows, which also have a value for
+/// each sample inside `[...]` blocks.
+pub trait SampleScope: Scope {
+    /// The value of a variable for the sample `sam_idx`, which is its value
+    /// for the row unless it is per-sample.
+    fn sample_value(&self, variable: &Variable, sam_idx: usize) -> Result<Value, String>;
+}
+
+impl FormatString {
+    pub fn parse(source: &str) -> Result<FormatString, ExpressionError> {
+        let format_string = FormatStringParser::parse(Rule::format_string, source)
+            .map_err(|error| syntax_error(source, error))?
+            .next()
+            .expect("the format_string rule always matches once");
+        let parts = format_string
+            .into_inner()
+            .filter(|pair| pair.as_rule() != Rule::EOI)
+            .map(|pair| build(pair, false))
+            .collect::<Result<_, _>>()?;
+        Ok(FormatString {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The fields the format string refers to, other than `%SAMPLE`.
+    pub fn variables(&self) -> Vec<&Variable> {
+        fn collect<'a>(parts: &'a [Part], variables: &mut Vec<&'a Variable>) {
+            for part in parts {
+                match part {
+                    Part::Text(_) => {}
+                    Part::Field(variable) if variable.name == "SAMPLE" => {}
+                    Part::Field(variable) => variables.push(variable),
+                    Part::Samples { parts, .. } => collect(parts, variables),
+                }
+            }
+        }
+        let mut variables = Vec::new();
+        collect(&self.parts, &mut variables);
+        variables
+    }
+
+    /// The span of the first `[...]` block, if there is one.
+    pub fn samples_span(&self) -> Option<Span> {
+        self.parts.iter().find_map(|part| match part {
+            Part::Samples { span, .. } => Some(span.clone()),
+            _ => None,
+        })
+    }
+
+    /// Formats a row, whose `[...]` blocks are repeated for `samples`, each
+    /// its index among the pfile's samples and its IID.
+    pub fn format(
+        &self,
+        scope: &impl SampleScope,
+        samples: &[(usize, String)],
+    ) -> Result<String, ExpressionError> {
+        let mut formatted = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => formatted.push_str(text),
+                Part::Field(variable) => {
+                    let value = scope
+                        .value(variable)
+                        .map_err(|message| ExpressionError::new(message, variable.span.clone()))?;
+                    formatted.push_str(&field_text(&value));
+                }
+                Part::Samples { parts, .. } => {
+                    for (sam_idx, sample_id) in samples {
+                        format_sample(&mut formatted, parts, scope, *sam_idx, sample_id)?;
+                    }
+                }
+            }
+        }
+        Ok(formatted)
+    }
+}
+
+fn format_sample(
+    formatted: &mut String,
+    parts: &[Part],
+    scope: &impl SampleScope,
+    sam_idx: usize,
+    sample_id: &str,
+) -> Result<(), ExpressionError> {
+    for part in parts {
+        match part {
+            Part::Text(text) => formatted.push_str(text),
+            Part::Field(variable) if variable.name == "SAMPLE" => formatted.push_str(sample_id),
+            Part::Field(variable) => {
+                let value = scope
+                    .sample_value(variable, sam_idx)
+                    .map_err(|message| ExpressionError::new(message, variable.span.clone()))?;
+                formatted.push_str(&field_text(&value));
+            }
+            Part::Samples { .. } => unreachable!("the grammar doesn't nest blocks"),
+        }
+    }
+    Ok(())
+}
+
+/// The text of a field's value, with flags as 1 or 0 like in bcftools.
+fn field_text(value: &Value) -> String {
+    match value {
+        Value::Bool(flag) => u8::from(*flag).to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn build(pair: Pair<Rule>, in_samples: bool) -> Result<Part, ExpressionError> {
+    let span = pair.as_span().start()..pair.as_span().end();
+    Ok(match pair.as_rule() {
+        Rule::text => Part::Text(pair.as_str().to_string()),
+        Rule::escape => Part::Text(unescape(&pair.as_str()[1..]).to_string()),
+        Rule::field => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().expect("fields start with a name").as_str();
+            if name == "SAMPLE" && !in_samples {
+                return Err(ExpressionError::new(
+                    "%SAMPLE can only be used inside a [...] block".to_string(),
+                    span,
+                ));
+            }
+            Part::Field(Variable {
+                name: name.to_string(),
+                subscript: inner.next().map(|index| index.as_str().to_string()),
+                span,
+            })
+        }
+        Rule::samples => Part::Samples {
+            parts: pair
+                .into_inner()
+                .map(|pair| build(pair, true))
+                .collect::<Result<_, _>>()?,
+            span,
+        },
+        rule => unreachable!("{:?} is not part of a format string", rule),
+    })
+}
+
+/// The character an escape like `\t` stands for. `\n`, `\t` and `\r` are
+/// the usual, and other characters stand for themselves, e.g. `\[`.
+fn unescape(escaped: &str) -> &str {
+    match escaped {
+        "n" => "\n",
+        "t" => "\t",
+        "r" => "\r",
+        escaped => escaped,
+    }
+}
+
+/// Explains what went wrong where, since pest's list of expected rules means
+/// little to someone who hasn't read the grammar.
+fn syntax_error(source: &str, error: pest::error::Error<Rule>) -> ExpressionError {
+    let pos = match error.location {
+        InputLocation::Pos(pos) => pos,
+        InputLocation::Span((start, _)) => start,
+    };
+    // pest gets past a `%`, or a `\` at the end, before failing on what
+    // follows it
+    let pos = match source[..pos].chars().next_back() {
+        Some('%') => pos - 1,
+        Some('\\') if pos == source.len() => pos - 1,
+        _ => pos,
+    };
+    let found = source[pos..].chars().next();
+    let message = match found {
+        Some('%') => "expected a field name after %",
+        Some('\\') => "\\ at the end of the format string",
+        Some('[') => "[...] blocks cannot be nested",
+        Some(']') => "] without a matching [",
+        _ => "[ without a matching ]",
+    };
+    let len = found.map_or(0, char::len_utf8);
+    ExpressionError::new(message.to_string(), pos..pos + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A row whose samples have the GTs `gts`, by their index in the pfile.
+    struct GtScope {
+        gts: Vec<&'static str>,
+    }
+
+    impl Scope for GtScope {
+        fn value(&self, variable: &Variable) -> Result<Value, String> {
+            match variable.name.as_str() {
+                "ID" => Ok(Value::Str("rs1".to_string())),
+                "INFO/DB" => Ok(Value::Bool(true)),
+                "INFO/H2" => Ok(Value::Bool(false)),
+                name => Err(format!("no {}", name)),
+            }
+        }
+    }
+
+    impl SampleScope for GtScope {
+        fn sample_value(&self, variable: &Variable, sam_idx: usize) -> Result<Value, String> {
+            match variable.name.as_str() {
+                "GT" => Ok(Value::Str(self.gts[sam_idx].to_string())),
+                _ => self.value(variable),
+            }
+        }
+    }
+
+    #[test]
+    fn samples_get_their_own_values() {
+        let scope = GtScope {
+            gts: vec!["0/0", "0/1", "1/1", "./."],
+        };
+        let format = FormatString::parse(r"%ID[\t%SAMPLE=%GT]\n").unwrap();
+        // only some samples kept, like with --keep
+        let samples = [(1, "S2".to_string()), (3, "S4".to_string())];
+        assert_eq!(
+            format.format(&scope, &samples).unwrap(),
+            "rs1\tS2=0/1\tS4=./.\n"
+        );
+    }
+
+    #[test]
+    fn flags_are_1_or_0() {
+        let scope = GtScope { gts: vec!["0/1"] };
+        let format = FormatString::parse(r"%INFO/DB %INFO/H2[ %INFO/DB]\n").unwrap();
+        let samples = [(0, "S1".to_string())];
+        assert_eq!(format.format(&scope, &samples).unwrap(), "1 0 1\n");
+    }
+
+    #[test]
+    fn escapes_and_subscripts() {
+        let format = FormatString::parse(r"%INFO/AC{1}\t\%\n").unwrap();
+        let variables = format.variables();
+        assert_eq!(variables.len(), 1);
+        assert_eq!(variables[0].name, "INFO/AC");
+        assert_eq!(variables[0].subscript.as_deref(), Some("1"));
+        assert!(FormatString::parse("[%GT[%GT]]").is_err());
+    }
+}
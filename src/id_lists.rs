@@ -0,0 +1,202 @@
+//! Lists of samples or variants to keep or remove, read from files like the
+//! ones plink2's --keep, --remove, --extract and --exclude take.
+//!
+//! A sample list has a sample per line. Its columns are named by a header
+//! line starting with `#FID` or `#IID`; without one, a single column is the
+//! IID and otherwise the first two are the FID and the IID. A variant list
+//! is just variant IDs, separated by any whitespace.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::pfile::PgenError;
+
+/// The samples of a sample list, by IID or by FID and IID.
+#[derive(Debug, Clone, Default)]
+pub struct SampleList {
+    /// The FIDs listed with each IID, None when the list has no FIDs.
+    iids: HashMap<String, Vec<Option<String>>>,
+}
+
+impl SampleList {
+    /// Reads a sample list. Lines starting with `#` other than the header
+    /// line are skipped.
+    pub fn from_file(path: &str) -> Result<SampleList, PgenError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut columns = None;
+        let mut iids: HashMap<String, Vec<Option<String>>> = HashMap::new();
+        for (line_idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let invalid = |message: &str| PgenError::InvalidInput {
+                path: path.to_string(),
+                message: format!("line {}: {}", line_idx + 1, message),
+            };
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.is_empty() {
+                continue;
+            }
+            if columns.is_none() && (line.starts_with("#FID") || line.starts_with("#IID")) {
+                let column = |name: &str| {
+                    fields
+                        .iter()
+                        .position(|field| field.trim_start_matches('#') == name)
+                };
+                let iid_idx = column("IID").ok_or_else(|| invalid("expected an IID column"))?;
+                columns = Some((column("FID"), iid_idx));
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let (fid_idx, iid_idx) = *columns.get_or_insert(match fields.len() {
+                1 => (None, 0),
+                _ => (Some(0), 1),
+            });
+            if fields.len() <= iid_idx.max(fid_idx.unwrap_or_default()) {
+                return Err(invalid(match fid_idx {
+                    Some(_) => "expected an FID and an IID",
+                    None => "expected an IID",
+                }));
+            }
+            let fid = fid_idx.map(|fid_idx| fields[fid_idx].to_string());
+            iids.entry(fields[iid_idx].to_string())
+                .or_default()
+                .push(fid);
+        }
+        Ok(SampleList { iids })
+    }
+
+    /// Whether the sample `iid` of the family `fid` is listed. Without an
+    /// FID on either side, only the IID has to match.
+    pub fn contains(&self, fid: Option<&str>, iid: &str) -> bool {
+        self.iids.get(iid).is_some_and(|fids| {
+            fids.iter().any(|listed_fid| match (listed_fid, fid) {
+                (Some(listed_fid), Some(fid)) => listed_fid == fid,
+                _ => true,
+            })
+        })
+    }
+}
+
+/// The variant IDs of a variant list.
+#[derive(Debug, Clone, Default)]
+pub struct VariantList {
+    ids: HashSet<String>,
+}
+
+impl VariantList {
+    /// Reads a variant list, of IDs separated by any whitespace.
+    pub fn from_file(path: &str) -> Result<VariantList, PgenError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut ids = HashSet::new();
+        for line in reader.lines() {
+            ids.extend(line?.split_whitespace().map(str::to_string));
+        }
+        Ok(VariantList { ids })
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// The listed IDs, in no particular order.
+    pub(crate) fn ids(&self) -> Vec<&str> {
+        self.ids.iter().map(String::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Reads `contents` as a sample list.
+    fn sample_list(name: &str, contents: &str) -> Result<SampleList, PgenError> {
+        let path =
+            std::env::temp_dir().join(format!("pgen-rs-{}-{}.txt", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let list = SampleList::from_file(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        list
+    }
+
+    #[test]
+    fn sample_list_headers() {
+        let list = sample_list("keep-fid-iid", "#FID\tIID\tSEX\nF1\tS1\t1\nF2 S2  2\n").unwrap();
+        assert!(list.contains(Some("F1"), "S1"));
+        assert!(list.contains(Some("F2"), "S2"));
+        assert!(!list.contains(Some("F2"), "S1"));
+        assert!(!list.contains(Some("F1"), "S3"));
+        // a .psam without FIDs only has IIDs to match
+        assert!(list.contains(None, "S1"));
+
+        // the columns can be in any order
+        let list = sample_list("keep-iid-fid", "#IID FID\nS1 F1\n").unwrap();
+        assert!(list.contains(Some("F1"), "S1"));
+        assert!(!list.contains(Some("S1"), "F1"));
+
+        // without an FID column, any family matches
+        let list = sample_list("keep-iid", "## a comment\n#IID\tSEX\nS1\t1\n# S2\n").unwrap();
+        assert!(list.contains(Some("F9"), "S1"));
+        assert!(list.contains(None, "S1"));
+        assert!(!list.contains(None, "S2"));
+    }
+
+    #[test]
+    fn sample_list_columns() {
+        // a single column is the IID
+        let list = sample_list("keep-one", "S1\n\nS2\n").unwrap();
+        assert!(list.contains(Some("F1"), "S1"));
+        assert!(list.contains(None, "S2"));
+        // and otherwise the first two are the FID and IID, whatever follows
+        let list = sample_list("keep-two", "F1 S1 extra\nF1\tS2\nF2 S1\n").unwrap();
+        assert!(list.contains(Some("F1"), "S1"));
+        assert!(list.contains(Some("F2"), "S1"));
+        assert!(list.contains(Some("F1"), "S2"));
+        assert!(!list.contains(Some("F2"), "S2"));
+        assert!(!list.contains(None, "F1"));
+        assert!(sample_list("keep-empty", "").unwrap().iids.is_empty());
+    }
+
+    #[test]
+    fn sample_list_errors() {
+        let message = |result: Result<SampleList, PgenError>| match result {
+            Err(PgenError::InvalidInput { message, .. }) => message,
+            result => panic!("expected invalid input, got {:?}", result),
+        };
+        assert_eq!(
+            message(sample_list("keep-no-iid", "#FID\tSEX\nF1\t1\n")),
+            "line 1: expected an IID column"
+        );
+        // the first line decides the columns
+        assert_eq!(
+            message(sample_list("keep-short", "F1 S1\nS2\n")),
+            "line 2: expected an FID and an IID"
+        );
+        assert_eq!(
+            message(sample_list("keep-short-header", "#IID\tFID\nS1\tF1\nS2\n")),
+            "line 3: expected an FID and an IID"
+        );
+        assert!(matches!(
+            SampleList::from_file("/nonexistent/keep.txt"),
+            Err(PgenError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn variant_list() {
+        let path = std::env::temp_dir().join(format!("pgen-rs-extract-{}.txt", std::process::id()));
+        fs::write(&path, "rs1 rs2\n\n\trs3  \nrs1\n").unwrap();
+        let list = VariantList::from_file(&path.to_string_lossy()).unwrap();
+        fs::remove_file(&path).unwrap();
+        for id in ["rs1", "rs2", "rs3"] {
+            assert!(list.contains(id));
+        }
+        assert!(!list.contains("rs4"));
+        assert!(!list.contains(""));
+        let mut ids = list.ids();
+        ids.sort_unstable();
+        assert_eq!(ids, ["rs1", "rs2", "rs3"]);
+    }
+}
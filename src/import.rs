@@ -0,0 +1,490 @@
+//! Creating pfiles from other formats.
+//!
+//! A VCF is read twice: first for the .pvar and .psam and to learn whether
+//! the .pgen needs to be variable-width, then for the genotypes, since the
+//! .pgen header needs the number of variants before any record. A PLINK 1
+//! fileset has those counts in its .bim and .fam.
+
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use crate::pfile::{NonrefFlagsStorage, PgenError, PgenHeader, StorageMode};
+use crate::pgen_record::{
+    bytes_to_represent, clear_genovec_padding, genovec_fill, genovec_get, genovec_set, set_bit,
+    Genotypes,
+};
+use crate::pgen_writer::PgenWriter;
+
+/// The columns of a VCF before the genotypes, which make up the .pvar.
+const SITE_COLUMNS: usize = 8;
+
+/// The alleles of a sample's GT, if not missing, and whether it is phased.
+type Call = Option<(u32, u32, bool)>;
+
+/// The start of a SNP-major .bed.
+const BED_MAGIC: [u8; 3] = [0x6C, 0x1B, 0x01];
+
+/// The .pgen hardcall code of each .bed code, the inverse of the mapping in
+/// `bed_writer`.
+const PGEN_CODES: [u8; 4] = [0b10, 0b11, 0b01, 0b00];
+
+/// Converts a VCF, plain or BGZF-compressed, into OUT_PREFIX.pgen,
+/// OUT_PREFIX.pvar and OUT_PREFIX.psam.
+///
+/// The .pvar keeps the `##` lines of the VCF except for `##fileformat` and
+/// the `##FORMAT` lines, and the .pgen keeps the GT calls. Haploid calls are
+/// stored as homozygous, like plink2 does. The .pgen is fixed-width unless
+/// some variants are multiallelic or some calls are phased.
+pub fn import_vcf(vcf_path: &str, out_prefix: &str) -> Result<(), PgenError> {
+    let mut pvar_writer = BufWriter::new(File::create(format!("{}.pvar", out_prefix))?);
+    let mut sample_ids = None;
+    let mut allele_counts = Vec::new();
+    let mut phased = false;
+    for (line_idx, line) in vcf_lines(vcf_path)?.enumerate() {
+        let line = line?;
+        let invalid = |message: String| invalid_vcf(vcf_path, line_idx, message);
+        if line.starts_with("##") {
+            if !line.starts_with("##fileformat=") && !line.starts_with("##FORMAT=") {
+                writeln!(pvar_writer, "{}", line)?;
+            }
+        } else if let Some(column_names) = line.strip_prefix('#') {
+            let columns = column_names.split('\t').collect::<Vec<&str>>();
+            writeln!(
+                pvar_writer,
+                "#{}",
+                columns[..SITE_COLUMNS.min(columns.len())].join("\t")
+            )?;
+            sample_ids = Some(
+                columns
+                    .iter()
+                    .skip(SITE_COLUMNS + 1)
+                    .map(|id| id.to_string())
+                    .collect::<Vec<String>>(),
+            );
+        } else {
+            let num_samples = sample_ids
+                .as_ref()
+                .ok_or_else(|| invalid("a variant comes before the #CHROM line".to_string()))?
+                .len();
+            let columns = line.split('\t').collect::<Vec<&str>>();
+            let expected_columns = SITE_COLUMNS + if num_samples > 0 { 1 + num_samples } else { 0 };
+            if columns.len() != expected_columns {
+                return Err(invalid(format!(
+                    "expected {} columns, found {}",
+                    expected_columns,
+                    columns.len()
+                )));
+            }
+            writeln!(pvar_writer, "{}", columns[..SITE_COLUMNS].join("\t"))?;
+            allele_counts.push(allele_count(columns[4]));
+            phased = phased || columns[SITE_COLUMNS..].iter().any(|col| col.contains('|'));
+        }
+    }
+    pvar_writer.flush()?;
+    let sample_ids = sample_ids.ok_or_else(|| PgenError::MissingHeaderLine {
+        path: vcf_path.to_string(),
+    })?;
+
+    let mut psam_writer = BufWriter::new(File::create(format!("{}.psam", out_prefix))?);
+    writeln!(psam_writer, "#IID")?;
+    for sample_id in &sample_ids {
+        writeln!(psam_writer, "{}", sample_id)?;
+    }
+    psam_writer.flush()?;
+
+    let max_allele_count = allele_counts.iter().copied().max().unwrap_or(2);
+    let fixed_width = max_allele_count == 2 && !phased;
+    let header = PgenHeader {
+        storage_mode: if fixed_width {
+            StorageMode::FixedWidth
+        } else {
+            StorageMode::VariableWidth
+        },
+        num_variants: allele_counts.len() as u32,
+        num_samples: sample_ids.len() as u32,
+        record_type_bits: if fixed_width { 0 } else { 8 },
+        record_length_bytes: if fixed_width { 0 } else { 4 },
+        allele_count_bytes: if max_allele_count > 2 {
+            bytes_to_represent(max_allele_count)
+        } else {
+            0
+        },
+        nonref_flags: NonrefFlagsStorage::NotStored,
+    };
+    let sam_idxs = (0..sample_ids.len()).collect::<Vec<usize>>();
+    let mut pgen_writer = PgenWriter::create(&format!("{}.pgen", out_prefix), header, Vec::new())?;
+    let mut var_idx = 0;
+    for (line_idx, line) in vcf_lines(vcf_path)?.enumerate() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: String| invalid_vcf(vcf_path, line_idx, message);
+        let allele_count = allele_counts[var_idx];
+        let mut columns = line.split('\t').skip(SITE_COLUMNS);
+        let gt_idx = columns
+            .next()
+            .and_then(|format| format.split(':').position(|key| key == "GT"));
+        let calls = columns
+            .map(
+                |col| match gt_idx.and_then(|gt_idx| col.split(':').nth(gt_idx)) {
+                    Some(gt) => parse_gt(gt, allele_count).map_err(&invalid),
+                    None => Ok(None),
+                },
+            )
+            .collect::<Result<Vec<Call>, PgenError>>()?;
+        pgen_writer.write_variant(&genotypes_of_calls(&calls), &sam_idxs, allele_count)?;
+        var_idx += 1;
+    }
+    pgen_writer.finish()
+}
+
+/// Converts a PLINK 1 fileset, i.e. BED_PREFIX.bed, BED_PREFIX.bim and
+/// BED_PREFIX.fam, into OUT_PREFIX.pgen, OUT_PREFIX.pvar and OUT_PREFIX.psam.
+///
+/// A1 becomes the ALT allele and A2 the REF allele, which the .pgen marks as
+/// provisional since nothing says A2 is the reference, and the .pvar only
+/// keeps the centimorgan positions if some are nonzero. The .psam uses NA for
+/// the missing sexes and phenotypes of the .fam.
+pub fn import_bed(bed_prefix: &str, out_prefix: &str) -> Result<(), PgenError> {
+    let bim_path = format!("{}.bim", bed_prefix);
+    let bim = read_plink_table(&bim_path, 6)?;
+    let fam = read_plink_table(&format!("{}.fam", bed_prefix), 6)?;
+
+    let has_cm = bim.iter().any(|fields| fields[2].parse::<f64>() != Ok(0.0));
+    let mut pvar_writer = BufWriter::new(File::create(format!("{}.pvar", out_prefix))?);
+    writeln!(
+        pvar_writer,
+        "#CHROM\tPOS\tID\tREF\tALT{}",
+        if has_cm { "\tCM" } else { "" }
+    )?;
+    for fields in &bim {
+        let alt = if fields[4] == "0" { "." } else { &fields[4] };
+        write!(
+            pvar_writer,
+            "{}\t{}\t{}\t{}\t{}",
+            fields[0], fields[3], fields[1], fields[5], alt
+        )?;
+        if has_cm {
+            write!(pvar_writer, "\t{}", fields[2])?;
+        }
+        writeln!(pvar_writer)?;
+    }
+    pvar_writer.flush()?;
+
+    let mut psam_writer = BufWriter::new(File::create(format!("{}.psam", out_prefix))?);
+    writeln!(psam_writer, "#FID\tIID\tPAT\tMAT\tSEX\tPHENO1")?;
+    for fields in &fam {
+        let sex = if ["1", "2"].contains(&fields[4].as_str()) {
+            &fields[4]
+        } else {
+            "NA"
+        };
+        let pheno = if fields[5] == "-9" { "NA" } else { &fields[5] };
+        writeln!(
+            psam_writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            fields[0], fields[1], fields[2], fields[3], sex, pheno
+        )?;
+    }
+    psam_writer.flush()?;
+
+    let bed_path = format!("{}.bed", bed_prefix);
+    let invalid = |message: String| PgenError::InvalidInput {
+        path: bed_path.clone(),
+        message,
+    };
+    let mut bed = BufReader::new(File::open(&bed_path)?);
+    let mut magic = [0u8; 3];
+    bed.read_exact(&mut magic)
+        .map_err(|_| invalid("too short to be a .bed".to_string()))?;
+    if magic[..2] != BED_MAGIC[..2] {
+        return Err(invalid("not a .bed file".to_string()));
+    }
+    if magic[2] != BED_MAGIC[2] {
+        return Err(invalid(
+            "only SNP-major .bed files are supported".to_string(),
+        ));
+    }
+
+    let header = PgenHeader {
+        storage_mode: StorageMode::FixedWidth,
+        num_variants: bim.len() as u32,
+        num_samples: fam.len() as u32,
+        record_type_bits: 0,
+        record_length_bytes: 0,
+        allele_count_bytes: 0,
+        nonref_flags: NonrefFlagsStorage::AllSet,
+    };
+    let mut pgen_codes = [0u8; 256];
+    for (byte, pgen_byte) in pgen_codes.iter_mut().enumerate() {
+        for sam_idx in 0..4 {
+            let code = genovec_get(&[byte as u8], sam_idx);
+            genovec_set(
+                std::slice::from_mut(pgen_byte),
+                sam_idx,
+                PGEN_CODES[code as usize],
+            );
+        }
+    }
+    let sam_idxs = (0..fam.len()).collect::<Vec<usize>>();
+    let mut pgen_writer = PgenWriter::create(&format!("{}.pgen", out_prefix), header, Vec::new())?;
+    let mut row = vec![0u8; fam.len().div_ceil(4)];
+    for var_idx in 0..bim.len() {
+        bed.read_exact(&mut row).map_err(|_| {
+            invalid(format!(
+                "ends at variant {} of the {} in {}",
+                var_idx,
+                bim.len(),
+                bim_path
+            ))
+        })?;
+        let mut hardcalls = row
+            .iter()
+            .map(|&byte| pgen_codes[byte as usize])
+            .collect::<Vec<u8>>();
+        clear_genovec_padding(&mut hardcalls, fam.len() as u32);
+        pgen_writer.write_variant(&Genotypes::unphased(hardcalls), &sam_idxs, 2)?;
+    }
+    pgen_writer.finish()
+}
+
+/// Reads a .bim or .fam, whose lines have `num_fields` fields separated by
+/// whitespace.
+fn read_plink_table(path: &str, num_fields: usize) -> Result<Vec<Vec<String>>, PgenError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut rows = Vec::new();
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields = line
+            .split_whitespace()
+            .map(|field| field.to_string())
+            .collect::<Vec<String>>();
+        if fields.len() != num_fields {
+            return Err(PgenError::InvalidInput {
+                path: path.to_string(),
+                message: format!(
+                    "line {}: expected {} fields, found {}",
+                    line_idx + 1,
+                    num_fields,
+                    fields.len()
+                ),
+            });
+        }
+        rows.push(fields);
+    }
+    Ok(rows)
+}
+
+/// The lines of a VCF, decompressing it if it starts like a gzip file.
+fn vcf_lines(vcf_path: &str) -> Result<std::io::Lines<Box<dyn BufRead>>, PgenError> {
+    let mut vcf = BufReader::new(File::open(vcf_path)?);
+    let reader: Box<dyn BufRead> = if vcf.fill_buf()?.starts_with(&[0x1F, 0x8B]) {
+        Box::new(BufReader::new(MultiGzDecoder::new(vcf)))
+    } else {
+        Box::new(vcf)
+    };
+    Ok(reader.lines())
+}
+
+fn invalid_vcf(vcf_path: &str, line_idx: usize, message: String) -> PgenError {
+    PgenError::InvalidInput {
+        path: vcf_path.to_string(),
+        message: format!("line {}: {}", line_idx + 1, message),
+    }
+}
+
+/// Number of alleles (REF included) of a variant with the given ALT column,
+/// where a missing ALT still counts as an allele.
+fn allele_count(alt: &str) -> u32 {
+    alt.split(',').count() as u32 + 1
+}
+
+/// Parses a GT field. Calls with any missing allele are missing, and
+/// haploid calls are homozygous.
+fn parse_gt(gt: &str, allele_count: u32) -> Result<Call, String> {
+    let alleles = gt.split(['/', '|']).collect::<Vec<&str>>();
+    if alleles.len() > 2 {
+        return Err(format!("GT {} is polyploid", gt));
+    }
+    if alleles.contains(&".") {
+        return Ok(None);
+    }
+    let alleles = alleles
+        .iter()
+        .map(|allele| {
+            allele
+                .parse::<u32>()
+                .ok()
+                .filter(|&allele| allele < allele_count)
+                .ok_or_else(|| format!("invalid allele {} in GT {}", allele, gt))
+        })
+        .collect::<Result<Vec<u32>, String>>()?;
+    let second = alleles.get(1).copied().unwrap_or(alleles[0]);
+    Ok(Some((alleles[0], second, gt.contains('|'))))
+}
+
+/// The genotypes of a record with the given calls, as `PgenWriter` takes
+/// them: hardcalls, with patches for the calls involving an ALT allele other
+/// than the first, and the phase of phased hets. The record is phased if any
+/// call is, hom or het.
+fn genotypes_of_calls(calls: &[Call]) -> Genotypes {
+    let num_samples = calls.len();
+    let mut hardcalls = genovec_fill(num_samples as u32, 0b00);
+    let mut multiallelic_patches = HashMap::new();
+    let mut phase_present = vec![0u8; num_samples.div_ceil(8)];
+    let mut phase_info = vec![0u8; num_samples.div_ceil(8)];
+    let mut phased = false;
+    for (sam_idx, call) in calls.iter().enumerate() {
+        let Some((first, second, is_phased)) = *call else {
+            genovec_set(&mut hardcalls, sam_idx, 0b11);
+            continue;
+        };
+        let (low, high) = (first.min(second), first.max(second));
+        let code = match (low, high) {
+            (0, 0) => 0b00,
+            (0, _) => 0b01,
+            _ => 0b10,
+        };
+        genovec_set(&mut hardcalls, sam_idx, code);
+        if (code == 0b01 && high > 1) || (code == 0b10 && (low, high) != (1, 1)) {
+            multiallelic_patches.insert(sam_idx, (low, high));
+        }
+        // homs are phased along with their record, without a phase bit
+        phased = phased || is_phased;
+        if is_phased && low != high {
+            set_bit(&mut phase_present, sam_idx);
+            if first > second {
+                set_bit(&mut phase_info, sam_idx);
+            }
+        }
+    }
+
+    let mut genotypes = Genotypes::unphased(hardcalls);
+    genotypes.multiallelic_patches = multiallelic_patches;
+    if phased {
+        genotypes.phased = true;
+        genotypes.phase_present = phase_present;
+        genotypes.phase_info = phase_info;
+    }
+    genotypes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pfile::{Pfile, SampleFilter, VariantFilter};
+    use std::fs;
+
+    /// The GT columns of the records of a VCF.
+    fn vcf_calls(vcf: &str) -> Vec<String> {
+        vcf.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                line.split('\t')
+                    .skip(SITE_COLUMNS + 1)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn import_then_filter() {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("calls").to_string_lossy().into_owned();
+        let vcf_path = format!("{}.vcf", prefix);
+        let mut vcf = "##fileformat=VCFv4.2\n".to_string();
+        vcf.push_str("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\ta\tb\tc\n");
+        for (pos, alt, calls) in [
+            (10, "G", "0|1\t1|1\t./."),
+            (20, "T", "1|1\t0|0\t1|1"),
+            (30, "T", "0/1\t1/1\t0/0"),
+            (40, "T,G", "0/2\t1/2\t0/1"),
+            (50, "T,G", "2|0\t1|2\t2|2"),
+        ] {
+            vcf.push_str(&format!(
+                "1\t{}\t.\tA\t{}\t.\t.\t.\tGT\t{}\n",
+                pos, alt, calls
+            ));
+        }
+        fs::write(&vcf_path, &vcf).unwrap();
+        import_vcf(&vcf_path, &prefix).unwrap();
+
+        let pfile = Pfile::from_prefix(prefix.clone()).unwrap();
+        let out_path = dir.join("out.vcf");
+        let filter = |sam_filter, var_filter| {
+            pfile
+                .output_vcf(sam_filter, var_filter, out_path.clone(), false)
+                .unwrap();
+            vcf_calls(&fs::read_to_string(&out_path).unwrap())
+        };
+        assert_eq!(
+            filter(SampleFilter::default(), VariantFilter::default()),
+            vcf_calls(&vcf)
+        );
+        let sam_filter = SampleFilter {
+            query: Some(r#"IID != "a""#.to_string()),
+            ..SampleFilter::default()
+        };
+        let var_filter = VariantFilter {
+            query: Some("POS >= 20".to_string()),
+            ..VariantFilter::default()
+        };
+        assert_eq!(
+            filter(sam_filter, var_filter),
+            ["0|0 1|1", "1/1 0/0", "1/2 0/1", "1|2 2|2"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bed_round_trip() {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-bed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let prefix = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        // a variant without A1, and one with a centimorgan position
+        let bim = "1\trs1\t0\t10\tG\tA\n1\trs2\t0\t20\t0\tC\n2\trs3\t1.5\t5\tT\tC\n";
+        let fam = "F1\tS1\t0\t0\t1\t-9\nF1\tS2\t0\t0\t2\t1.5\nF2\tS3\tS1\tS2\t0\t-9\n";
+        // hom A2, het and hom A1, then a missing call, then two hets
+        let bed = [0x6C, 0x1B, 0x01, 0b00_10_11, 0b01_11_11, 0b10_00_10];
+        fs::write(format!("{}.bim", prefix("in")), bim).unwrap();
+        fs::write(format!("{}.fam", prefix("in")), fam).unwrap();
+        fs::write(format!("{}.bed", prefix("in")), bed).unwrap();
+        import_bed(&prefix("in"), &prefix("pfile")).unwrap();
+
+        let pvar = fs::read_to_string(format!("{}.pvar", prefix("pfile"))).unwrap();
+        assert_eq!(
+            pvar,
+            "#CHROM\tPOS\tID\tREF\tALT\tCM\n1\t10\trs1\tA\tG\t0\n\
+             1\t20\trs2\tC\t.\t0\n2\t5\trs3\tC\tT\t1.5\n"
+        );
+        let pfile = Pfile::from_prefix(prefix("pfile")).unwrap();
+        pfile
+            .output_bed(
+                SampleFilter::default(),
+                VariantFilter::default(),
+                &prefix("out"),
+            )
+            .unwrap();
+        let read = |extension: &str| fs::read(format!("{}.{}", prefix("out"), extension)).unwrap();
+        assert_eq!(read("bim"), bim.as_bytes());
+        assert_eq!(read("fam"), fam.as_bytes());
+        assert_eq!(read("bed"), bed);
+
+        // several ALT alleles can't go in a .bed, even if the .pgen doesn't
+        // count them
+        let pvar_path = format!("{}.pvar", prefix("pfile"));
+        fs::write(&pvar_path, pvar.replace("\tG\t", "\tG,T\t")).unwrap();
+        let output = pfile.output_bed(
+            SampleFilter::default(),
+            VariantFilter::default(),
+            &prefix("out"),
+        );
+        assert!(matches!(output, Err(PgenError::Unencodable(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
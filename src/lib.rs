@@ -0,0 +1,34 @@
+//! Reading .pgen files along with their .pvar and .psam metadata.
+//!
+//! `PgenReader` is the entry point for reading genotypes; `Pfile` has the
+//! querying and filtering behind the `pgen-rs` CLI.
+
+mod bcf;
+mod bed_writer;
+mod bgzf;
+mod expression;
+mod filter_parser;
+mod format_string;
+mod id_lists;
+mod import;
+mod metadata_file;
+mod pfile;
+mod pgen_record;
+mod pgen_writer;
+mod pvar_index;
+mod pvar_parser;
+mod reader;
+mod regions;
+mod tabix;
+
+pub use expression::ExpressionError;
+pub use id_lists::{SampleList, VariantList};
+pub use import::{import_bed, import_vcf};
+pub use pfile::{
+    NonrefFlagsStorage, Pfile, PgenError, PgenHeader, QueryFormat, QueryType, SampleFilter,
+    StorageMode, VariantFilter,
+};
+pub use pgen_record::Genotypes;
+pub use reader::{PgenReader, Samples, Variants};
+pub use regions::Regions;
+pub use tabix::IndexFormat;
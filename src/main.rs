@@ -1,16 +1,17 @@
 mod cli;
-mod pfile;
-mod pvar_parser;
-mod filter_parser;
 
 use actix_web::{web, HttpResponse, Responder};
 use clap::Parser;
-use cli::{Cli, Commands};
-use pfile::Pfile;
+use cli::{Cli, Commands, IdListArgs, InFormat, IndexFormatArg, OutFormat, RegionArgs};
+use pgen_rs::{
+    import_bed, import_vcf, IndexFormat, PgenError, Pfile, QueryFormat, QueryType, SampleFilter,
+    VariantFilter,
+};
 
 use serde::{Deserialize, Serialize};
 use shellwords::split;
 use std::error::Error as StdError;
+use std::path::PathBuf;
 
 async fn index() -> HttpResponse {
     HttpResponse::Ok()
@@ -30,12 +31,6 @@ async fn scripts() -> impl Responder {
         .body(include_str!("scripts.js"))
 }
 
-#[derive(Deserialize)]
-enum QueryType {
-    Variant,
-    Sample,
-}
-
 #[derive(Deserialize)]
 struct FetchAIRequest {
     pfile_prefix: String,
@@ -43,13 +38,25 @@ struct FetchAIRequest {
     query_type: QueryType,
 }
 
-async fn fetch_ai_response(req_body: web::Json<FetchAIRequest>) -> impl Responder {
+async fn fetch_ai_response(
+    config: web::Data<ServeConfig>,
+    req_body: web::Json<FetchAIRequest>,
+) -> impl Responder {
     // Read the secret from the environment variable
-    let api_key = std::env::var("OPENAI_KEY")
-        .expect("SECRET_KEY must be set in .env file or environment variable");
+    let api_key = match std::env::var("OPENAI_KEY") {
+        Ok(api_key) => api_key,
+        Err(_) => {
+            return HttpResponse::InternalServerError()
+                .body("Error: OPENAI_KEY must be set in .env file or environment variable");
+        }
+    };
+    let pfile_prefix = match config.resolve(&req_body.pfile_prefix) {
+        Ok(pfile_prefix) => pfile_prefix,
+        Err(message) => return HttpResponse::BadRequest().body(format!("Error: {}", message)),
+    };
     // Call the fetch_response_from_ai function with the provided prompt and API key
     match fetch_response_from_ai(
-        &req_body.pfile_prefix,
+        &pfile_prefix,
         &req_body.query_type,
         &req_body.prompt,
         &api_key,
@@ -66,76 +73,317 @@ struct SubmitQueryRequest {
     query: String, // Define the fields of the request as needed
 }
 
-async fn submit_query(req_body: web::Json<SubmitQueryRequest>) -> impl Responder {
+/// Settings shared by all of the web server's handlers.
+struct ServeConfig {
+    /// Directory that the paths coming from the web interface are resolved
+    /// against, canonicalized.
+    data_root: PathBuf,
+}
+
+impl ServeConfig {
+    /// Resolves a path from the web interface against the data root,
+    /// rejecting it if it leads outside of the root, whether by being
+    /// absolute, through `..` or through a symlink. The path itself needn't
+    /// exist, like a pfile prefix or an output file, but its directory must.
+    fn resolve(&self, path: &str) -> Result<String, String> {
+        let outside = || format!("{} is outside the data root", path);
+        let joined = self.data_root.join(path);
+        let resolved = match joined.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                // file_name is None for a path ending in ..
+                let (Some(dir), Some(file_name)) = (joined.parent(), joined.file_name()) else {
+                    return Err(outside());
+                };
+                let dir = dir
+                    .canonicalize()
+                    .map_err(|_| format!("the directory of {} doesn't exist", path))?;
+                dir.join(file_name)
+            }
+        };
+        if !resolved.starts_with(&self.data_root) {
+            return Err(outside());
+        }
+        Ok(resolved.to_string_lossy().into_owned())
+    }
+
+    /// Resolves every path a command from the web interface reads or
+    /// writes, see `resolve`.
+    fn resolve_paths(&self, command: &mut Commands) -> Result<(), String> {
+        let resolve = |path: &mut String| -> Result<(), String> {
+            *path = self.resolve(path)?;
+            Ok(())
+        };
+        let resolve_lists = |regions: &mut RegionArgs, id_lists: &mut IdListArgs| {
+            let IdListArgs {
+                keep,
+                remove,
+                extract,
+                exclude,
+            } = id_lists;
+            [&mut regions.regions_file, keep, remove, extract, exclude]
+                .into_iter()
+                .flatten()
+                .try_for_each(resolve)
+        };
+        match command {
+            Commands::Query {
+                pfile_prefix,
+                regions,
+                id_lists,
+                ..
+            } => {
+                resolve(pfile_prefix)?;
+                resolve_lists(regions, id_lists)
+            }
+            Commands::Filter {
+                pfile_prefix,
+                regions,
+                id_lists,
+                out_file,
+                ..
+            } => {
+                resolve(pfile_prefix)?;
+                resolve_lists(regions, id_lists)?;
+                if let Some(out_file) = out_file {
+                    *out_file = self.resolve(&out_file.to_string_lossy())?.into();
+                }
+                Ok(())
+            }
+            Commands::Import {
+                input, out_prefix, ..
+            } => {
+                resolve(input)?;
+                out_prefix.iter_mut().try_for_each(resolve)
+            }
+            Commands::Index { pfile_prefix } => resolve(pfile_prefix),
+            Commands::Serve { .. } => Ok(()),
+        }
+    }
+}
+
+async fn submit_query(
+    config: web::Data<ServeConfig>,
+    req_body: web::Json<SubmitQueryRequest>,
+) -> impl Responder {
     // Access the query from the request body
     let user_query = req_body.query.clone();
     println!("Received user query: {}", user_query);
 
     // Split the user query using shell parsing rules
-    let user_query_parts = split(&user_query).unwrap();
+    let user_query_parts = match split(&user_query) {
+        Ok(parts) => parts,
+        Err(error) => {
+            return HttpResponse::BadRequest().body(format!("Error parsing user query: {}", error));
+        }
+    };
     println!("PARTS {:?}", user_query_parts);
     let cli_result = Cli::try_parse_from(user_query_parts);
 
     println!("RESULT {:?}", cli_result);
-    let cli = match cli_result {
+    let mut cli = match cli_result.and_then(|cli| cli.command.validate().map(|()| cli)) {
         Ok(cli) => cli,
         Err(error) => {
             return HttpResponse::BadRequest().body(format!("Error parsing user query: {}", error));
         }
     };
+    if let Err(message) = config.resolve_paths(&mut cli.command) {
+        return HttpResponse::BadRequest().body(format!("Error: {}", message));
+    }
 
     // Execute the corresponding command
     match cli.command {
-        Some(Commands::Query {
+        Commands::Query {
             pfile_prefix,
             query_fstring,
+            fstring_expression,
             query,
             query_samples,
-        }) => {
-            let pfile = Pfile::from_prefix(pfile_prefix);
-            if query_samples {
-                let mut reader = pfile.psam_reader().unwrap();
-                pfile
-                    .query_metadata(&mut reader, query, query_fstring)
-                    .unwrap();
-            } else {
-                let mut reader = pfile.pvar_reader().unwrap();
-                pfile
-                    .query_metadata(&mut reader, query, query_fstring)
-                    .unwrap();
+            regions,
+            id_lists,
+        } => {
+            let format = query_format(query_fstring, fstring_expression);
+            let selection = (regions, id_lists);
+            match run_query(pfile_prefix, format, query, query_samples, selection) {
+                Ok(()) => HttpResponse::Ok().body("Success: executed query"),
+                Err(err) => error_response(err),
             }
-            return HttpResponse::Ok().body("Success: executed query");
         }
-        Some(Commands::Filter {
+        Commands::Filter {
             pfile_prefix,
             var_query,
             sam_query,
+            regions,
+            id_lists,
             out_file,
-        }) => {
-            let pfile = Pfile::from_prefix(pfile_prefix);
-            let out_file =
-                out_file.unwrap_or_else(|| format!("{}.pgen-rs.vcf", pfile.pfile_prefix).into());
-            pfile.output_vcf(sam_query, var_query, out_file).unwrap();
-            return HttpResponse::Ok().body("Success: created VCF file");
+            out_format,
+            index_format,
+            dosage,
+        } => {
+            let out_options = (out_format, index_format, dosage);
+            let queries = (var_query, sam_query);
+            let selection = (regions, id_lists);
+            match run_filter(pfile_prefix, queries, selection, out_file, out_options) {
+                Ok(()) => HttpResponse::Ok().body("Success: created output files"),
+                Err(err) => error_response(err),
+            }
+        }
+        Commands::Import {
+            input,
+            in_format,
+            out_prefix,
+        } => match run_import(input, in_format, out_prefix) {
+            Ok(()) => HttpResponse::Ok().body("Success: created output files"),
+            Err(err) => error_response(err),
+        },
+        Commands::Index { pfile_prefix } => match run_index(pfile_prefix) {
+            Ok(()) => HttpResponse::Ok().body("Success: created the index"),
+            Err(err) => error_response(err),
+        },
+        Commands::Serve { .. } => {
+            HttpResponse::BadRequest().body("Invalid user query: cannot start a server from the web interface")
         }
-        None => {
-            return HttpResponse::BadRequest().body("Invalid user query: No command provided");
+    }
+}
+
+/// Blames the user for errors in what they typed, and the server for the
+/// rest.
+fn error_response(err: PgenError) -> HttpResponse {
+    match err {
+        PgenError::Expression { .. }
+        | PgenError::MissingColumn { .. }
+        | PgenError::InvalidRegion { .. } => {
+            HttpResponse::BadRequest().body(format!("Error: {}", err))
         }
+        _ => HttpResponse::InternalServerError().body(format!("Error: {}", err)),
+    }
+}
+
+/// The fstring of `query`, which `--expression` makes an expression.
+fn query_format(query_fstring: String, fstring_expression: bool) -> QueryFormat {
+    if fstring_expression {
+        QueryFormat::Expression(query_fstring)
+    } else {
+        QueryFormat::FormatString(query_fstring)
+    }
+}
+
+/// Runs the `query` subcommand, printing the results to stdout.
+fn run_query(
+    pfile_prefix: String,
+    format: QueryFormat,
+    query: Option<String>,
+    query_samples: bool,
+    selection: (RegionArgs, IdListArgs),
+) -> Result<(), PgenError> {
+    let (query_type, queries) = if query_samples {
+        (QueryType::Sample, (None, query))
+    } else {
+        (QueryType::Variant, (query, None))
     };
+    let (var_filter, sam_filter) = filters(queries, selection)?;
+    let pfile = Pfile::from_prefix(pfile_prefix)?;
+    pfile.query_metadata(&query_type, var_filter, sam_filter, format)
+}
 
-    HttpResponse::Ok().finish() // Return a response indicating success
+/// Runs the `filter` subcommand, writing the results in `out_format`.
+fn run_filter(
+    pfile_prefix: String,
+    queries: (Option<String>, Option<String>),
+    selection: (RegionArgs, IdListArgs),
+    out_file: Option<PathBuf>,
+    (out_format, index_format, dosage): (OutFormat, Option<IndexFormatArg>, bool),
+) -> Result<(), PgenError> {
+    let (var_filter, sam_filter) = filters(queries, selection)?;
+    let pfile = Pfile::from_prefix(pfile_prefix)?;
+    match out_format {
+        OutFormat::Vcf => {
+            let out_file = out_file
+                .unwrap_or_else(|| format!("{}.pgen-rs.vcf", pfile.pfile_prefix()).into());
+            pfile.output_vcf(sam_filter, var_filter, out_file, dosage)
+        }
+        OutFormat::VcfGz => {
+            let out_file = out_file
+                .unwrap_or_else(|| format!("{}.pgen-rs.vcf.gz", pfile.pfile_prefix()).into());
+            let index_format = index_format.map(IndexFormat::from);
+            pfile.output_vcf_gz(sam_filter, var_filter, out_file, dosage, index_format)
+        }
+        OutFormat::Bcf => {
+            let out_file = out_file
+                .unwrap_or_else(|| format!("{}.pgen-rs.bcf", pfile.pfile_prefix()).into());
+            pfile.output_bcf(sam_filter, var_filter, out_file, dosage)
+        }
+        OutFormat::Pgen | OutFormat::Bed => {
+            let out_prefix = out_file.map_or_else(
+                || format!("{}.pgen-rs", pfile.pfile_prefix()),
+                |out_file| out_file.to_string_lossy().into_owned(),
+            );
+            if out_format == OutFormat::Pgen {
+                pfile.output_pgen(sam_filter, var_filter, &out_prefix)
+            } else {
+                pfile.output_bed(sam_filter, var_filter, &out_prefix)
+            }
+        }
+    }
 }
 
-fn main() {
-    // test_pgen();
+/// The variants and samples to keep, given the variant and sample queries
+/// and the regions and ID lists, which are read here.
+fn filters(
+    (var_query, sam_query): (Option<String>, Option<String>),
+    (regions, id_lists): (RegionArgs, IdListArgs),
+) -> Result<(VariantFilter, SampleFilter), PgenError> {
+    let (extract, exclude) = id_lists.variant_lists()?;
+    let (keep, remove) = id_lists.sample_lists()?;
+    let var_filter = VariantFilter {
+        query: var_query,
+        regions: regions.regions()?,
+        extract,
+        exclude,
+    };
+    let sam_filter = SampleFilter {
+        query: sam_query,
+        keep,
+        remove,
+    };
+    Ok((var_filter, sam_filter))
+}
 
-    // Load environment variables from the .env file
-    dotenv::dotenv().ok();
+/// Runs the `import` subcommand, writing the pfile next to the input unless
+/// given a prefix.
+fn run_import(
+    input: String,
+    in_format: InFormat,
+    out_prefix: Option<String>,
+) -> Result<(), PgenError> {
+    match in_format {
+        InFormat::Vcf => {
+            let out_prefix = out_prefix.unwrap_or_else(|| {
+                let stem = input.strip_suffix(".gz").unwrap_or(&input);
+                stem.strip_suffix(".vcf").unwrap_or(stem).to_string()
+            });
+            import_vcf(&input, &out_prefix)
+        }
+        InFormat::Bed => import_bed(&input, out_prefix.as_deref().unwrap_or(&input)),
+    }
+}
+
+/// Runs the `index` subcommand, writing the index next to the .pvar.
+fn run_index(pfile_prefix: String) -> Result<(), PgenError> {
+    Pfile::from_prefix(pfile_prefix)?.index_pvar()?;
+    Ok(())
+}
 
+/// Runs the `serve` subcommand, blocking until the web server shuts down.
+fn serve(address: String, port: u16, data_root: PathBuf) -> std::io::Result<()> {
+    // paths are checked against the canonical root, see ServeConfig::resolve
+    let data_root = data_root.canonicalize()?;
     // Start Actix-web server to serve the HTML page and handle API requests
-    actix_web::rt::System::new().block_on(async {
-        let server = actix_web::HttpServer::new(|| {
+    actix_web::rt::System::new().block_on(async move {
+        let config = web::Data::new(ServeConfig { data_root });
+        let server = actix_web::HttpServer::new(move || {
             actix_web::App::new()
+                .app_data(config.clone())
                 .route("/", actix_web::web::get().to(index))
                 .route("/styles.css", web::get().to(styles))
                 .route("/scripts.js", web::get().to(scripts))
@@ -145,15 +393,71 @@ fn main() {
                 )
                 .route("/submit_query", actix_web::web::post().to(submit_query))
         })
-        .bind("127.0.0.1:8080")
-        .unwrap()
+        .bind((address.as_str(), port))?
         .run();
 
-        println!("Server running at http://127.0.0.1:8080");
+        println!("Server running at http://{}:{}", address, port);
 
         // Wait for the server to finish running
-        let _ = server.await;
-    });
+        server.await
+    })
+}
+
+fn main() {
+    // Load environment variables from the .env file
+    dotenv::dotenv().ok();
+
+    let cli = Cli::parse();
+    if let Err(err) = cli.command.validate() {
+        err.exit();
+    }
+    let result = match cli.command {
+        Commands::Query {
+            pfile_prefix,
+            query_fstring,
+            fstring_expression,
+            query,
+            query_samples,
+            regions,
+            id_lists,
+        } => {
+            let format = query_format(query_fstring, fstring_expression);
+            let selection = (regions, id_lists);
+            run_query(pfile_prefix, format, query, query_samples, selection)
+        }
+        Commands::Filter {
+            pfile_prefix,
+            var_query,
+            sam_query,
+            regions,
+            id_lists,
+            out_file,
+            out_format,
+            index_format,
+            dosage,
+        } => {
+            let out_options = (out_format, index_format, dosage);
+            let queries = (var_query, sam_query);
+            let selection = (regions, id_lists);
+            run_filter(pfile_prefix, queries, selection, out_file, out_options)
+        }
+        Commands::Import {
+            input,
+            in_format,
+            out_prefix,
+        } => run_import(input, in_format, out_prefix),
+        Commands::Index { pfile_prefix } => run_index(pfile_prefix),
+        Commands::Serve {
+            address,
+            port,
+            data_root,
+        } => serve(address, port, data_root).map_err(PgenError::from),
+    };
+
+    if let Err(err) = result {
+        eprintln!("pgen-rs: {}", err);
+        std::process::exit(err.exit_code());
+    }
 }
 
 #[derive(Serialize)]
@@ -175,7 +479,7 @@ async fn fetch_response_from_ai(
     prompt: &str,
     api_key: &str,
 ) -> Result<String, Box<dyn StdError>> {
-    let pfile = Pfile::from_prefix(pfile_prefix.to_string());
+    let pfile = Pfile::from_prefix(pfile_prefix.to_string())?;
     let full_prompt = pfile.create_ai_query(query_type, prompt)?;
     let client = reqwest::Client::new();
     let request_body = OpenAIRequest {
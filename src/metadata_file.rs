@@ -0,0 +1,314 @@
+//! Finding the .pvar and .psam of a pfile, or the PLINK 1 .bim and .fam that
+//! plink2 also accepts in their place.
+//!
+//! plink2 can also compress the .pvar (and .psam) with zstd, into a
+//! `.pvar.zst`, which is decompressed while reading it.
+//!
+//! A .pvar or .psam normally ends its header with the column names, on a
+//! line starting with `#`. Files without that line, i.e. .bim and .fam files
+//! and headerless .pvar and .psam files, have fixed columns separated by any
+//! whitespace, which are given the names plink2 uses for them.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// The columns of a .bim, or of a .pvar without a header line.
+const BIM_COLUMNS: [&str; 6] = ["CHROM", "ID", "CM", "POS", "ALT", "REF"];
+/// The columns of a .fam, or of a .psam without a header line.
+const FAM_COLUMNS: [&str; 6] = ["FID", "IID", "PAT", "MAT", "SEX", "PHENO1"];
+
+/// The .pvar or .psam of a pfile, or what stands in for it.
+#[derive(Debug)]
+pub struct MetadataFile {
+    pub path: String,
+    /// The column names of a file without a header line, whose fields are
+    /// separated by any whitespace.
+    pub synthesized_columns: Option<Vec<&'static str>>,
+}
+
+impl MetadataFile {
+    /// PREFIX.pvar, or PREFIX.pvar.zst or PREFIX.bim if there is no .pvar.
+    pub fn find_pvar(pfile_prefix: &str) -> MetadataFile {
+        // the CM column is optional
+        MetadataFile::find(pfile_prefix, "pvar", "bim", &BIM_COLUMNS, 2)
+    }
+
+    /// PREFIX.psam, or PREFIX.psam.zst or PREFIX.fam if there is no .psam.
+    pub fn find_psam(pfile_prefix: &str) -> MetadataFile {
+        // the phenotype column is optional
+        MetadataFile::find(pfile_prefix, "psam", "fam", &FAM_COLUMNS, 5)
+    }
+
+    /// Finds the file and whether it has a header line. Headerless files can
+    /// lack the column at `optional_column`. Problems reading the file are
+    /// left for when it is actually read.
+    fn find(
+        pfile_prefix: &str,
+        extension: &str,
+        plink1_extension: &str,
+        columns: &[&'static str],
+        optional_column: usize,
+    ) -> MetadataFile {
+        let candidates = [
+            format!("{}.{}", pfile_prefix, extension),
+            format!("{}.{}.zst", pfile_prefix, extension),
+            format!("{}.{}", pfile_prefix, plink1_extension),
+        ];
+        let path = candidates
+            .iter()
+            .find(|path| Path::new(path).exists())
+            .unwrap_or(&candidates[0])
+            .clone();
+        let synthesized_columns = first_line(&path)
+            .ok()
+            .flatten()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let num_fields = line.split_whitespace().count();
+                columns
+                    .iter()
+                    .enumerate()
+                    .filter(|&(col_idx, _)| {
+                        num_fields >= columns.len() || col_idx != optional_column
+                    })
+                    .map(|(_, &column)| column)
+                    .collect()
+            });
+        MetadataFile {
+            path,
+            synthesized_columns,
+        }
+    }
+
+    /// Opens the file, decompressing it if it is a `.zst`.
+    pub fn open(&self) -> io::Result<Box<dyn BufRead>> {
+        open(&self.path)
+    }
+}
+
+fn open(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if path.ends_with(".zst") {
+        Ok(Box::new(BufReader::new(zstd::Decoder::new(file)?)))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// The first line of a file that isn't a `##` comment, if any.
+fn first_line(path: &str) -> io::Result<Option<String>> {
+    for line in open(path)?.lines() {
+        let line = line?;
+        if !line.starts_with("##") {
+            return Ok(Some(line));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads a file whose fields are separated by any whitespace as if they were
+/// separated by single tabs, for the csv reader.
+pub struct WhitespaceToTabs<R> {
+    inner: R,
+    line: String,
+    converted: Vec<u8>,
+    /// How much of `converted` has been read.
+    pos: usize,
+}
+
+impl<R: BufRead> WhitespaceToTabs<R> {
+    pub fn new(inner: R) -> WhitespaceToTabs<R> {
+        WhitespaceToTabs {
+            inner,
+            line: String::new(),
+            converted: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Read for WhitespaceToTabs<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.converted.len() {
+            self.line.clear();
+            self.converted.clear();
+            self.pos = 0;
+            if self.inner.read_line(&mut self.line)? == 0 {
+                return Ok(0);
+            }
+            for (field_idx, field) in self.line.split_whitespace().enumerate() {
+                if field_idx > 0 {
+                    self.converted.push(b'\t');
+                }
+                self.converted.extend_from_slice(field.as_bytes());
+            }
+            self.converted.push(b'\n');
+        }
+        let len = buf.len().min(self.converted.len() - self.pos);
+        buf[..len].copy_from_slice(&self.converted[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A fresh directory for the fixtures of a test.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn synthesized_columns() {
+        let dir = fixture_dir("metadata-columns");
+        let prefix = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let cases = [
+            ("bim", "1\trs1\t0\t10\tG\tA\n", Some(BIM_COLUMNS.to_vec())),
+            (
+                "bim",
+                "1 rs1 10  G A\n",
+                Some(vec!["CHROM", "ID", "POS", "ALT", "REF"]),
+            ),
+            ("pvar", "1\trs1\t0\t10\tG\tA\n", Some(BIM_COLUMNS.to_vec())),
+            (
+                "pvar",
+                "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\n",
+                None,
+            ),
+            ("fam", "F1 S1 0 0 1 -9\n", Some(FAM_COLUMNS.to_vec())),
+            (
+                "fam",
+                "F1\tS1\t0\t0\t1\n",
+                Some(vec!["FID", "IID", "PAT", "MAT", "SEX"]),
+            ),
+            ("psam", "F1 S1 0 0 1 -9\n", Some(FAM_COLUMNS.to_vec())),
+            ("psam", "#IID\tSEX\nS1\t1\n", None),
+        ];
+        for (case_idx, (extension, contents, columns)) in cases.into_iter().enumerate() {
+            let prefix = prefix(&format!("case{}", case_idx));
+            let path = format!("{}.{}", prefix, extension);
+            fs::write(&path, contents).unwrap();
+            let metadata_file = match extension {
+                "pvar" | "bim" => MetadataFile::find_pvar(&prefix),
+                _ => MetadataFile::find_psam(&prefix),
+            };
+            assert_eq!(metadata_file.path, path);
+            assert_eq!(metadata_file.synthesized_columns, columns, "{}", path);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pfile_metadata_before_plink1() {
+        let dir = fixture_dir("metadata-plink1");
+        let prefix = dir.join("both").to_string_lossy().into_owned();
+        // missing files are left to fail when read
+        let missing = MetadataFile::find_pvar(&prefix);
+        assert_eq!(missing.path, format!("{}.pvar", prefix));
+        assert!(missing.synthesized_columns.is_none());
+        assert!(missing.open().is_err());
+
+        fs::write(format!("{}.bim", prefix), "1\trs1\t0\t10\tG\tA\n").unwrap();
+        fs::write(format!("{}.fam", prefix), "F1 S1 0 0 1 -9\n").unwrap();
+        assert_eq!(
+            MetadataFile::find_pvar(&prefix).path,
+            format!("{}.bim", prefix)
+        );
+        assert_eq!(
+            MetadataFile::find_psam(&prefix).path,
+            format!("{}.fam", prefix)
+        );
+        fs::write(format!("{}.pvar", prefix), "#CHROM\tPOS\tID\tREF\tALT\n").unwrap();
+        fs::write(format!("{}.psam", prefix), "#IID\nS1\n").unwrap();
+        assert_eq!(
+            MetadataFile::find_pvar(&prefix).path,
+            format!("{}.pvar", prefix)
+        );
+        assert_eq!(
+            MetadataFile::find_psam(&prefix).path,
+            format!("{}.psam", prefix)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zstd_metadata() {
+        let dir = fixture_dir("metadata-zstd");
+        let prefix = dir.join("zst").to_string_lossy().into_owned();
+        let pvar = "##fileformat=VCFv4.2\n#CHROM\tPOS\tID\tREF\tALT\n1\t10\trs1\tA\tG\n";
+        let compressed = zstd::encode_all(pvar.as_bytes(), 3).unwrap();
+        fs::write(format!("{}.pvar.zst", prefix), compressed).unwrap();
+        fs::write(format!("{}.bim", prefix), "1\trs1\t0\t10\tG\tA\n").unwrap();
+
+        // a .pvar.zst comes before a .bim, and is read decompressed
+        let metadata_file = MetadataFile::find_pvar(&prefix);
+        assert_eq!(metadata_file.path, format!("{}.pvar.zst", prefix));
+        assert!(metadata_file.synthesized_columns.is_none());
+        let mut contents = String::new();
+        metadata_file
+            .open()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, pvar);
+        // but after a .pvar
+        fs::write(format!("{}.pvar", prefix), pvar).unwrap();
+        assert_eq!(
+            MetadataFile::find_pvar(&prefix).path,
+            format!("{}.pvar", prefix)
+        );
+
+        // a headerless .psam.zst gets the columns of a .fam
+        let psam = zstd::encode_all(&b"F1 S1 0 0 1\n"[..], 3).unwrap();
+        fs::write(format!("{}.psam.zst", prefix), psam).unwrap();
+        let metadata_file = MetadataFile::find_psam(&prefix);
+        assert_eq!(metadata_file.path, format!("{}.psam.zst", prefix));
+        assert_eq!(
+            metadata_file.synthesized_columns,
+            Some(vec!["FID", "IID", "PAT", "MAT", "SEX"])
+        );
+
+        // a .zst that isn't zstd fails to read
+        fs::write(format!("{}.psam.zst", prefix), "#IID\nS1\n").unwrap();
+        let metadata_file = MetadataFile::find_psam(&prefix);
+        assert!(metadata_file.synthesized_columns.is_none());
+        let mut contents = String::new();
+        let read = metadata_file
+            .open()
+            .and_then(|mut reader| reader.read_to_string(&mut contents));
+        assert!(read.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn whitespace_to_tabs() {
+        let input = "1 rs1\t 0  10\tG A\n\n  X\trs2 0 5 C T  \r\nY rs3 0 7 A C";
+        let mut converted = String::new();
+        WhitespaceToTabs::new(input.as_bytes())
+            .read_to_string(&mut converted)
+            .unwrap();
+        let expected = "1\trs1\t0\t10\tG\tA\n\nX\trs2\t0\t5\tC\tT\nY\trs3\t0\t7\tA\tC\n";
+        assert_eq!(converted, expected);
+
+        // into a buffer shorter than the lines
+        let mut reader = WhitespaceToTabs::new(input.as_bytes());
+        let mut buf = [0; 3];
+        let mut converted = Vec::new();
+        loop {
+            let len = reader.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            converted.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(converted, expected.as_bytes());
+    }
+}
@@ -1,249 +1,1573 @@
 use csv::{Reader, ReaderBuilder, StringRecord};
-use evalexpr::{
-    eval_boolean_with_context, eval_string_with_context, ContextWithMutableVariables,
-    HashMapContext, Value,
-};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::iter::Filter;
 use std::path::PathBuf;
 
-use crate::QueryType;
-use crate::pvar_parser::PvarParser;
-use crate::filter_parser::FilterParser;
+use crate::bcf::{BcfHeader, BcfWriter, FormatValues, Site};
+use crate::bed_writer::BedWriter;
+use crate::bgzf::BgzfWriter;
+use crate::expression::{Expression, ExpressionError, Scope, Value};
+use crate::filter_parser::Variable;
+use crate::format_string::{FormatString, SampleScope};
+use crate::id_lists::{SampleList, VariantList};
+use crate::metadata_file::{MetadataFile, WhitespaceToTabs};
+use crate::pgen_record::{self, bytes_to_represent, read_le_uint, Genotypes, RecordReader};
+use crate::pgen_writer::PgenWriter;
+use crate::pvar_index::{self, PvarIndex};
+use crate::pvar_parser::{InfoType, PvarParser};
+use crate::regions::Regions;
+use crate::tabix::{IndexBuilder, IndexFormat};
+
+/// What `query` prints for each kept row.
+pub enum QueryFormat {
+    /// A bcftools-style format string like `%CHROM\t%POS\n`.
+    FormatString(String),
+    /// An expression like `CHROM + " " + POS`, whose value is printed on its
+    /// own line.
+    Expression(String),
+}
 
-pub struct Pfile {
-    pub pfile_prefix: String,
+/// The records of a .pvar or .psam to go through, along with their
+/// indices.
+type Rows<'a> = Box<dyn Iterator<Item = Result<(usize, StringRecord), PgenError>> + 'a>;
+
+/// Whether to keep a .pvar or .psam record, before its query.
+type RowFilter<'a> = Box<dyn Fn(&StringRecord) -> bool + 'a>;
+
+/// Which variants `filter` keeps: those in `regions`, listed in `extract`
+/// and not in `exclude`, for which `query` holds, each if given.
+#[derive(Debug, Clone, Default)]
+pub struct VariantFilter {
+    pub query: Option<String>,
+    pub regions: Option<Regions>,
+    pub extract: Option<VariantList>,
+    pub exclude: Option<VariantList>,
+}
+
+/// Which samples `filter` keeps: those listed in `keep` and not in `remove`
+/// for which `query` holds, each if given.
+#[derive(Debug, Clone, Default)]
+pub struct SampleFilter {
+    pub query: Option<String>,
+    pub keep: Option<SampleList>,
+    pub remove: Option<SampleList>,
+}
+
+/// A parsed `QueryFormat`.
+enum Output {
+    FormatString(FormatString),
+    Expression(Expression),
+}
+
+/// Which of the metadata files a query is over.
+#[derive(Deserialize)]
+pub enum QueryType {
+    Variant,
+    Sample,
+}
+
+/// The first two bytes of a .pgen (and of a .pgen.pgi).
+const PGEN_MAGIC: [u8; 2] = [0x6C, 0x1B];
+
+/// The fixed columns of a VCF line, before FORMAT.
+const VCF_SITE_COLUMNS: [&str; 8] = ["CHROM", "POS", "ID", "REF", "ALT", "QUAL", "FILTER", "INFO"];
+
+/// Number of variants in each variant block of a variable-width pgen.
+pub(crate) const VARIANT_BLOCK_SIZE: usize = 1 << 16;
+
+/// Everything that can go wrong reading a pfile.
+#[derive(Debug)]
+pub enum PgenError {
+    /// The file doesn't start with the .pgen magic number.
+    BadMagic { path: String },
+    UnsupportedStorageMode(u8),
+    /// The header is well-formed but uses features we can't read, or its
+    /// values don't make sense.
+    InvalidHeader(String),
+    /// A genotype record ends before everything it declares.
+    TruncatedRecord { var_idx: usize },
+    /// A genotype record is long enough but its contents don't make sense.
+    InvalidRecord { var_idx: usize, message: String },
+    /// A .pvar or .psam lacks its header line.
+    MissingHeaderLine { path: String },
+    /// A .pvar or .psam lacks a column we need.
+    MissingColumn { path: String, column: String },
+    /// A query or format string failed to parse or evaluate.
+    Expression {
+        expression: String,
+        error: ExpressionError,
+    },
+    /// A .pvar or .psam line failed to parse.
+    Metadata(csv::Error),
+    /// The output can't be indexed, e.g. because it isn't sorted.
+    Unindexable(String),
+    /// The metadata can't be encoded in the output format, e.g. a position
+    /// that isn't a number in a BCF.
+    Unencodable(String),
+    /// A file being imported, a regions file, a sample or variant list or a
+    /// .pvar index is malformed, or a .pvar can't be indexed.
+    InvalidInput { path: String, message: String },
+    /// A variant was requested past the last one in the pfile.
+    VariantOutOfRange { var_idx: usize, num_variants: usize },
+    /// A region to restrict the variants to failed to parse.
+    InvalidRegion { region: String, message: String },
+    Io(io::Error),
+}
+
+impl PgenError {
+    /// The exit code of the CLI when failing with this error. Clap already
+    /// uses 2 for bad arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PgenError::Io(_) => 3,
+            PgenError::BadMagic { .. } => 4,
+            PgenError::UnsupportedStorageMode(_) => 5,
+            PgenError::InvalidHeader(_) => 6,
+            PgenError::TruncatedRecord { .. } => 7,
+            PgenError::InvalidRecord { .. } => 8,
+            PgenError::MissingHeaderLine { .. } => 9,
+            PgenError::MissingColumn { .. } => 10,
+            PgenError::Expression { .. } => 11,
+            PgenError::Metadata(_) => 12,
+            PgenError::VariantOutOfRange { .. } => 13,
+            PgenError::Unindexable(_) => 14,
+            PgenError::Unencodable(_) => 15,
+            PgenError::InvalidInput { .. } => 16,
+            PgenError::InvalidRegion { .. } => 17,
+        }
+    }
+}
+
+impl fmt::Display for PgenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PgenError::BadMagic { path } => write!(f, "{} is not a .pgen file", path),
+            PgenError::UnsupportedStorageMode(byte) => write!(
+                f,
+                "unsupported storage mode {:#04x} (only 0x02, 0x10 and 0x11 are supported)",
+                byte
+            ),
+            PgenError::InvalidHeader(message) => write!(f, "invalid .pgen header: {}", message),
+            PgenError::TruncatedRecord { var_idx } => {
+                write!(f, "the record of variant {} is truncated", var_idx)
+            }
+            PgenError::InvalidRecord { var_idx, message } => {
+                write!(f, "invalid record for variant {}: {}", var_idx, message)
+            }
+            PgenError::MissingHeaderLine { path } => {
+                write!(f, "{} has no header line starting with #", path)
+            }
+            PgenError::MissingColumn { path, column } => {
+                write!(f, "{} not among the headers of {}", column, path)
+            }
+            PgenError::Expression { expression, error } => {
+                // point at the part of the expression at fault, like
+                //
+                //     invalid expression at column 5: expected a value, found `>`
+                //       POS >> 3
+                //           ^
+                let start = expression[..error.span.start].chars().count();
+                let len = expression[error.span.clone()].chars().count().max(1);
+                write!(
+                    f,
+                    "invalid expression at column {}: {}\n  {}\n  {}{}",
+                    start + 1,
+                    error,
+                    expression,
+                    " ".repeat(start),
+                    "^".repeat(len)
+                )
+            }
+            PgenError::Metadata(error) => write!(f, "{}", error),
+            PgenError::Unindexable(message) => write!(f, "cannot index the output: {}", message),
+            PgenError::Unencodable(message) => write!(f, "cannot encode the output: {}", message),
+            PgenError::InvalidInput { path, message } => write!(f, "invalid {}: {}", path, message),
+            PgenError::VariantOutOfRange {
+                var_idx,
+                num_variants,
+            } => write!(
+                f,
+                "variant {} is out of range, there are only {} variants",
+                var_idx, num_variants
+            ),
+            PgenError::InvalidRegion { region, message } => {
+                write!(f, "invalid region `{}`: {}", region, message)
+            }
+            PgenError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for PgenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PgenError::Expression { error, .. } => Some(error),
+            PgenError::Metadata(error) => Some(error),
+            PgenError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PgenError {
+    fn from(error: io::Error) -> PgenError {
+        PgenError::Io(error)
+    }
+}
+
+impl From<csv::Error> for PgenError {
+    fn from(error: csv::Error) -> PgenError {
+        // csv wraps the I/O errors of its reader, which aren't parse errors
+        if error.is_io_error() {
+            match error.into_kind() {
+                csv::ErrorKind::Io(error) => PgenError::Io(error),
+                _ => unreachable!(),
+            }
+        } else {
+            PgenError::Metadata(error)
+        }
+    }
+}
+
+/// How the genotype records are laid out, from the third byte of the .pgen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// 0x02: every record is a 2-bit array of hardcalls, stored back to back.
+    FixedWidth,
+    /// 0x10: records have their own length and type, indexed in the header.
+    VariableWidth,
+    /// 0x11: like `VariableWidth`, but the index lives in a .pgen.pgi file.
+    VariableWidthWithPgi,
+}
+
+/// How the flags marking provisional REF alleles are stored, from bits 6-7
+/// of the header control byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonrefFlagsStorage {
+    NotStored,
+    AllUnset,
+    AllSet,
+    /// One bit per variant, stored in the header.
+    Explicit,
+}
+
+/// The header of a .pgen, i.e. everything up to and including the header
+/// control byte.
+#[derive(Debug, Clone)]
+pub struct PgenHeader {
+    pub storage_mode: StorageMode,
     pub num_variants: u32,
     pub num_samples: u32,
+    /// Width of each record type in the index: 4 or 8 bits, or 0 for fixed
+    /// width pgens, which have no record types.
+    pub record_type_bits: usize,
+    /// Width of each record length in the index, 1 to 4 bytes (0 for fixed
+    /// width pgens).
+    pub record_length_bytes: usize,
+    /// Width of each allele count in the index, 0 meaning every variant is
+    /// biallelic.
+    pub allele_count_bytes: usize,
+    pub nonref_flags: NonrefFlagsStorage,
+}
+
+impl StorageMode {
+    fn from_byte(byte: u8) -> Result<StorageMode, PgenError> {
+        match byte {
+            0x02 => Ok(StorageMode::FixedWidth),
+            0x10 => Ok(StorageMode::VariableWidth),
+            0x11 => Ok(StorageMode::VariableWidthWithPgi),
+            _ => Err(PgenError::UnsupportedStorageMode(byte)),
+        }
+    }
+}
+
+impl PgenHeader {
+    /// Reads the variant and sample counts and the header control byte,
+    /// which directly follow the storage mode.
+    fn read(storage_mode: StorageMode, reader: &mut impl Read) -> Result<PgenHeader, PgenError> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        let num_variants = u32::from_le_bytes(buf);
+
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        let num_samples = u32::from_le_bytes(buf);
+
+        let mut buf = [0u8; 1];
+        reader.read_exact(&mut buf)?;
+        let header_ctrl = buf[0];
+
+        // bits 6-7 say how the nonref flags are stored: 0 not at all, 1 all
+        // unset, 2 all set, and 3 explicitly in the header
+        let nonref_flags = match header_ctrl >> 6 {
+            0 => NonrefFlagsStorage::NotStored,
+            1 => NonrefFlagsStorage::AllUnset,
+            2 => NonrefFlagsStorage::AllSet,
+            _ => NonrefFlagsStorage::Explicit,
+        };
+
+        if storage_mode == StorageMode::FixedWidth {
+            // fixed-width records have neither types, lengths nor allele
+            // counts, so only the nonref flags may be set
+            if header_ctrl & 0x3F != 0 {
+                return Err(PgenError::InvalidHeader(format!(
+                    "unsupported header flags {:#010b} for a fixed-width pgen",
+                    header_ctrl
+                )));
+            }
+            return Ok(PgenHeader {
+                storage_mode,
+                num_variants,
+                num_samples,
+                record_type_bits: 0,
+                record_length_bytes: 0,
+                allele_count_bytes: 0,
+                nonref_flags,
+            });
+        }
+
+        // bits 0-3 give the record type width and the record length width;
+        // values of 8 and up are special encodings we don't support
+        let type_and_length_storage = header_ctrl & 0x0F;
+        if type_and_length_storage >= 8 {
+            return Err(PgenError::InvalidHeader(format!(
+                "unsupported record type/length storage {} (header flags {:#010b})",
+                type_and_length_storage, header_ctrl
+            )));
+        }
+        Ok(PgenHeader {
+            storage_mode,
+            num_variants,
+            num_samples,
+            record_type_bits: if type_and_length_storage & 4 == 0 { 4 } else { 8 },
+            record_length_bytes: (type_and_length_storage & 3) as usize + 1,
+            // bits 4-5 give the width of the allele counts
+            allele_count_bytes: ((header_ctrl >> 4) & 3) as usize,
+            nonref_flags,
+        })
+    }
+
+    /// Writes the header in the layout `read` expects, starting with the
+    /// magic number and storage mode.
+    pub(crate) fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let storage_mode = match self.storage_mode {
+            StorageMode::FixedWidth => 0x02,
+            StorageMode::VariableWidth => 0x10,
+            StorageMode::VariableWidthWithPgi => 0x11,
+        };
+        writer.write_all(&PGEN_MAGIC)?;
+        writer.write_all(&[storage_mode])?;
+        writer.write_all(&self.num_variants.to_le_bytes())?;
+        writer.write_all(&self.num_samples.to_le_bytes())?;
+
+        let mut header_ctrl = match self.nonref_flags {
+            NonrefFlagsStorage::NotStored => 0,
+            NonrefFlagsStorage::AllUnset => 1,
+            NonrefFlagsStorage::AllSet => 2,
+            NonrefFlagsStorage::Explicit => 3,
+        } << 6;
+        if self.storage_mode != StorageMode::FixedWidth {
+            let type_storage = if self.record_type_bits == 8 { 4 } else { 0 };
+            header_ctrl |= type_storage | (self.record_length_bytes as u8 - 1);
+            header_ctrl |= (self.allele_count_bytes as u8) << 4;
+        }
+        writer.write_all(&[header_ctrl])
+    }
+}
+
+/// Where to find each variant's record in the .pgen.
+enum VariantIndex {
+    /// Storage mode 0x02: every record is the same size and they are stored
+    /// back to back.
+    FixedWidth { records_start: u64, record_size: u64 },
+    /// Storage modes 0x10 and 0x11: each record has its own length and type.
+    VariableWidth {
+        /// Start of each record, plus a trailing entry for the end of the
+        /// last record.
+        record_offsets: Vec<u64>,
+        record_types: Vec<u8>,
+    },
+}
+
+pub struct Pfile {
+    pub(crate) pfile_prefix: String,
+    pub(crate) num_variants: u32,
+    pub(crate) num_samples: u32,
+    pub(crate) header: PgenHeader,
+    variant_index: VariantIndex,
+    /// Number of alleles (REF included) of each variant, empty if every
+    /// variant is biallelic.
+    allele_counts: Vec<u32>,
+    /// One bit per variant marking its REF allele as provisional, if the
+    /// flags are stored at all.
+    nonref_flags: Option<Vec<u8>>,
+    pvar: MetadataFile,
+    psam: MetadataFile,
 }
 
 impl Pfile {
+    pub fn pfile_prefix(&self) -> &str {
+        &self.pfile_prefix
+    }
+
+    /// The header of the .pgen, with the number of variants and samples.
+    pub fn header(&self) -> &PgenHeader {
+        &self.header
+    }
+
     pub fn pgen_path(&self) -> String {
         format!("{}.pgen", self.pfile_prefix)
     }
 
+    /// PREFIX.psam, or PREFIX.psam.zst or PREFIX.fam if there is no .psam.
     pub fn psam_path(&self) -> String {
-        format!("{}.psam", self.pfile_prefix)
+        self.psam.path.clone()
     }
 
+    /// PREFIX.pvar, or PREFIX.pvar.zst or PREFIX.bim if there is no .pvar.
     pub fn pvar_path(&self) -> String {
-        format!("{}.pvar", self.pfile_prefix)
+        self.pvar.path.clone()
     }
 
-    pub fn from_prefix(pfile_prefix: String) -> Pfile {
+    pub fn from_prefix(pfile_prefix: String) -> Result<Pfile, PgenError> {
         let pgen_path = format!("{}.pgen", pfile_prefix);
 
-        let pgen = File::open(pgen_path).unwrap();
+        let pgen = File::open(&pgen_path)?;
+        let pgen_len = pgen.metadata()?.len();
         let mut pgen_reader = BufReader::new(pgen);
 
-        let mut buf = [0u8; 2];
-        pgen_reader.read_exact(&mut buf).unwrap();
+        let mut buf = [0u8; 3];
+        pgen_reader.read_exact(&mut buf)?;
         // check the magic number
-        assert_eq!(buf, [0x6C, 0x1B]);
+        if buf[..2] != PGEN_MAGIC {
+            return Err(PgenError::BadMagic { path: pgen_path });
+        }
+        let storage_mode = StorageMode::from_byte(buf[2])?;
+
+        // storage mode 0x11 keeps the header and index in a separate
+        // .pgen.pgi file, whose first three bytes mirror the .pgen's
+        let mut header_reader: Box<dyn Read> =
+            if storage_mode == StorageMode::VariableWidthWithPgi {
+                let pgi_path = format!("{}.pgen.pgi", pfile_prefix);
+                let pgi = File::open(&pgi_path)?;
+                let mut pgi_reader = BufReader::new(pgi);
+                let mut buf = [0u8; 3];
+                pgi_reader.read_exact(&mut buf)?;
+                if buf[..2] != PGEN_MAGIC {
+                    return Err(PgenError::BadMagic { path: pgi_path });
+                }
+                Box::new(pgi_reader)
+            } else {
+                Box::new(pgen_reader)
+            };
+        let header = PgenHeader::read(storage_mode, &mut header_reader).map_err(truncated_header)?;
+        let mut pfile = Pfile::new(pfile_prefix, header);
+
+        match pfile.header.storage_mode {
+            // the simplest fixed-width storage mode
+            StorageMode::FixedWidth => {
+                let mut records_start = 12;
+                if pfile.header.nonref_flags == NonrefFlagsStorage::Explicit {
+                    let mut flags_buf = vec![0u8; (pfile.num_variants as usize).div_ceil(8)];
+                    header_reader
+                        .read_exact(&mut flags_buf)
+                        .map_err(|error| truncated_header(error.into()))?;
+                    records_start += flags_buf.len() as u64;
+                    pfile.nonref_flags = Some(flags_buf);
+                }
+                pfile.variant_index = VariantIndex::FixedWidth {
+                    records_start,
+                    record_size: pfile.variant_record_size() as u64,
+                };
+            }
+            StorageMode::VariableWidth | StorageMode::VariableWidthWithPgi => {
+                pfile
+                    .read_variable_width_index(&mut header_reader, pgen_len)
+                    .map_err(truncated_header)?;
+            }
+        }
+        if pfile.header.nonref_flags == NonrefFlagsStorage::AllSet {
+            pfile.nonref_flags = Some(vec![0xFF; (pfile.num_variants as usize).div_ceil(8)]);
+        }
+        Ok(pfile)
+    }
 
-        let mut buf = [0u8; 1];
-        pgen_reader.read_exact(&mut buf).unwrap();
-        let storage_mode = buf[0];
-        // for now we only deal with the simplest fixed-width storage mode
-        assert!(storage_mode == 0x02);
+    /// A pfile with an empty index.
+    fn new(pfile_prefix: String, header: PgenHeader) -> Pfile {
+        Pfile {
+            pvar: MetadataFile::find_pvar(&pfile_prefix),
+            psam: MetadataFile::find_psam(&pfile_prefix),
+            pfile_prefix,
+            num_variants: header.num_variants,
+            num_samples: header.num_samples,
+            header,
+            variant_index: VariantIndex::FixedWidth {
+                records_start: 0,
+                record_size: 0,
+            },
+            allele_counts: Vec::new(),
+            nonref_flags: None,
+        }
+    }
 
-        let mut buf = [0u8; 4];
-        pgen_reader.read_exact(&mut buf).unwrap();
-        let num_variants = u32::from_le_bytes(buf);
-        // println!("variant_count: {}", num_variants);
+    /// Reads the index of a variable-width pgen, which follows the header.
+    ///
+    /// It starts with the offset of each variant block (every block holds
+    /// 2^16 variants), and then for each block
+    ///
+    /// ```text
+    /// record types    (4 or 8 bits per variant)
+    /// record lengths  (1 to 4 bytes per variant)
+    /// allele counts   (0 to 3 bytes per variant)
+    /// nonref flags    (1 bit per variant, if stored explicitly)
+    /// ```
+    ///
+    /// Every record has to end within the `pgen_len` bytes of the .pgen.
+    fn read_variable_width_index(
+        &mut self,
+        reader: &mut impl Read,
+        pgen_len: u64,
+    ) -> Result<(), PgenError> {
+        let record_type_bits = self.header.record_type_bits;
+        let record_length_bytes = self.header.record_length_bytes;
+        let allele_count_bytes = self.header.allele_count_bytes;
+
+        let num_variants = self.num_variants as usize;
+        let num_blocks = num_variants.div_ceil(VARIANT_BLOCK_SIZE);
+        let mut block_offsets = Vec::with_capacity(num_blocks);
+        for _ in 0..num_blocks {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            block_offsets.push(u64::from_le_bytes(buf));
+        }
 
-        let mut buf = [0u8; 4];
-        pgen_reader.read_exact(&mut buf).unwrap();
-        let num_samples = u32::from_le_bytes(buf);
-        // println!("sample_count: {}", num_samples);
+        let mut record_offsets = Vec::with_capacity(num_variants + 1);
+        let mut record_types = Vec::with_capacity(num_variants);
+        let mut nonref_flags = Vec::new();
+        for (block_idx, block_offset) in block_offsets.into_iter().enumerate() {
+            let block_len =
+                VARIANT_BLOCK_SIZE.min(num_variants - block_idx * VARIANT_BLOCK_SIZE);
+
+            let mut types_buf = vec![0u8; (block_len * record_type_bits).div_ceil(8)];
+            reader.read_exact(&mut types_buf)?;
+            if record_type_bits == 8 {
+                record_types.extend_from_slice(&types_buf);
+            } else {
+                // two record types per byte, low nibble first
+                record_types.extend((0..block_len).map(|i| (types_buf[i / 2] >> (4 * (i % 2))) & 0x0F));
+            }
 
-        let mut buf = [0u8; 1];
-        pgen_reader.read_exact(&mut buf).unwrap();
-        // print this bit in binary
-        // println!("flags: {:08b}", buf[0]);
-        assert_eq!(buf, [0x40]);
+            let mut lengths_buf = vec![0u8; block_len * record_length_bytes];
+            reader.read_exact(&mut lengths_buf)?;
+            let mut record_offset = block_offset;
+            for length in lengths_buf.chunks_exact(record_length_bytes) {
+                record_offsets.push(record_offset);
+                record_offset = record_offset.saturating_add(read_le_uint(length));
+            }
+            if block_idx == num_blocks - 1 {
+                record_offsets.push(record_offset);
+            }
 
-        Pfile {
-            pfile_prefix,
-            num_variants,
-            num_samples,
+            let mut allele_counts_buf = vec![0u8; block_len * allele_count_bytes];
+            reader.read_exact(&mut allele_counts_buf)?;
+            for allele_count in allele_counts_buf.chunks_exact(allele_count_bytes.max(1)) {
+                let allele_count = read_le_uint(allele_count) as u32;
+                if allele_count < 2 {
+                    return Err(PgenError::InvalidHeader(format!(
+                        "variant {} has fewer than 2 alleles",
+                        self.allele_counts.len()
+                    )));
+                }
+                self.allele_counts.push(allele_count);
+            }
+
+            if self.header.nonref_flags == NonrefFlagsStorage::Explicit {
+                let mut flags_buf = vec![0u8; block_len.div_ceil(8)];
+                reader.read_exact(&mut flags_buf)?;
+                nonref_flags.extend_from_slice(&flags_buf);
+            }
         }
+
+        // the size of each record is the difference of consecutive offsets
+        if record_offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(PgenError::InvalidHeader(
+                "the record offsets are not in increasing order".to_string(),
+            ));
+        }
+        if let Some(&records_end) = record_offsets.last().filter(|&&end| end > pgen_len) {
+            return Err(PgenError::InvalidHeader(format!(
+                "the records end at byte {}, past the end of the {}-byte .pgen",
+                records_end, pgen_len
+            )));
+        }
+
+        if self.header.nonref_flags == NonrefFlagsStorage::Explicit {
+            self.nonref_flags = Some(nonref_flags);
+        }
+        self.variant_index = VariantIndex::VariableWidth {
+            record_offsets,
+            record_types,
+        };
+        Ok(())
     }
 
+    /// Number of alleles of the variant, REF included.
+    pub fn allele_count(&self, var_idx: usize) -> u32 {
+        self.allele_counts.get(var_idx).copied().unwrap_or(2)
+    }
 
-    fn parse_info_query(&self, query: String) -> Option<(Vec<String>, Vec<String>)> {
-        let info = FilterParser::get_info_query(&query);
-        match info {
-            Some(info_vals) => {
-                let info_keys = info_vals.first();
-                let info_filtervals = info_vals.last();
-                match (info_keys, info_filtervals) {
-                    (Some(info_keys), Some(info_filtervals)) => {
-                        if let Some(valid_info_keys) = PvarParser::get_meta_idnames(&self.pvar_path()).ok() {
-                            if info_keys.iter().all(|item| valid_info_keys.contains(item)) {
-                                return Some((info_keys.to_vec(), info_filtervals.to_vec()));
-                            }
-                        }
+    /// Whether the REF allele of the variant is provisional, i.e. not
+    /// necessarily the reference genome's.
+    fn is_provisional_ref(&self, var_idx: usize) -> bool {
+        self.nonref_flags
+            .as_ref()
+            .is_some_and(|flags| pgen_record::get_bit(flags, var_idx))
+    }
 
-                    },
-                    _ => (),
-                };
-            },
-            None => (),
+    /// Reads the raw record of a variant.
+    fn read_record(&self, pgen_reader: &mut File, var_idx: usize) -> Result<Vec<u8>, PgenError> {
+        let (record_offset, record_size) = match &self.variant_index {
+            VariantIndex::FixedWidth {
+                records_start,
+                record_size,
+            } => (records_start + var_idx as u64 * record_size, *record_size),
+            VariantIndex::VariableWidth { record_offsets, .. } => (
+                record_offsets[var_idx],
+                record_offsets[var_idx + 1] - record_offsets[var_idx],
+            ),
         };
-        None
+        // read the whole record to file
+        // this restricts the number of syscalls to |variants| instead of |variants| * |samples|
+        let mut record_buf = vec![0u8; record_size as usize];
+        pgen_reader.seek(SeekFrom::Start(record_offset))?;
+        pgen_reader.read_exact(&mut record_buf).map_err(|error| {
+            if error.kind() == io::ErrorKind::UnexpectedEof {
+                PgenError::TruncatedRecord { var_idx }
+            } else {
+                PgenError::Io(error)
+            }
+        })?;
+        Ok(record_buf)
     }
 
-    pub fn query_metadata(
-        &self,
-        reader: &mut Reader<File>,
-        query: Option<String>,
-        f_string: String,
-    ) -> csv::Result<()> {
-        let headers: StringRecord = reader.headers()?.clone();
+    fn record_type(&self, var_idx: usize) -> u8 {
+        match &self.variant_index {
+            VariantIndex::FixedWidth { .. } => 0,
+            VariantIndex::VariableWidth { record_types, .. } => record_types[var_idx],
+        }
+    }
 
-        // if query.is_some() {
-        //     if let Some((info_keys, info_filtervals)) = Self::parse_info_query(&self, query.clone().unwrap()) {
+    /// Reads the genotypes of a variant.
+    ///
+    /// LD-compressed records are stored relative to the last record before
+    /// them that is not, so `ld_base` caches the hardcalls of that record
+    /// (along with its variant index) between calls. Reading variants in
+    /// increasing order then never reads a record twice.
+    pub(crate) fn read_genotypes(
+        &self,
+        pgen_reader: &mut File,
+        var_idx: usize,
+        ld_base: &mut Option<(usize, Vec<u8>)>,
+    ) -> Result<Genotypes, PgenError> {
+        let record_type = self.record_type(var_idx);
+        let record = self.read_record(pgen_reader, var_idx)?;
+        let mut record_reader = RecordReader::new(&record, var_idx);
+        let hardcalls = self.read_hardcalls(pgen_reader, &mut record_reader, var_idx, ld_base)?;
+        let mut genotypes = Genotypes::unphased(hardcalls);
+
+        // the tracks after the hardcalls come in the order of their bits
+        if record_type & 0x08 != 0 {
+            genotypes.read_multiallelic(
+                &mut record_reader,
+                self.num_samples,
+                self.allele_count(var_idx),
+            )?;
+        }
+        if record_type & 0x10 != 0 {
+            genotypes.read_phase(&mut record_reader, self.num_samples)?;
+        }
+        let dosage_mode = (record_type >> 5) & 0b11;
+        if dosage_mode != 0 {
+            genotypes.read_dosages(&mut record_reader, self.num_samples, dosage_mode)?;
+            if record_type & 0x80 != 0 {
+                genotypes.read_dphase(&mut record_reader, self.num_samples)?;
+            }
+        }
+        Ok(genotypes)
+    }
 
-        //     } else {
-        //         println!("invalid query: these fields don't exist in the INFO column");
-        //     }
-        // }
+    /// Reads the hardcalls of a variant, giving 2 bits per sample packed in
+    /// the same way as a fixed-width record.
+    fn read_hardcalls(
+        &self,
+        pgen_reader: &mut File,
+        record_reader: &mut RecordReader,
+        var_idx: usize,
+        ld_base: &mut Option<(usize, Vec<u8>)>,
+    ) -> Result<Vec<u8>, PgenError> {
+        let record_type = self.record_type(var_idx);
+        if !pgen_record::is_ld_compressed(record_type) {
+            let genovec =
+                pgen_record::read_hardcalls(record_reader, record_type, self.num_samples)?;
+            *ld_base = Some((var_idx, genovec.clone()));
+            return Ok(genovec);
+        }
 
-        for (_idx, rcd) in reader.records().enumerate() {
-            let rcd = rcd?;
-            let mut context = HashMapContext::new();
-            for (var, val) in std::iter::zip(&headers, &rcd) {
-                // add the individual values from the INFO column to the context
-                if var == "INFO" {
-                    let kvpairs = PvarParser::get_info_kv_pairs(val);
-                    for (k, v) in kvpairs {
-                        context.set_value(format!("{}{}{}","INFO[",k ,"]"), Value::String(v)).unwrap();
+        let base_idx = (0..var_idx)
+            .rev()
+            .find(|&idx| !pgen_record::is_ld_compressed(self.record_type(idx)))
+            .ok_or_else(|| {
+                record_reader.invalid("LD-compressed against no earlier record".to_string())
+            })?;
+        let base_genovec = match ld_base {
+            Some((idx, base_genovec)) if *idx == base_idx => base_genovec,
+            _ => {
+                let base_record = self.read_record(pgen_reader, base_idx)?;
+                let base_genovec = pgen_record::read_hardcalls(
+                    &mut RecordReader::new(&base_record, base_idx),
+                    self.record_type(base_idx),
+                    self.num_samples,
+                )?;
+                &ld_base.insert((base_idx, base_genovec)).1
+            }
+        };
+        pgen_record::read_ld_hardcalls(record_reader, record_type, self.num_samples, base_genovec)
+    }
 
+    /// Prints `format` for each variant `var_filter` keeps, or each sample
+    /// `sam_filter` keeps when querying samples. When querying variants, the
+    /// samples of `[...]` blocks are those `sam_filter` keeps regardless of
+    /// its query.
+    pub fn query_metadata(
+        &self,
+        query_type: &QueryType,
+        var_filter: VariantFilter,
+        sam_filter: SampleFilter,
+        format: QueryFormat,
+    ) -> Result<(), PgenError> {
+        let query = parse_query(match query_type {
+            QueryType::Variant => var_filter.query.clone(),
+            QueryType::Sample => sam_filter.query.clone(),
+        })?;
+        let format = match format {
+            QueryFormat::FormatString(format) => Output::FormatString(
+                FormatString::parse(&format).map_err(|error| expression_error(&format, error))?,
+            ),
+            QueryFormat::Expression(expression) => {
+                Output::Expression(parse_expression(&expression)?)
+            }
+        };
+        let mut variables = expression_variables(query.iter());
+        let mut samples = Vec::new();
+        match &format {
+            Output::FormatString(format) => {
+                variables.extend(
+                    format
+                        .variables()
+                        .into_iter()
+                        .map(|var| (format.source(), var)),
+                );
+                if let Some(span) = format.samples_span() {
+                    if let QueryType::Sample = query_type {
+                        let message = "[...] blocks can only be used when querying variants";
+                        let error = ExpressionError::new(message.to_string(), span);
+                        return Err(expression_error(format.source(), error));
                     }
+                    samples = self.filter_samples(SampleFilter {
+                        query: None,
+                        ..sam_filter.clone()
+                    })?;
                 }
-                    context
-                        .set_value(var.to_string(), Value::String(val.to_string()))
-                        .unwrap();
             }
-            let query_res = query.as_ref().map_or(true, |query| {
-                eval_boolean_with_context(query, &context).unwrap()
-            });
+            Output::Expression(expression) => {
+                variables.extend(expression_variables(std::iter::once(expression)));
+            }
+        }
+        let (mut reader, mut variant_variables) = match query_type {
+            QueryType::Sample => (self.psam_reader()?, None),
+            QueryType::Variant => (
+                self.pvar_reader()?,
+                Some(self.variant_variables(&variables)?),
+            ),
+        };
+        let headers: StringRecord = reader.headers()?.clone();
+        let bindings = Bindings::new(&headers, &variables, variant_variables.as_ref())?;
+        let (keep_row, rows) = match query_type {
+            QueryType::Sample => (
+                self.sample_row_filter(&headers, &sam_filter)?,
+                rows(&mut reader),
+            ),
+            QueryType::Variant => (
+                self.variant_row_filter(&headers, &var_filter)?,
+                self.variant_rows(&mut reader, &var_filter, query.as_ref())?,
+            ),
+        };
+        let mut stdout = BufWriter::new(io::stdout().lock());
+
+        for row in rows {
+            let (idx, rcd) = row?;
+            if !keep_row(&rcd) {
+                continue;
+            }
+            let scope = match &mut variant_variables {
+                Some(variant_variables) => variant_variables.scope(self, idx, &bindings, &rcd)?,
+                None => RowScope::new(&bindings, &rcd),
+            };
+            let query_res = match &query {
+                Some(query) => query
+                    .eval_filter(&scope)
+                    .map_err(|error| expression_error(query.source(), error))?,
+                None => true,
+            };
 
             if query_res {
-                let output = eval_string_with_context(&f_string, &context).unwrap();
-                println!("{}", output);
+                match &format {
+                    Output::FormatString(format) => {
+                        let output = format
+                            .format(&scope, &samples)
+                            .map_err(|error| expression_error(format.source(), error))?;
+                        write!(stdout, "{}", output)?;
+                    }
+                    Output::Expression(expression) => {
+                        let output = expression
+                            .eval(&scope)
+                            .map_err(|error| expression_error(expression.source(), error))?;
+                        writeln!(stdout, "{}", output)?;
+                    }
+                }
             }
         }
+        stdout.flush()?;
         Ok(())
     }
 
     pub fn output_vcf(
         &self,
-        sam_query: Option<String>,
-        var_query: Option<String>,
+        sam_filter: SampleFilter,
+        var_filter: VariantFilter,
         filename: PathBuf,
-    ) -> csv::Result<()> {
-        let (pvar_header, pvar_column_names) = self.read_pvar_header();
-        let mut psam_reader = self.psam_reader()?;
-        let sam_header = psam_reader.headers()?;
-        // Index of the sample id in each sample record.
-        let sam_rcd_id_idx = sam_header
-            .iter()
-            .enumerate()
-            .find_map(|(idx, col)| {
-                // TODO: make this a constant
-                if col == "IID" {
-                    Some(idx)
-                } else {
-                    None
+        dosage: bool,
+    ) -> Result<(), PgenError> {
+        let mut vcf_writer = BufWriter::new(File::create(filename)?);
+        self.write_vcf(&mut vcf_writer, sam_filter, var_filter, dosage, |_, _| {
+            Ok(())
+        })?;
+        vcf_writer.flush()?;
+        Ok(())
+    }
+
+    /// Like `output_vcf`, but BGZF-compressed like bgzip does, and optionally
+    /// indexed to FILENAME.tbi or FILENAME.csi.
+    pub fn output_vcf_gz(
+        &self,
+        sam_filter: SampleFilter,
+        var_filter: VariantFilter,
+        filename: PathBuf,
+        dosage: bool,
+        index_format: Option<IndexFormat>,
+    ) -> Result<(), PgenError> {
+        let pvar_headers = self.pvar_reader()?.headers()?.clone();
+        let chrom_idx = self.pvar_column(&pvar_headers, "CHROM")?;
+        let pos_idx = self.pvar_column(&pvar_headers, "POS")?;
+        let ref_idx = self.pvar_column(&pvar_headers, "REF")?;
+        let info_idx = self.pvar_column(&pvar_headers, "INFO").ok();
+
+        let mut bgzf_writer = BgzfWriter::new(BufWriter::new(File::create(&filename)?));
+        let mut index_builder = index_format.map(IndexBuilder::new);
+        let mut line_start = 0;
+        self.write_vcf(
+            &mut bgzf_writer,
+            sam_filter,
+            var_filter,
+            dosage,
+            |bgzf_writer, var_rcd| {
+                let line_end = bgzf_writer.virtual_offset();
+                if let (Some(index_builder), Some(var_rcd)) = (&mut index_builder, var_rcd) {
+                    let field = |idx: usize| var_rcd.get(idx).unwrap_or_default();
+                    let pos = field(pos_idx)
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|&pos| pos > 0)
+                        .ok_or_else(|| {
+                            PgenError::Unindexable(format!("invalid position {}", field(pos_idx)))
+                        })?;
+                    // records span their REF allele, unless they give an END
+                    let end = info_idx
+                        .and_then(|info_idx| info_end(field(info_idx)))
+                        .unwrap_or(pos - 1 + field(ref_idx).len() as u64);
+                    index_builder.push(field(chrom_idx), pos - 1, end, line_start, line_end)?;
                 }
-            })
-            .unwrap_or_else(|| panic!("IID not among the headers of {}", self.psam_path()));
-        let var_idx_rcds = self.filter_metadata(&mut self.pvar_reader()?, var_query)?;
-        let sam_idx_rcs = self.filter_metadata(&mut psam_reader, sam_query)?;
-        // println!("filtered metadata");
-        let sam_ids = sam_idx_rcs
+                line_start = line_end;
+                Ok(())
+            },
+        )?;
+        bgzf_writer.finish()?;
+
+        if let Some(index_builder) = index_builder {
+            let extension = index_format.map_or("", |format| format.extension());
+            index_builder.write(format!("{}.{}", filename.display(), extension))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the VCF of the kept samples and variants. `line_written` is
+    /// called after the header (with None) and after each variant's line
+    /// (with its .pvar record).
+    fn write_vcf<W: Write>(
+        &self,
+        vcf_writer: &mut W,
+        sam_filter: SampleFilter,
+        var_filter: VariantFilter,
+        dosage: bool,
+        mut line_written: impl FnMut(&mut W, Option<&StringRecord>) -> Result<(), PgenError>,
+    ) -> Result<(), PgenError> {
+        let (pvar_header, pvar_column_names) = self.read_pvar_header()?;
+        let var_idx_rcds = self.filter_variants(&mut self.pvar_reader()?, var_filter)?;
+        let sam_idx_ids = self.filter_samples(sam_filter)?;
+        let sam_ids = sam_idx_ids
             .iter()
-            .map(|(_idx, rcd)| rcd.get(sam_rcd_id_idx).unwrap().to_string())
-            .collect::<Vec<String>>()
+            .map(|(_idx, id)| id.as_str())
+            .collect::<Vec<&str>>()
             .join("\t");
-        let vcf = File::create(filename)?;
-        let mut vcf_writer = BufWriter::new(vcf);
-        // write the header
-        writeln!(vcf_writer, "##fileformat=VCFv4.2").unwrap();
-        writeln!(vcf_writer, "##source=pgen-rs").unwrap();
-        write!(vcf_writer, "{}", pvar_header).unwrap();
-
-        // avoid push_str since it is slow
-        let pvar_column_names = pvar_column_names.trim().to_string();
-        write!(vcf_writer, "{}", pvar_column_names).unwrap();
-        writeln!(vcf_writer, "\tFORMAT\t{}", &sam_ids).unwrap();
+        // write the header, which only declares GT along with the dosages
+        let format_keys = self.format_keys(&var_idx_rcds, dosage);
+        let declared_keys = if dosage { &format_keys[..] } else { &[] };
+        self.write_vcf_meta(vcf_writer, &pvar_header, declared_keys)?;
+
+        // the columns of a .bim aren't in VCF order, so its rows are
+        // rearranged into the VCF's site columns
+        let site_columns = match self.pvar.synthesized_columns {
+            Some(_) => {
+                let pvar_headers = self.pvar_reader()?.headers()?.clone();
+                Some(VCF_SITE_COLUMNS.map(|column| self.pvar_column(&pvar_headers, column).ok()))
+            }
+            None => None,
+        };
+        let pvar_column_names = match site_columns {
+            Some(_) => format!("#{}", VCF_SITE_COLUMNS.join("\t")),
+            None => pvar_column_names.trim().to_string(),
+        };
+        // Index of the INFO column, which gets the PR flag for provisional
+        // REF alleles.
+        let info_col_idx = pvar_column_names
+            .trim_start_matches('#')
+            .split('\t')
+            .position(|col| col == "INFO");
+        write!(vcf_writer, "{}", pvar_column_names)?;
+        writeln!(vcf_writer, "\tFORMAT\t{}", &sam_ids)?;
+        line_written(vcf_writer, None)?;
 
         // now the fun part, write the actual data
-        let pgen = File::open(self.pgen_path()).unwrap();
+        let pgen = File::open(self.pgen_path())?;
         // seems that BufReader makes things slower
-        // let mut pgen_reader = BufReader::new(pgen);
         let mut pgen_reader = pgen;
         // For writing the hot part of the loop (the body of the VCF) we will
         // use BufWriter::write for performance reasons.
-        // println!("wrote header");
+        let mut ld_base = None;
         for (var_idx, var_rcd) in var_idx_rcds.iter() {
-            for col in var_rcd.iter() {
-                vcf_writer.write(col.as_bytes())?;
-                vcf_writer.write(b"\t")?;
-            }
-            vcf_writer.write("GT".as_bytes())?;
-            // pvar_line.push_str("\tGT");
-            // write!(vcf_writer, "\tGT").unwrap();
-
-            let record_offset = 12 + (*var_idx as u32 * self.variant_record_size()) as u64;
-            // read the whole record to file
-            // this restricts the number of syscalls to |variants| instead of |variants| * |samples|
-            let mut record_buf = vec![0u8; self.variant_record_size() as usize];
-            pgen_reader.seek(SeekFrom::Start(record_offset)).unwrap();
-            pgen_reader.read_exact(&mut record_buf).unwrap();
-            for (sam_idx, _sam_rcd) in sam_idx_rcs.iter() {
-                let sample_offset = sam_idx / 4;
-                let host_byte = record_buf[sample_offset];
-                let in_byte_offset = sam_idx % 4;
-                let encoded_genotype = (host_byte >> (in_byte_offset * 2)) & 0b11;
-                //
-                let genotype = match encoded_genotype {
-                    0b00 => "0/0",
-                    0b01 => "0/1",
-                    0b10 => "1/1",
-                    0b11 => "./.",
-                    _ => panic!("unexpected genotype"),
-                };
-                // pvar_line.push_str("\t");
-                // pvar_line.push_str(genotype);
-                vcf_writer.write(b"\t")?;
-                vcf_writer.write(genotype.as_bytes())?;
+            let provisional_ref = self.is_provisional_ref(*var_idx);
+            let write_column = |vcf_writer: &mut W, col_idx: usize, col: &str| {
+                if provisional_ref && Some(col_idx) == info_col_idx {
+                    if col == "." {
+                        vcf_writer.write_all(b"PR")?;
+                    } else {
+                        vcf_writer.write_all(col.as_bytes())?;
+                        vcf_writer.write_all(b";PR")?;
+                    }
+                } else {
+                    vcf_writer.write_all(col.as_bytes())?;
+                }
+                vcf_writer.write_all(b"\t")
+            };
+            match &site_columns {
+                Some(site_columns) => {
+                    for (col_idx, idx) in site_columns.iter().enumerate() {
+                        let col = idx.and_then(|idx| var_rcd.get(idx)).unwrap_or(".");
+                        write_column(vcf_writer, col_idx, col)?;
+                    }
+                }
+                None => {
+                    for (col_idx, col) in var_rcd.iter().enumerate() {
+                        write_column(vcf_writer, col_idx, col)?;
+                    }
+                }
+            }
+            let haplotype_dosages = dosage && self.has_phased_dosage(*var_idx);
+            if haplotype_dosages {
+                vcf_writer.write_all(b"GT:DS:HDS")?;
+            } else if dosage {
+                vcf_writer.write_all(b"GT:DS")?;
+            } else {
+                vcf_writer.write_all(b"GT")?;
             }
-            // pvar_line.push_str("\n");
-            vcf_writer.write(b"\n")?;
-            // write!(vcf_writer, "{}", pvar_line).unwrap();
+
+            let genotypes = self.read_genotypes(&mut pgen_reader, *var_idx, &mut ld_base)?;
+            for (sam_idx, _sam_id) in sam_idx_ids.iter() {
+                vcf_writer.write_all(b"\t")?;
+                vcf_writer.write_all(genotypes.vcf_genotype(*sam_idx).as_bytes())?;
+                if dosage {
+                    vcf_writer.write_all(b":")?;
+                    vcf_writer.write_all(genotypes.vcf_dosage(*sam_idx).as_bytes())?;
+                }
+                if haplotype_dosages {
+                    vcf_writer.write_all(b":")?;
+                    vcf_writer.write_all(genotypes.vcf_haplotype_dosages(*sam_idx).as_bytes())?;
+                }
+            }
+            vcf_writer.write_all(b"\n")?;
+            line_written(vcf_writer, Some(var_rcd))?;
         }
         Ok(())
     }
 
+    /// Like `output_vcf`, but as a BCF 2.2, with each INFO value typed as its
+    /// `##INFO` line declares. The header declares whatever contigs, filters
+    /// and INFO keys the .pvar header lacks.
+    pub fn output_bcf(
+        &self,
+        sam_filter: SampleFilter,
+        var_filter: VariantFilter,
+        filename: PathBuf,
+        dosage: bool,
+    ) -> Result<(), PgenError> {
+        let (pvar_header, _) = self.read_pvar_header()?;
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        // only CHROM, POS and REF are required, the rest default to missing
+        for column in ["CHROM", "POS", "REF"] {
+            self.pvar_column(&pvar_headers, column)?;
+        }
+        let site_columns =
+            VCF_SITE_COLUMNS.map(|column| self.pvar_column(&pvar_headers, column).ok());
+        let var_idx_rcds = self.filter_variants(&mut pvar_reader, var_filter)?;
+        let sam_idx_ids = self.filter_samples(sam_filter)?;
+
+        let format_keys = self.format_keys(&var_idx_rcds, dosage);
+        let mut meta = Vec::new();
+        self.write_vcf_meta(&mut meta, &pvar_header, &format_keys)?;
+        let mut bcf_header = BcfHeader::new(&String::from_utf8_lossy(&meta));
+        for (var_idx, var_rcd) in &var_idx_rcds {
+            bcf_header.declare(&self.bcf_site(*var_idx, var_rcd, &site_columns));
+        }
+        let sam_ids = sam_idx_ids
+            .iter()
+            .map(|(_idx, id)| id.clone())
+            .collect::<Vec<String>>();
+        let mut bcf_writer = BcfWriter::new(
+            BufWriter::new(File::create(filename)?),
+            bcf_header,
+            &sam_ids,
+        )?;
+
+        let mut pgen_reader = File::open(self.pgen_path())?;
+        let mut ld_base = None;
+        for (var_idx, var_rcd) in &var_idx_rcds {
+            let genotypes = self.read_genotypes(&mut pgen_reader, *var_idx, &mut ld_base)?;
+            let mut format = vec![(
+                "GT",
+                FormatValues::Genotypes(
+                    sam_idx_ids
+                        .iter()
+                        .map(|(sam_idx, _)| {
+                            genotypes.phased_alleles(*sam_idx).map(|(first, second)| {
+                                (first, second, genotypes.is_phased(*sam_idx))
+                            })
+                        })
+                        .collect(),
+                ),
+            )];
+            if dosage {
+                let values = sam_idx_ids
+                    .iter()
+                    .map(|(sam_idx, _)| genotypes.dosage(*sam_idx).map(|dosage| dosage as f32))
+                    .collect();
+                format.push((
+                    "DS",
+                    FormatValues::Floats {
+                        per_sample: 1,
+                        values,
+                    },
+                ));
+            }
+            if dosage && self.has_phased_dosage(*var_idx) {
+                let values = sam_idx_ids
+                    .iter()
+                    .flat_map(|(sam_idx, _)| match genotypes.haplotype_dosages(*sam_idx) {
+                        Some((first, second)) => [Some(first as f32), Some(second as f32)],
+                        None => [None, None],
+                    })
+                    .collect();
+                format.push((
+                    "HDS",
+                    FormatValues::Floats {
+                        per_sample: 2,
+                        values,
+                    },
+                ));
+            }
+            bcf_writer.write_record(&self.bcf_site(*var_idx, var_rcd, &site_columns), &format)?;
+        }
+        bcf_writer.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// The site of a variant for `BcfWriter`, given the .pvar columns of
+    /// CHROM, POS, ID, REF, ALT, QUAL, FILTER and INFO. Provisional REF
+    /// alleles get the PR flag.
+    fn bcf_site<'a>(
+        &self,
+        var_idx: usize,
+        var_rcd: &'a StringRecord,
+        site_columns: &[Option<usize>; 8],
+    ) -> Site<'a> {
+        let [chrom, pos, id, reference, alt, qual, filter, info] =
+            site_columns.map(|idx| idx.and_then(|idx| var_rcd.get(idx)).unwrap_or("."));
+        Site {
+            chrom,
+            pos,
+            id,
+            reference,
+            alt,
+            qual,
+            filter,
+            info: match (self.is_provisional_ref(var_idx), info) {
+                (false, _) => Cow::Borrowed(info),
+                (true, ".") => Cow::Borrowed("PR"),
+                (true, _) => Cow::Owned(format!("{};PR", info)),
+            },
+        }
+    }
+
+    /// Writes the `##` lines of a VCF header: the .pvar header comments and
+    /// the FORMAT fields among GT, DS and HDS in `format_keys`.
+    fn write_vcf_meta(
+        &self,
+        vcf_writer: &mut impl Write,
+        pvar_header: &str,
+        format_keys: &[&str],
+    ) -> io::Result<()> {
+        writeln!(vcf_writer, "##fileformat=VCFv4.2")?;
+        writeln!(vcf_writer, "##source=pgen-rs")?;
+        write!(vcf_writer, "{}", pvar_header)?;
+        if self.nonref_flags.is_some() {
+            writeln!(
+                vcf_writer,
+                "##INFO=<ID=PR,Number=0,Type=Flag,Description=\"Provisional reference allele, may not be based on real reference genome\">"
+            )?;
+        }
+        if format_keys.contains(&"GT") {
+            writeln!(
+                vcf_writer,
+                "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">"
+            )?;
+        }
+        if format_keys.contains(&"DS") {
+            writeln!(
+                vcf_writer,
+                "##FORMAT=<ID=DS,Number=1,Type=Float,Description=\"Estimated dosage of the first ALT allele : [P(0/1)+2*P(1/1)]\">"
+            )?;
+        }
+        if format_keys.contains(&"HDS") {
+            writeln!(
+                vcf_writer,
+                "##FORMAT=<ID=HDS,Number=2,Type=Float,Description=\"Estimated haploid dosage of the first ALT allele\">"
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The FORMAT fields of the kept variants: GT, and with `dosage`, DS and
+    /// HDS if any of them has phased dosages.
+    fn format_keys(
+        &self,
+        var_idx_rcds: &[(usize, StringRecord)],
+        dosage: bool,
+    ) -> Vec<&'static str> {
+        let mut format_keys = vec!["GT"];
+        if dosage {
+            format_keys.push("DS");
+            if var_idx_rcds
+                .iter()
+                .any(|(var_idx, _)| self.has_phased_dosage(*var_idx))
+            {
+                format_keys.push("HDS");
+            }
+        }
+        format_keys
+    }
+
+    /// Whether a variant's record has phased dosages, which only those
+    /// variants get HDS for.
+    fn has_phased_dosage(&self, var_idx: usize) -> bool {
+        self.record_type(var_idx) & 0x80 != 0
+    }
+
+    /// The kept samples, with their IIDs.
+    fn filter_samples(&self, sam_filter: SampleFilter) -> Result<Vec<(usize, String)>, PgenError> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?;
+        // Index of the sample id in each sample record.
+        let sam_rcd_id_idx = sam_header
+            .iter()
+            .position(|col| col == "IID")
+            .ok_or_else(|| PgenError::MissingColumn {
+                path: self.psam_path(),
+                column: "IID".to_string(),
+            })?;
+        Ok(self
+            .filter_sample_rows(&mut psam_reader, sam_filter)?
+            .into_iter()
+            .map(|(idx, rcd)| (idx, rcd.get(sam_rcd_id_idx).unwrap_or_default().to_string()))
+            .collect())
+    }
+
+    /// What variant expressions and format strings can refer to besides the
+    /// .pvar columns, given the variables they refer to and their sources.
+    /// The header is only scanned for INFO types, and the genotypes only
+    /// read, when the variables need them.
+    fn variant_variables(
+        &self,
+        variables: &[(&str, &Variable)],
+    ) -> Result<VariantVariables, PgenError> {
+        let info_types = if variables.iter().any(|(_, var)| info_key(var).is_some()) {
+            PvarParser::info_types(&self.read_pvar_header()?.0)
+        } else {
+            HashMap::new()
+        };
+        Ok(VariantVariables {
+            info_types,
+            genotypes: self.genotype_variables(variables)?,
+        })
+    }
+
+    /// The genotype variables among `variables`, if any, since reading the
+    /// genotypes is much slower than reading the .pvar.
+    fn genotype_variables(
+        &self,
+        variables: &[(&str, &Variable)],
+    ) -> Result<Option<GenotypeVariables>, PgenError> {
+        let mut sam_idxs = None;
+        let mut needed = false;
+        for &(source, variable) in variables {
+            let name = variable.name.as_str();
+            if !is_genotype_variable(name) {
+                continue;
+            }
+            needed = true;
+            let (Some(iid), true) = (
+                &variable.subscript,
+                GENOTYPE_FIELDS.contains(&field_name(name)),
+            ) else {
+                continue;
+            };
+            let sam_idxs = match &mut sam_idxs {
+                Some(sam_idxs) => sam_idxs,
+                None => sam_idxs.insert(
+                    self.filter_samples(SampleFilter::default())?
+                        .into_iter()
+                        .map(|(sam_idx, iid)| (iid, sam_idx))
+                        .collect::<HashMap<String, usize>>(),
+                ),
+            };
+            if !sam_idxs.contains_key(iid) {
+                let message = format!("no sample {} in {}", iid, self.psam_path());
+                let error = ExpressionError::new(message, variable.span.clone());
+                return Err(expression_error(source, error));
+            }
+        }
+        if !needed {
+            return Ok(None);
+        }
+        Ok(Some(GenotypeVariables {
+            sam_idxs: sam_idxs.unwrap_or_default(),
+            pgen: File::open(self.pgen_path())?,
+            ld_base: None,
+            genotypes: None,
+        }))
+    }
+
+    /// The index of a .pvar column.
+    fn pvar_column(&self, pvar_headers: &StringRecord, column: &str) -> Result<usize, PgenError> {
+        pvar_headers
+            .iter()
+            .position(|col| col == column)
+            .ok_or_else(|| PgenError::MissingColumn {
+                path: self.pvar_path(),
+                column: column.to_string(),
+            })
+    }
+
+    /// Writes the kept samples and variants as a new pfile, i.e. to
+    /// OUT_PREFIX.pgen, OUT_PREFIX.pvar and OUT_PREFIX.psam. The .pvar and
+    /// .psam keep the header comments of the originals, and a .bim's columns
+    /// are put in the order of a .pvar.
+    ///
+    /// The .pgen is fixed-width when it only needs to hold biallelic
+    /// hardcalls, and variable-width otherwise so that multiallelic calls,
+    /// phase and dosages survive.
+    pub fn output_pgen(
+        &self,
+        sam_filter: SampleFilter,
+        var_filter: VariantFilter,
+        out_prefix: &str,
+    ) -> Result<(), PgenError> {
+        let var_idx_rcds = self.filter_variants(&mut self.pvar_reader()?, var_filter)?;
+        let sam_idx_rcds = self.filter_sample_rows(&mut self.psam_reader()?, sam_filter)?;
+        self.write_pvar(format!("{}.pvar", out_prefix), &var_idx_rcds)?;
+        Pfile::write_metadata(
+            format!("{}.psam", out_prefix),
+            self.read_psam_header()?,
+            &sam_idx_rcds,
+        )?;
+
+        let fixed_width = var_idx_rcds.iter().all(|(var_idx, _)| {
+            self.allele_count(*var_idx) == 2 && self.record_type(*var_idx) & 0xF8 == 0
+        });
+        let max_allele_count = var_idx_rcds
+            .iter()
+            .map(|(var_idx, _)| self.allele_count(*var_idx))
+            .max()
+            .unwrap_or(2);
+        let header = PgenHeader {
+            storage_mode: if fixed_width {
+                StorageMode::FixedWidth
+            } else {
+                StorageMode::VariableWidth
+            },
+            num_variants: var_idx_rcds.len() as u32,
+            num_samples: sam_idx_rcds.len() as u32,
+            record_type_bits: if fixed_width { 0 } else { 8 },
+            record_length_bytes: if fixed_width { 0 } else { 4 },
+            allele_count_bytes: if max_allele_count > 2 {
+                bytes_to_represent(max_allele_count)
+            } else {
+                0
+            },
+            nonref_flags: self.header.nonref_flags,
+        };
+        let mut nonref_flags = vec![0u8; var_idx_rcds.len().div_ceil(8)];
+        for (new_idx, (var_idx, _)) in var_idx_rcds.iter().enumerate() {
+            if self.is_provisional_ref(*var_idx) {
+                pgen_record::set_bit(&mut nonref_flags, new_idx);
+            }
+        }
+
+        let sam_idxs = sam_idx_rcds
+            .iter()
+            .map(|(sam_idx, _)| *sam_idx)
+            .collect::<Vec<usize>>();
+        let mut pgen_writer =
+            PgenWriter::create(&format!("{}.pgen", out_prefix), header, nonref_flags)?;
+        let mut pgen_reader = File::open(self.pgen_path())?;
+        let mut ld_base = None;
+        for (var_idx, _) in var_idx_rcds.iter() {
+            let genotypes = self.read_genotypes(&mut pgen_reader, *var_idx, &mut ld_base)?;
+            pgen_writer.write_variant(&genotypes, &sam_idxs, self.allele_count(*var_idx))?;
+        }
+        pgen_writer.finish()
+    }
+
+    /// Writes the hardcalls of the kept samples and variants as a PLINK 1
+    /// fileset, i.e. to OUT_PREFIX.bed, OUT_PREFIX.bim and OUT_PREFIX.fam.
+    ///
+    /// The .bim has ALT as A1 and REF as A2, a missing ALT being 0, and a
+    /// centimorgan position of 0 unless the .pvar has a CM column. The .fam
+    /// takes FID, PAT, MAT, SEX and PHENO1 from the .psam, with plink's
+    /// missing values for those it lacks. Phase and dosages are lost, and
+    /// multiallelic variants can't be written at all.
+    pub fn output_bed(
+        &self,
+        sam_filter: SampleFilter,
+        var_filter: VariantFilter,
+        out_prefix: &str,
+    ) -> Result<(), PgenError> {
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let bim_columns = ["CHROM", "ID", "CM", "POS", "ALT", "REF"]
+            .map(|column| self.pvar_column(&pvar_headers, column).ok());
+        for column in ["CHROM", "POS", "REF"] {
+            self.pvar_column(&pvar_headers, column)?;
+        }
+        let var_idx_rcds = self.filter_variants(&mut pvar_reader, var_filter)?;
+        // a pgen without allele counts leaves the .pvar to say whether a
+        // variant has several ALT alleles
+        let alt_idx = bim_columns[4];
+        if let Some((var_idx, _)) = var_idx_rcds.iter().find(|(var_idx, var_rcd)| {
+            self.allele_count(*var_idx) > 2
+                || alt_idx
+                    .and_then(|alt_idx| var_rcd.get(alt_idx))
+                    .is_some_and(|alt| alt.contains(','))
+        }) {
+            return Err(PgenError::Unencodable(format!(
+                "variant {} has several ALT alleles, but a .bed can only hold biallelic variants",
+                var_idx
+            )));
+        }
+
+        let mut psam_reader = self.psam_reader()?;
+        let psam_headers = psam_reader.headers()?.clone();
+        let fam_columns = ["FID", "IID", "PAT", "MAT", "SEX", "PHENO1"]
+            .map(|column| psam_headers.iter().position(|col| col == column));
+        if fam_columns[1].is_none() {
+            return Err(PgenError::MissingColumn {
+                path: self.psam_path(),
+                column: "IID".to_string(),
+            });
+        }
+        let sam_idx_rcds = self.filter_sample_rows(&mut psam_reader, sam_filter)?;
+
+        let mut bim_writer = BufWriter::new(File::create(format!("{}.bim", out_prefix))?);
+        for (_, var_rcd) in &var_idx_rcds {
+            let [chrom, id, cm, pos, alt, reference] =
+                bim_columns.map(|idx| idx.and_then(|idx| var_rcd.get(idx)));
+            // plink 1 writes a missing allele as 0
+            let alt = alt.filter(|alt| *alt != ".").unwrap_or("0");
+            writeln!(
+                bim_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                chrom.unwrap_or_default(),
+                id.unwrap_or("."),
+                cm.unwrap_or("0"),
+                pos.unwrap_or_default(),
+                alt,
+                reference.unwrap_or_default()
+            )?;
+        }
+        bim_writer.flush()?;
+
+        let mut fam_writer = BufWriter::new(File::create(format!("{}.fam", out_prefix))?);
+        for (_, sam_rcd) in &sam_idx_rcds {
+            let [fid, iid, pat, mat, sex, pheno] =
+                fam_columns.map(|idx| idx.and_then(|idx| sam_rcd.get(idx)));
+            // plink 1 only knows sexes 1 and 2, and -9 as a missing phenotype
+            let sex = sex.filter(|sex| ["1", "2"].contains(sex)).unwrap_or("0");
+            let pheno = pheno.filter(|pheno| *pheno != "NA").unwrap_or("-9");
+            writeln!(
+                fam_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                fid.unwrap_or("0"),
+                iid.unwrap_or_default(),
+                pat.unwrap_or("0"),
+                mat.unwrap_or("0"),
+                sex,
+                pheno
+            )?;
+        }
+        fam_writer.flush()?;
+
+        let sam_idxs = sam_idx_rcds
+            .iter()
+            .map(|(sam_idx, _)| *sam_idx)
+            .collect::<Vec<usize>>();
+        let mut bed_writer = BedWriter::create(&format!("{}.bed", out_prefix))?;
+        let mut pgen_reader = File::open(self.pgen_path())?;
+        let mut ld_base = None;
+        for (var_idx, _) in var_idx_rcds.iter() {
+            let genotypes = self.read_genotypes(&mut pgen_reader, *var_idx, &mut ld_base)?;
+            bed_writer.write_variant(&genotypes, &sam_idxs)?;
+        }
+        bed_writer.finish()
+    }
+
+    /// Writes a .pvar or .psam with the given header comments and column
+    /// names line, and the given rows.
+    fn write_metadata(
+        meta_path: String,
+        (header_comments, column_names): (String, String),
+        idx_rcds: &[(usize, StringRecord)],
+    ) -> Result<(), PgenError> {
+        let mut meta_writer = BufWriter::new(File::create(meta_path)?);
+        meta_writer.write_all(header_comments.as_bytes())?;
+        writeln!(meta_writer, "{}", column_names.trim_end())?;
+        for (_idx, rcd) in idx_rcds {
+            for (col_idx, col) in rcd.iter().enumerate() {
+                if col_idx > 0 {
+                    meta_writer.write_all(b"\t")?;
+                }
+                meta_writer.write_all(col.as_bytes())?;
+            }
+            meta_writer.write_all(b"\n")?;
+        }
+        meta_writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the kept variants as a .pvar. A .bim, or a .pvar without a
+    /// header line, has its columns put in the standard order, and its
+    /// missing ALTs written as `.`, like `import_bed` does. Its CM column is
+    /// only kept if some centimorgan positions are nonzero.
+    fn write_pvar(
+        &self,
+        pvar_path: String,
+        var_idx_rcds: &[(usize, StringRecord)],
+    ) -> Result<(), PgenError> {
+        let Some(columns) = &self.pvar.synthesized_columns else {
+            return Pfile::write_metadata(pvar_path, self.read_pvar_header()?, var_idx_rcds);
+        };
+        let column = |name: &str| columns.iter().position(|col| *col == name);
+        let has_cm = column("CM").is_some_and(|cm_idx| {
+            var_idx_rcds
+                .iter()
+                .any(|(_, rcd)| rcd.get(cm_idx).map(str::parse::<f64>) != Some(Ok(0.0)))
+        });
+        let names = ["CHROM", "POS", "ID", "REF", "ALT", "CM"];
+        let names = if has_cm { &names[..] } else { &names[..5] };
+        let col_idxs = names.iter().map(|name| column(name)).collect::<Vec<_>>();
+        let alt_idx = column("ALT");
+        let rcds = var_idx_rcds
+            .iter()
+            .map(|(var_idx, rcd)| {
+                let rcd = col_idxs
+                    .iter()
+                    .map(|&col_idx| {
+                        let field = col_idx.and_then(|col_idx| rcd.get(col_idx));
+                        match field.unwrap_or_default() {
+                            "0" if col_idx == alt_idx => ".",
+                            field => field,
+                        }
+                    })
+                    .collect::<StringRecord>();
+                (*var_idx, rcd)
+            })
+            .collect::<Vec<_>>();
+        let header = (String::new(), format!("#{}\n", names.join("\t")));
+        Pfile::write_metadata(pvar_path, header, &rcds)
+    }
+
     fn variant_record_size(&self) -> u32 {
         let bit_size = self.num_samples * 2;
 
-        (bit_size / 8) + if bit_size % 8 == 0 { 0 } else { 1 }
+        bit_size.div_ceil(8)
+    }
+
+    fn read_pvar_header(&self) -> Result<(String, String), PgenError> {
+        Pfile::read_metadata_header(&self.pvar)
     }
 
-    fn read_pvar_header(&self) -> (String, String) {
-        let pvar = File::open(self.pvar_path()).unwrap();
-        let mut pvar_reader = BufReader::new(pvar);
+    fn read_psam_header(&self) -> Result<(String, String), PgenError> {
+        Pfile::read_metadata_header(&self.psam)
+    }
+
+    /// Reads the header comments and the column names line of a .pvar or
+    /// .psam, each with their line endings. Files without a header line get
+    /// one with their synthesized column names.
+    fn read_metadata_header(meta: &MetadataFile) -> Result<(String, String), PgenError> {
+        if let Some(columns) = &meta.synthesized_columns {
+            return Ok((String::new(), format!("#{}\n", columns.join("\t"))));
+        }
+        let meta_path = meta.path.clone();
+        let mut meta_reader = meta.open()?;
         // read all lines that start with # and store them in a vector
         let mut header_lines = Vec::new();
         loop {
             let mut buf = String::new();
-            pvar_reader.read_line(&mut buf).unwrap();
+            meta_reader.read_line(&mut buf)?;
             if buf.starts_with('#') {
                 header_lines.push(buf);
             } else {
@@ -251,40 +1575,50 @@ impl Pfile {
             }
         }
         // the last line must be the column names
-        let header = header_lines.pop().unwrap();
+        let header = header_lines
+            .pop()
+            .ok_or(PgenError::MissingHeaderLine { path: meta_path })?;
         // return the header comments and the column names
-        (header_lines.join(""), header)
+        Ok((header_lines.join(""), header))
     }
 
-    /// Gives the offset to the start of the headers without the headers'
-    /// comment prefix.
+    /// Gives a reader starting at the headers without the headers' comment
+    /// prefix.
     ///
     /// The pvar file will look like the following
     /// (psams look the same with different column types)
     ///
-    ///     ## Some headers
-    ///     ## ...
-    ///     ## Other headers
-    ///     ## Other headers
-    ///     #CHROM ID POS ...
-    ///     12 id pos ...
-    ///     11 id pos ...
+    /// ```text
+    /// ## Some headers
+    /// ## ...
+    /// ## Other headers
+    /// ## Other headers
+    /// #CHROM ID POS ...
+    /// 12 id pos ...
+    /// 11 id pos ...
+    /// ```
     ///
     /// Per the spec, the last header line will indicate the headers for
     /// the rest of the pvar file, in the case of the example this would be
     ///
-    ///     #CHROM ID POS ...
+    /// ```text
+    /// #CHROM ID POS ...
+    /// ```
     ///
-    /// We want to seek to just after the # in that line so that we can
+    /// We want to start just after the # in that line so that we can
     /// give the file reader to csv and it'll handle parsing everything.
     ///
-    ///     #CHROM ID POS ...
-    ///      ^
-    ///      |
-    ///      start here
-    fn find_metadata_file_header_start(meta_file: String) -> io::Result<u64> {
-        let meta_file = File::open(meta_file)?;
-        let mut meta_raw_reader = BufReader::new(meta_file);
+    /// ```text
+    /// #CHROM ID POS ...
+    ///  ^
+    ///  |
+    ///  start here
+    /// ```
+    ///
+    /// A .zst can't be seeked, so instead the lines read past that point
+    /// are put back in front of the rest of the file.
+    fn metadata_file_from_header(meta: &MetadataFile) -> Result<Box<dyn Read>, PgenError> {
+        let mut meta_raw_reader = meta.open()?;
         #[allow(unused_assignments)]
         let mut prev_buf = String::new();
         let mut buf = String::new();
@@ -294,83 +1628,254 @@ impl Pfile {
             meta_raw_reader.read_line(&mut buf)?;
             // We are reading the data now
             if !buf.starts_with('#') {
-                let current_pos = meta_raw_reader.stream_position()?;
                 // The current line is not what we're looking for.
                 // The header is the previous line, but it is forced to start
                 // with a #.
-                // We want to rewind to just after that #.
-                let offset = (buf.len() + prev_buf.len()) as u64 - 1;
-                return Ok(current_pos - offset);
+                let Some(header) = prev_buf.strip_prefix('#') else {
+                    return Err(PgenError::MissingHeaderLine {
+                        path: meta.path.clone(),
+                    });
+                };
+                let read_past = header.to_string() + &buf;
+                return Ok(Box::new(io::Cursor::new(read_past).chain(meta_raw_reader)));
             }
         }
     }
 
-    fn metadata_file_reader(file: String, num_rows: usize) -> io::Result<Reader<File>> {
-        let header_start = Pfile::find_metadata_file_header_start(file.clone())?;
-        let mut meta_file = File::open(file)?;
-        meta_file.seek(SeekFrom::Start(header_start))?;
+    fn metadata_file_reader(
+        meta: &MetadataFile,
+        num_rows: usize,
+    ) -> Result<Reader<Box<dyn Read>>, PgenError> {
+        let meta_file: Box<dyn Read> = match &meta.synthesized_columns {
+            Some(columns) => {
+                // the fields can be separated by any whitespace, and the
+                // synthesized column names stand in for the header line
+                let header = format!("{}\n", columns.join("\t"));
+                Box::new(io::Cursor::new(header).chain(WhitespaceToTabs::new(meta.open()?)))
+            }
+            None => Pfile::metadata_file_from_header(meta)?,
+        };
 
         let meta_reader = ReaderBuilder::new()
             .delimiter(b'\t')
             // per the spec, there are no comments
             .buffer_capacity(num_rows)
-            // we seek to exactly where the headers start
+            // we start exactly where the headers start
             .has_headers(true)
             .from_reader(meta_file);
         Ok(meta_reader)
     }
 
-    pub fn pvar_reader(&self) -> io::Result<Reader<File>> {
-        Pfile::metadata_file_reader(self.pvar_path(), self.num_variants as usize)
+    pub(crate) fn pvar_reader(&self) -> Result<Reader<Box<dyn Read>>, PgenError> {
+        Pfile::metadata_file_reader(&self.pvar, self.num_variants as usize)
     }
 
-    fn _filter_test(&self) -> io::Result<()> {
-        let mut reader = self.pvar_reader()?;
-        let headers: StringRecord = reader.headers()?.clone();
-        let mut count = 0;
-        for record in reader.records() {
-            let mut context = HashMapContext::new();
-            for (var, val) in std::iter::zip(&headers, &record?) {
-                context
-                    .set_value(var.to_string(), Value::String(val.to_string()))
-                    .unwrap();
-            }
-            if eval_boolean_with_context("ID == \"rs8100066\"", &context).unwrap() {
-                count += 1;
+    pub(crate) fn psam_reader(&self) -> Result<Reader<Box<dyn Read>>, PgenError> {
+        Pfile::metadata_file_reader(&self.psam, self.num_samples as usize)
+    }
+
+    /// The kept variants, whose query can also refer to their typed INFO
+    /// keys and genotypes.
+    fn filter_variants(
+        &self,
+        pvar_reader: &mut Reader<Box<dyn Read>>,
+        var_filter: VariantFilter,
+    ) -> Result<Vec<(usize, StringRecord)>, PgenError> {
+        let var_query = parse_query(var_filter.query.clone())?;
+        let variant_variables = self.variant_variables(&expression_variables(var_query.iter()))?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let keep_row = self.variant_row_filter(&pvar_headers, &var_filter)?;
+        let rows = self.variant_rows(pvar_reader, &var_filter, var_query.as_ref())?;
+        let variant_variables = Some(variant_variables);
+        self.filter_rows(&pvar_headers, rows, var_query, variant_variables, keep_row)
+    }
+
+    /// The kept .psam records.
+    fn filter_sample_rows(
+        &self,
+        psam_reader: &mut Reader<Box<dyn Read>>,
+        sam_filter: SampleFilter,
+    ) -> Result<Vec<(usize, StringRecord)>, PgenError> {
+        let sam_query = parse_query(sam_filter.query.clone())?;
+        let psam_headers = psam_reader.headers()?.clone();
+        let keep_row = self.sample_row_filter(&psam_headers, &sam_filter)?;
+        self.filter_metadata(psam_reader, sam_query, None, keep_row)
+    }
+
+    /// Indexes the .pvar for finding the variants in some regions or with
+    /// some IDs, see `pvar_index`. Returns the path of the index.
+    pub fn index_pvar(&self) -> Result<String, PgenError> {
+        pvar_index::write(&self.pvar)
+    }
+
+    /// The .pvar records that can pass `var_filter`, whose query is `query`.
+    /// With a fresh index, and regions, an extract list or a query that only
+    /// holds for some IDs, those are only the records the index finds.
+    /// Otherwise they are all.
+    fn variant_rows<'a>(
+        &self,
+        pvar_reader: &'a mut Reader<Box<dyn Read>>,
+        var_filter: &VariantFilter,
+        query: Option<&Expression>,
+    ) -> Result<Rows<'a>, PgenError> {
+        let query_ids = query.and_then(|query| query.required_values("ID"));
+        let extract_ids = var_filter.extract.as_ref().map(VariantList::ids);
+        if var_filter.regions.is_none() && query_ids.is_none() && extract_ids.is_none() {
+            return Ok(rows(pvar_reader));
+        }
+        let Some(mut index) = PvarIndex::open(&self.pvar, self.num_variants as usize)? else {
+            return Ok(rows(pvar_reader));
+        };
+        let mut var_idxs = match &var_filter.regions {
+            Some(regions) => Some(index.variants_in(regions)?),
+            None => None,
+        };
+        for ids in [query_ids, extract_ids].into_iter().flatten() {
+            let with_ids = index.variants_with_ids(&ids)?;
+            match &mut var_idxs {
+                Some(var_idxs) => {
+                    var_idxs.retain(|var_idx| with_ids.binary_search(var_idx).is_ok())
+                }
+                None => var_idxs = Some(with_ids),
             }
         }
-        println!("Keeping {} rows", count);
-        Ok(())
+        let var_idxs = var_idxs.expect("regions or IDs narrow the variants down");
+        Ok(Box::new(index.records(&self.pvar, var_idxs)?))
     }
 
-    pub fn psam_reader(&self) -> io::Result<Reader<File>> {
-        Pfile::metadata_file_reader(self.psam_path(), self.num_samples as usize)
+    /// Whether a .pvar record passes `var_filter` before its query: it is in
+    /// the regions, by its CHROM and POS, and its ID is in the extract list
+    /// and not in the exclude list.
+    fn variant_row_filter<'a>(
+        &self,
+        pvar_headers: &StringRecord,
+        var_filter: &'a VariantFilter,
+    ) -> Result<RowFilter<'a>, PgenError> {
+        let regions = match &var_filter.regions {
+            Some(regions) => Some((
+                regions,
+                self.pvar_column(pvar_headers, "CHROM")?,
+                self.pvar_column(pvar_headers, "POS")?,
+            )),
+            None => None,
+        };
+        let id_idx = match (&var_filter.extract, &var_filter.exclude) {
+            (None, None) => None,
+            _ => Some(self.pvar_column(pvar_headers, "ID")?),
+        };
+        Ok(Box::new(move |rcd: &StringRecord| {
+            let in_regions = match regions {
+                Some((regions, chrom_idx, pos_idx)) => rcd
+                    .get(pos_idx)
+                    .and_then(|pos| pos.parse().ok())
+                    .is_some_and(|pos| {
+                        regions.contains(rcd.get(chrom_idx).unwrap_or_default(), pos)
+                    }),
+                None => true,
+            };
+            let id = id_idx
+                .and_then(|id_idx| rcd.get(id_idx))
+                .unwrap_or_default();
+            in_regions
+                && var_filter
+                    .extract
+                    .as_ref()
+                    .is_none_or(|extract| extract.contains(id))
+                && !var_filter
+                    .exclude
+                    .as_ref()
+                    .is_some_and(|exclude| exclude.contains(id))
+        }))
+    }
+
+    /// Whether a .psam record passes `sam_filter` before its query: its IID,
+    /// and FID if both the .psam and the list have one, are in the keep list
+    /// and not in the remove list.
+    fn sample_row_filter<'a>(
+        &self,
+        psam_headers: &StringRecord,
+        sam_filter: &'a SampleFilter,
+    ) -> Result<RowFilter<'a>, PgenError> {
+        let column = |column: &str| psam_headers.iter().position(|col| col == column);
+        let id_idxs = match (&sam_filter.keep, &sam_filter.remove) {
+            (None, None) => None,
+            _ => Some((
+                column("FID"),
+                column("IID").ok_or_else(|| PgenError::MissingColumn {
+                    path: self.psam_path(),
+                    column: "IID".to_string(),
+                })?,
+            )),
+        };
+        Ok(Box::new(move |rcd: &StringRecord| {
+            let Some((fid_idx, iid_idx)) = id_idxs else {
+                return true;
+            };
+            let fid = fid_idx.and_then(|fid_idx| rcd.get(fid_idx));
+            let iid = rcd.get(iid_idx).unwrap_or_default();
+            sam_filter
+                .keep
+                .as_ref()
+                .is_none_or(|keep| keep.contains(fid, iid))
+                && !sam_filter
+                    .remove
+                    .as_ref()
+                    .is_some_and(|remove| remove.contains(fid, iid))
+        }))
     }
 
     fn filter_metadata(
         &self,
-        meta_reader: &mut Reader<File>,
-        query: Option<String>,
-    ) -> csv::Result<Vec<(usize, StringRecord)>> {
+        meta_reader: &mut Reader<Box<dyn Read>>,
+        query: Option<Expression>,
+        variant_variables: Option<VariantVariables>,
+        keep_row: impl Fn(&StringRecord) -> bool,
+    ) -> Result<Vec<(usize, StringRecord)>, PgenError> {
         let headers: StringRecord = meta_reader.headers()?.clone();
+        self.filter_rows(
+            &headers,
+            rows(meta_reader),
+            query,
+            variant_variables,
+            keep_row,
+        )
+    }
+
+    /// The `rows` that pass `keep_row` and then `query`, whose columns are
+    /// `headers`.
+    fn filter_rows(
+        &self,
+        headers: &StringRecord,
+        rows: Rows,
+        query: Option<Expression>,
+        mut variant_variables: Option<VariantVariables>,
+        keep_row: impl Fn(&StringRecord) -> bool,
+    ) -> Result<Vec<(usize, StringRecord)>, PgenError> {
+        let bindings = Bindings::new(
+            headers,
+            &expression_variables(query.iter()),
+            variant_variables.as_ref(),
+        )?;
         let mut kept_idx_vars = Vec::new();
-        for (idx, rcd) in meta_reader.records().enumerate() {
-            let rcd = rcd?;
-            let query_res = query.as_ref().map_or(true, |query| {
-                let mut context = HashMapContext::new();
-                for (var, val) in std::iter::zip(&headers, &rcd) {
-                    if var == "INFO" {
-                        let kvpairs = PvarParser::get_info_kv_pairs(val);
-                        for (k, v) in kvpairs {
-                            context.set_value(format!("{}{}{}","INFO[",k ,"]"), Value::String(v)).unwrap();
+        for row in rows {
+            let (idx, rcd) = row?;
+            if !keep_row(&rcd) {
+                continue;
+            }
+            let query_res = match &query {
+                Some(query) => {
+                    let scope = match &mut variant_variables {
+                        Some(variant_variables) => {
+                            variant_variables.scope(self, idx, &bindings, &rcd)?
                         }
-                    }
-                    context
-                        .set_value(var.to_string(), Value::String(val.to_string()))
-                        .unwrap();
+                        None => RowScope::new(&bindings, &rcd),
+                    };
+                    query
+                        .eval_filter(&scope)
+                        .map_err(|error| expression_error(query.source(), error))?
                 }
-                eval_boolean_with_context(query, &context).unwrap()
-            });
+                None => true,
+            };
             if query_res {
                 kept_idx_vars.push((idx, rcd));
             }
@@ -378,19 +1883,28 @@ impl Pfile {
         Ok(kept_idx_vars)
     }
 
-    /// Returns the columns and first row for the given metadata type.
+    /// Returns the columns and first row for the given metadata type, then
+    /// the descriptions of the INFO keys for variants.
     ///
     /// E.g. for .psam (with spaces intead of tabs):
     ///
+    /// ```text
     /// IID SEX
     /// id1 N/A
-    pub fn metadata_columns_and_first_row(&self, query_type: &QueryType) -> io::Result<String> {
+    /// ```
+    fn metadata_columns_and_first_row(&self, query_type: &QueryType) -> Result<String, PgenError> {
         let mut meta_reader = match query_type {
             QueryType::Sample => self.psam_reader(),
             QueryType::Variant => self.pvar_reader(),
         }?;
 
-        let descriptions = PvarParser::format_descriptions(&self.pvar_path()).ok().unwrap().join("\n");
+        // a file without a header line has no descriptions to scan for
+        let descriptions = match (query_type, &self.pvar.synthesized_columns) {
+            (QueryType::Sample, _) | (_, Some(_)) => String::new(),
+            (QueryType::Variant, None) => {
+                PvarParser::format_descriptions(self.pvar.open()?)?.join("\n")
+            }
+        };
         let columns = meta_reader
             .headers()?
             .iter()
@@ -399,14 +1913,24 @@ impl Pfile {
         let first_row = meta_reader
             .records()
             .next()
-            .unwrap()?
+            .transpose()?
+            .unwrap_or_default();
+        let first_row = first_row
             .iter()
             .collect::<Vec<&str>>()
             .join("\t");
-        Ok(columns + "\n" + &descriptions + "\n" + &first_row)
+        Ok([columns, first_row, descriptions]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n"))
     }
 
-    pub fn create_ai_query(&self, query_type: &QueryType, prompt: &str) -> io::Result<String> {
+    pub fn create_ai_query(
+        &self,
+        query_type: &QueryType,
+        prompt: &str,
+    ) -> Result<String, PgenError> {
         match query_type {
             QueryType::Sample => {
                 Ok(format!(r#"
@@ -414,11 +1938,9 @@ I've developed a tool for filtering genomic data by sample and I would like you
 
 Queries are expressions that return a boolean value in a simple expression language. This language supports operations common to many programming languages, such as `==` for equality comparison, `""` for constructing string literals, and  `||` for boolean OR.
 
-To make these queries not behave statically, the tool instantiates special variables with values from the data itself. All of the variables are of type string, even those which represent numeric data.
-
-When filtering, the tool creates variables which correspond to the metadata fields in the sample, such as its identifier `IID`.
+To make these queries not behave statically, the tool instantiates special variables with values from the data itself. When filtering, the tool creates a variable for each column of the sample's row in the .psam file, such as its identifier `IID`, its family `FID`, `SEX` or a phenotype like `PHENO1`. All of these variables are strings, but strings compared with numbers are compared as numbers, so `PHENO1 > 1.5` compares the phenotype numerically.
 
-Below is a list of all variables (with INFO being a dictionary), sample values for each variable, and finally one or more lines of descriptions for the keys in the INFO dictionary. The first line corresponds to the variables. The second line is an sample value for each. The remaining lines starting with a "-" are the descriptions of the INFO fields.
+Below is a list of all variables. The first line corresponds to the variables. The second line is the value of each for the first sample.
 
 ```
 {}
@@ -449,11 +1971,11 @@ I've developed a tool for filtering genomic data by variant and I would like you
 
 Queries are expressions that return a boolean value in a simple expression language. This language supports operations common to many programming languages, such as `==` for equality comparison, `""` for constructing string literals, and  `||` for boolean OR.
 
-To make these queries not behave statically, the tool instantiates special variables with values from the data itself. All of the variables are of type string, even those which represent numeric data.
+To make these queries not behave statically, the tool instantiates special variables with values from the data itself. When filtering, the tool creates variables which correspond to the standard values seen in a .vcf file. For example, `ALT` references to the alternate allele for the current variant. There may be other variables too.
 
-When filtering, the tool creates variables which correspond to the standard values seen in a .vcf file. For example, `ALT` references to the alternate allele for the current variant. There may be other variables too.
+The numeric variables are `POS`, `QUAL` and `CM` (the centimorgan position, in files converted from PLINK 1), the INFO values declared as `Integer` or `Float` in the .pvar header, and the counts computed from the genotypes: `N_HET`, `N_HOMALT`, `N_MISSING`, `AC` (the number of ALT alleles in the calls), `AN` (the number of alleles in the calls) and `AF` (`AC / AN`). INFO values declared as `Flag` are booleans, and the other variables are strings, but strings compared with numbers are compared as numbers. Fields with several comma-separated values, like `ALT`, match a comparison if any of their values does.
 
-Below is a list of all variables (with INFO being a dictionary), sample values for each variable, and finally one or more lines of descriptions for the keys in the INFO dictionary. The first line corresponds to the variables. The second line is a sample value for each. The remaining lines starting with a "-" are the descriptions of the INFO fields.
+Below is a list of all variables (with the INFO keys available as `INFO/KEY`), sample values for each variable, and finally one or more lines of descriptions for the keys in the INFO dictionary. The first line corresponds to the variables. The second line is a sample value for each. The remaining lines starting with a "-" are the descriptions of the INFO fields.
 
 ```
 {}
@@ -464,19 +1986,19 @@ Below are three sample queries:
 Keep all of the variants with `POS` equal to `10` or `20`.
 
 ```
-POS=="10" || POS=="20"
+POS == 10 || POS == 20
 ```
 
 Keep all of the variants with `G` as the alternate allele and whose `POS` isn't `10`.
 
 ```
-ALT=="G" && POS !="10"
+ALT == "G" && POS != 10
 ```
 
 Keep all of the variants with `AC` (meaning allele count, located in the INFO column) equal to `20`.
 
 ```
-INFO[AC] == "20"
+INFO/AC == 20
 ```
 
 I would like you to write me a query with the following specification: {}.
@@ -487,3 +2009,845 @@ Please return a response with one query per line. If you are confident in it, on
         }
     }
 }
+
+/// The per-sample fields of variant expressions, read from the genotypes.
+const GENOTYPE_FIELDS: [&str; 2] = ["GT", "DS"];
+
+/// The variables of variant expressions computed from the genotypes of all
+/// of the pfile's samples.
+const GENOTYPE_COUNT_VARIABLES: [&str; 6] = ["N_HET", "N_HOMALT", "N_MISSING", "AC", "AN", "AF"];
+
+/// `GT` for `GT`, `FMT/GT` and `FORMAT/GT`.
+fn field_name(name: &str) -> &str {
+    name.strip_prefix("FMT/")
+        .or_else(|| name.strip_prefix("FORMAT/"))
+        .unwrap_or(name)
+}
+
+/// Where the variables of a query or format string get their values, worked
+/// out once from the column names rather than for each row: a column, an
+/// INFO key, or the genotypes of a variant. Only the referenced columns and
+/// INFO keys are looked at in each row.
+struct Bindings {
+    /// The variables bound to a column or the genotypes, by name.
+    names: HashMap<String, Binding>,
+    info_idx: Option<usize>,
+    /// The referenced INFO keys, with their declared types.
+    info_keys: Vec<(String, Option<InfoType>)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    Column(usize),
+    /// An index into `Bindings::info_keys`.
+    Info(usize),
+    Genotypes,
+}
+
+impl Bindings {
+    /// Binds `variables`, along with the sources they come from, to the
+    /// columns `headers` and, for variants, to the INFO keys and genotypes,
+    /// failing on the first one that refers to none of them.
+    fn new(
+        headers: &StringRecord,
+        variables: &[(&str, &Variable)],
+        variant_variables: Option<&VariantVariables>,
+    ) -> Result<Bindings, PgenError> {
+        let mut bindings = Bindings {
+            names: HashMap::new(),
+            info_idx: variant_variables
+                .and_then(|_| headers.iter().position(|header| header == "INFO")),
+            info_keys: Vec::new(),
+        };
+        for &(source, variable) in variables {
+            let error = |message| {
+                expression_error(source, ExpressionError::new(message, variable.span.clone()))
+            };
+            let name = variable.name.as_str();
+            let binding = match bindings.get(variable) {
+                Some(binding) => binding,
+                None => match (info_key(variable), bindings.info_idx, variant_variables) {
+                    (Some(key), Some(_), Some(variant_variables)) => {
+                        let info_type = variant_variables.info_types.get(key).cloned();
+                        bindings.info_keys.push((key.to_string(), info_type));
+                        Binding::Info(bindings.info_keys.len() - 1)
+                    }
+                    _ => {
+                        let binding = match headers.iter().position(|header| header == name) {
+                            Some(col_idx) => Binding::Column(col_idx),
+                            None if variant_variables.is_some_and(|v| v.genotypes.is_some())
+                                && is_genotype_variable(name) =>
+                            {
+                                Binding::Genotypes
+                            }
+                            None => {
+                                let columns = headers.iter().collect::<Vec<_>>().join(", ");
+                                return Err(error(format!(
+                                    "unknown variable `{}`, the columns are {}",
+                                    name, columns
+                                )));
+                            }
+                        };
+                        bindings.names.insert(name.to_string(), binding);
+                        binding
+                    }
+                },
+            };
+            // GT[IID] and DS[IID] are checked by `Pfile::genotype_variables`
+            match (binding, &variable.subscript) {
+                (_, None) => {}
+                (Binding::Info(_), Some(_)) if name == "INFO" => {}
+                (Binding::Genotypes, Some(_)) if GENOTYPE_FIELDS.contains(&field_name(name)) => {}
+                (Binding::Genotypes, Some(_)) => {
+                    return Err(error(format!("{} has no subscript", name)));
+                }
+                (_, Some(subscript)) => {
+                    if subscript.parse::<usize>().is_err() {
+                        return Err(error(format!(
+                            "`{}` is not an index of {}",
+                            subscript, name
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(bindings)
+    }
+
+    fn get(&self, variable: &Variable) -> Option<Binding> {
+        match (info_key(variable), self.info_idx) {
+            (Some(key), Some(_)) => self
+                .info_keys
+                .iter()
+                .position(|(info_key, _)| info_key == key)
+                .map(Binding::Info),
+            _ => self.names.get(&variable.name).copied(),
+        }
+    }
+}
+
+/// The values of the variables of one row of a .pvar or .psam, as bound by
+/// `Bindings`.
+struct RowScope<'a> {
+    bindings: &'a Bindings,
+    rcd: &'a StringRecord,
+    /// The values of the referenced INFO keys, parsed when first needed.
+    info: OnceCell<Vec<Value>>,
+    genotypes: Option<GenotypeScope<'a>>,
+}
+
+impl<'a> RowScope<'a> {
+    fn new(bindings: &'a Bindings, rcd: &'a StringRecord) -> RowScope<'a> {
+        RowScope {
+            bindings,
+            rcd,
+            info: OnceCell::new(),
+            genotypes: None,
+        }
+    }
+
+    fn binding(&self, variable: &Variable) -> Result<Binding, String> {
+        self.bindings
+            .get(variable)
+            .ok_or_else(|| format!("`{}` wasn't bound before reading the rows", variable.name))
+    }
+}
+
+impl Scope for RowScope<'_> {
+    fn value(&self, variable: &Variable) -> Result<Value, String> {
+        let value = match self.binding(variable)? {
+            Binding::Column(col_idx) => {
+                column_value(&variable.name, self.rcd.get(col_idx).unwrap_or_default())
+            }
+            Binding::Info(key_idx) => {
+                let info = self.info.get_or_init(|| {
+                    let info_idx = self.bindings.info_idx.expect("INFO keys are bound to INFO");
+                    info_values(
+                        self.rcd.get(info_idx).unwrap_or_default(),
+                        &self.bindings.info_keys,
+                    )
+                });
+                // INFO[KEY] only names the key
+                if variable.name == "INFO" {
+                    return Ok(info[key_idx].clone());
+                }
+                info[key_idx].clone()
+            }
+            Binding::Genotypes => {
+                let genotypes = self
+                    .genotypes
+                    .as_ref()
+                    .expect("genotypes are bound when read");
+                return Ok(genotypes.value(variable));
+            }
+        };
+        Ok(match &variable.subscript {
+            None => value,
+            Some(subscript) => {
+                let idx: usize = subscript.parse().expect("checked by `Bindings::new`");
+                match value {
+                    Value::List(values) => values.into_iter().nth(idx).unwrap_or(Value::Missing),
+                    value if idx == 0 => value,
+                    _ => Value::Missing,
+                }
+            }
+        })
+    }
+}
+
+impl SampleScope for RowScope<'_> {
+    fn sample_value(&self, variable: &Variable, sam_idx: usize) -> Result<Value, String> {
+        let field = field_name(&variable.name);
+        match (self.binding(variable)?, &self.genotypes) {
+            (Binding::Genotypes, Some(genotypes))
+                if GENOTYPE_FIELDS.contains(&field) && variable.subscript.is_none() =>
+            {
+                Ok(genotypes.sample_value(field, sam_idx))
+            }
+            _ => self.value(variable),
+        }
+    }
+}
+
+/// The INFO key of `INFO/KEY` or `INFO[KEY]`.
+fn info_key(variable: &Variable) -> Option<&str> {
+    match variable.name.strip_prefix("INFO/") {
+        Some(key) => Some(key),
+        None if variable.name == "INFO" => variable.subscript.as_deref(),
+        None => None,
+    }
+}
+
+/// Whether a variable of variant expressions is computed from the genotypes.
+fn is_genotype_variable(name: &str) -> bool {
+    GENOTYPE_FIELDS.contains(&field_name(name)) || GENOTYPE_COUNT_VARIABLES.contains(&name)
+}
+
+/// The value of a .pvar or .psam field: POS, QUAL and CM are numbers, and
+/// ALT and FILTER are lists if they hold several values.
+fn column_value(column: &str, field: &str) -> Value {
+    match (column, field) {
+        (_, ".") => Value::Missing,
+        ("POS", field) => field
+            .parse()
+            .map_or_else(|_| Value::Str(field.to_string()), Value::Int),
+        ("QUAL" | "CM", field) => field
+            .parse()
+            .map_or_else(|_| Value::Str(field.to_string()), Value::Float),
+        ("ALT", field) => Value::from_field(field, ','),
+        ("FILTER", field) => Value::from_field(field, ';'),
+        (_, field) => Value::Str(field.to_string()),
+    }
+}
+
+/// The values of the INFO keys `keys` in one pass over an INFO column, the
+/// first occurrence of a key winning. Flags are true when present and false
+/// otherwise, if declared as such; other keys are missing if they aren't
+/// there.
+fn info_values(info: &str, keys: &[(String, Option<InfoType>)]) -> Vec<Value> {
+    let mut values: Vec<Option<Value>> = vec![None; keys.len()];
+    for kv in info.split(';') {
+        let (key, field) = match kv.split_once('=') {
+            Some((key, field)) => (key, Some(field)),
+            None => (kv, None),
+        };
+        if let Some(key_idx) = keys.iter().position(|(info_key, _)| info_key == key) {
+            let value = &mut values[key_idx];
+            if value.is_none() {
+                *value = Some(info_value(field, keys[key_idx].1.as_ref()));
+            }
+        }
+    }
+    values
+        .into_iter()
+        .zip(keys)
+        .map(|(value, (_, info_type))| {
+            value.unwrap_or(match info_type {
+                Some(info_type) if info_type.value_type == "Flag" => Value::Bool(false),
+                _ => Value::Missing,
+            })
+        })
+        .collect()
+}
+
+/// The value of an INFO key that is present, `field` being None for a flag,
+/// typed according to its `##INFO` line if it has one. Keys with several
+/// values (a Number other than 0 or 1) are lists even when they hold one.
+/// Undeclared keys are strings or lists of strings.
+fn info_value(field: Option<&str>, info_type: Option<&InfoType>) -> Value {
+    let Some(v) = field else {
+        return Value::Bool(true);
+    };
+    let Some(info_type) = info_type else {
+        return Value::from_field(v, ',');
+    };
+    if info_type.value_type == "Flag" {
+        return Value::Bool(true);
+    }
+    let item = |item: &str| match (item, info_type.value_type.as_str()) {
+        (".", _) => Value::Missing,
+        (item, "Integer") => item
+            .parse()
+            .map_or_else(|_| Value::Str(item.to_string()), Value::Int),
+        (item, "Float") => item
+            .parse()
+            .map_or_else(|_| Value::Str(item.to_string()), Value::Float),
+        (item, _) => Value::Str(item.to_string()),
+    };
+    if v.contains(',') || !matches!(info_type.number.as_str(), "0" | "1") {
+        Value::List(v.split(',').map(item).collect())
+    } else {
+        item(v)
+    }
+}
+
+/// The genotype variables of a variant, see `GenotypeVariables`.
+struct GenotypeScope<'a> {
+    genotypes: &'a Genotypes,
+    num_samples: usize,
+    sam_idxs: &'a HashMap<String, usize>,
+    /// N_HET, N_HOMALT, N_MISSING, AC and AN, counted when first needed.
+    counts: OnceCell<[i64; 5]>,
+}
+
+impl GenotypeScope<'_> {
+    /// The value of a genotype variable, whose subscript is checked by
+    /// `Bindings::new`.
+    fn value(&self, variable: &Variable) -> Value {
+        let name = variable.name.as_str();
+        let field = field_name(name);
+        if GENOTYPE_FIELDS.contains(&field) {
+            let sample_value = |sam_idx: usize| self.sample_value(field, sam_idx);
+            return match &variable.subscript {
+                // checked to be a sample by `Pfile::genotype_variables`
+                Some(iid) => sample_value(self.sam_idxs[iid]),
+                None => Value::Samples((0..self.num_samples).map(sample_value).collect()),
+            };
+        }
+        let count_idx = GENOTYPE_COUNT_VARIABLES
+            .iter()
+            .position(|var| *var == name)
+            .expect("bound to the genotypes");
+        let counts = self.counts.get_or_init(|| self.count());
+        match count_idx {
+            // like bcftools, a variant without calls has no frequency
+            5 if counts[4] == 0 => Value::Missing,
+            5 => Value::Float(counts[3] as f64 / counts[4] as f64),
+            count_idx => Value::Int(counts[count_idx]),
+        }
+    }
+
+    /// The value of one of `GENOTYPE_FIELDS` for a sample.
+    fn sample_value(&self, field: &str, sam_idx: usize) -> Value {
+        match field {
+            "GT" => Value::Genotype {
+                alleles: self.genotypes.phased_alleles(sam_idx),
+                phased: self.genotypes.is_phased(sam_idx),
+            },
+            _ => self
+                .genotypes
+                .dosage(sam_idx)
+                .map_or(Value::Missing, Value::Float),
+        }
+    }
+
+    fn count(&self) -> [i64; 5] {
+        let (mut n_het, mut n_homalt, mut n_missing, mut ac) = (0, 0, 0, 0);
+        for sam_idx in 0..self.num_samples {
+            match self.genotypes.alleles(sam_idx) {
+                None => n_missing += 1,
+                Some((first, second)) => {
+                    if first != second {
+                        n_het += 1;
+                    } else if first != 0 {
+                        n_homalt += 1;
+                    }
+                    ac += (first != 0) as i64 + (second != 0) as i64;
+                }
+            }
+        }
+        let an = 2 * (self.num_samples as i64 - n_missing);
+        [n_het, n_homalt, n_missing, ac, an]
+    }
+}
+
+/// What variant expressions can refer to besides the .pvar columns.
+struct VariantVariables {
+    /// The Number and Type of the INFO keys the .pvar header declares, if
+    /// the expressions refer to INFO keys.
+    info_types: HashMap<String, InfoType>,
+    genotypes: Option<GenotypeVariables>,
+}
+
+impl VariantVariables {
+    /// The variables of the variant `var_idx`, reading its genotypes if
+    /// needed.
+    fn scope<'a>(
+        &'a mut self,
+        pfile: &Pfile,
+        var_idx: usize,
+        bindings: &'a Bindings,
+        rcd: &'a StringRecord,
+    ) -> Result<RowScope<'a>, PgenError> {
+        let mut scope = RowScope::new(bindings, rcd);
+        if let Some(genotypes) = &mut self.genotypes {
+            scope.genotypes = Some(genotypes.read(pfile, var_idx)?);
+        }
+        Ok(scope)
+    }
+}
+
+/// What it takes to read the genotype variables of each variant, when an
+/// expression refers to any.
+struct GenotypeVariables {
+    /// The samples of the `GT[IID]` and `DS[IID]` variables, by IID.
+    sam_idxs: HashMap<String, usize>,
+    pgen: File,
+    ld_base: Option<(usize, Vec<u8>)>,
+    genotypes: Option<Genotypes>,
+}
+
+impl GenotypeVariables {
+    /// Reads the genotypes of the variant `var_idx`. Variants are read in
+    /// order, so LD-compressed records find their base cached.
+    fn read(&mut self, pfile: &Pfile, var_idx: usize) -> Result<GenotypeScope<'_>, PgenError> {
+        let genotypes = pfile.read_genotypes(&mut self.pgen, var_idx, &mut self.ld_base)?;
+        Ok(GenotypeScope {
+            genotypes: self.genotypes.insert(genotypes),
+            num_samples: pfile.num_samples as usize,
+            sam_idxs: &self.sam_idxs,
+            counts: OnceCell::new(),
+        })
+    }
+}
+
+/// The END key of an INFO column, if it has one.
+pub(crate) fn info_end(info: &str) -> Option<u64> {
+    info.split(';')
+        .find_map(|kv| kv.strip_prefix("END="))
+        .and_then(|end| end.parse().ok())
+}
+
+/// Running out of file while reading the header means the header is
+/// truncated, rather than some I/O problem.
+fn truncated_header(error: PgenError) -> PgenError {
+    match error {
+        PgenError::Io(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            PgenError::InvalidHeader("the file ends before the header does".to_string())
+        }
+        error => error,
+    }
+}
+
+fn expression_error(source: &str, error: ExpressionError) -> PgenError {
+    PgenError::Expression {
+        expression: source.to_string(),
+        error,
+    }
+}
+
+fn parse_expression(source: &str) -> Result<Expression, PgenError> {
+    Expression::parse(source).map_err(|error| expression_error(source, error))
+}
+
+/// The variables of expressions, along with the source of their expression.
+fn expression_variables<'a>(
+    expressions: impl Iterator<Item = &'a Expression>,
+) -> Vec<(&'a str, &'a Variable)> {
+    expressions
+        .flat_map(|expression| {
+            let source = expression.source();
+            expression
+                .variables()
+                .into_iter()
+                .map(move |var| (source, var))
+        })
+        .collect()
+}
+
+/// All the records of a .pvar or .psam.
+fn rows(meta_reader: &mut Reader<Box<dyn Read>>) -> Rows<'_> {
+    Box::new(
+        meta_reader
+            .records()
+            .enumerate()
+            .map(|(idx, rcd)| Ok((idx, rcd?))),
+    )
+}
+
+fn parse_query(query: Option<String>) -> Result<Option<Expression>, PgenError> {
+    query.as_deref().map(parse_expression).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn variable_width_index() {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-index-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // 3 variants of 4 samples: 0/0 0/1 1/1 ./. as a 2-bit array, then
+        // LD-compressed against it with sample 0 hom alt, then a difflist
+        // against hom ref with sample 3 het
+        let records = [vec![0b11_10_01_00], vec![1, 0, 0b10], vec![1, 3, 0b01]];
+        // 4-bit record types, 1-byte lengths and allele counts, and explicit
+        // nonref flags, marking the second variant's REF as provisional
+        let counts_and_flags = [3, 0, 0, 0, 4, 0, 0, 0, 0xD0];
+        let index_len = 3 + counts_and_flags.len() as u64 + 8 + 2 + 3 + 3 + 1;
+        for (storage_mode, records_start) in [(0x10, index_len), (0x11, 3)] {
+            let prefix = dir.join(format!("mode{:x}", storage_mode));
+            let prefix = prefix.to_string_lossy().into_owned();
+            let mut header = vec![0x6C, 0x1B, storage_mode];
+            header.extend_from_slice(&counts_and_flags);
+            header.extend_from_slice(&records_start.to_le_bytes());
+            header.extend_from_slice(&[0x20, 0x04]);
+            header.extend(records.iter().map(|record| record.len() as u8));
+            header.extend_from_slice(&[2, 2, 3]);
+            header.push(0b010);
+            let mut pgen = match storage_mode {
+                0x10 => header,
+                _ => {
+                    fs::write(format!("{}.pgen.pgi", prefix), header).unwrap();
+                    vec![0x6C, 0x1B, storage_mode]
+                }
+            };
+            pgen.extend(records.concat());
+            fs::write(format!("{}.pgen", prefix), pgen).unwrap();
+
+            let pfile = Pfile::from_prefix(prefix).unwrap();
+            assert_eq!(pfile.header.record_type_bits, 4);
+            assert_eq!(pfile.header.allele_count_bytes, 1);
+            assert_eq!(
+                (0..3)
+                    .map(|var_idx| pfile.allele_count(var_idx))
+                    .collect::<Vec<_>>(),
+                [2, 2, 3]
+            );
+            assert_eq!(
+                (0..3)
+                    .map(|var_idx| pfile.is_provisional_ref(var_idx))
+                    .collect::<Vec<_>>(),
+                [false, true, false]
+            );
+            let mut pgen = File::open(pfile.pgen_path()).unwrap();
+            let mut ld_base = None;
+            let calls = (0..3)
+                .map(|var_idx| {
+                    let genotypes = pfile
+                        .read_genotypes(&mut pgen, var_idx, &mut ld_base)
+                        .unwrap();
+                    (0..4)
+                        .map(|sam_idx| genotypes.vcf_genotype(sam_idx))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                calls,
+                ["0/0 0/1 1/1 ./.", "1/1 0/1 1/1 ./.", "0/0 0/0 0/0 0/1"],
+                "storage mode {:#x}",
+                storage_mode
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_offsets_past_the_end() {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-offsets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("offsets").to_string_lossy().into_owned();
+        // a variant of 4 samples whose 1-byte record starts at byte 22,
+        // right after the index
+        let pgen = |records_start: u64, record_len: u8| {
+            let mut pgen = vec![0x6C, 0x1B, 0x10, 1, 0, 0, 0, 4, 0, 0, 0, 0x00];
+            pgen.extend_from_slice(&records_start.to_le_bytes());
+            pgen.extend_from_slice(&[0x00, record_len, 0b11_10_01_00]);
+            fs::write(format!("{}.pgen", prefix), pgen).unwrap();
+            Pfile::from_prefix(prefix.clone())
+        };
+        assert!(pgen(22, 1).is_ok());
+        assert!(matches!(pgen(22, 2), Err(PgenError::InvalidHeader(_))));
+        assert!(matches!(pgen(23, 1), Err(PgenError::InvalidHeader(_))));
+        assert!(matches!(
+            pgen(u64::MAX, 1),
+            Err(PgenError::InvalidHeader(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Reads the header of 3 variants of 2 samples with the control byte
+    /// `header_ctrl`.
+    fn read_header(storage_mode: StorageMode, header_ctrl: u8) -> Result<PgenHeader, PgenError> {
+        let bytes = [3, 0, 0, 0, 2, 0, 0, 0, header_ctrl];
+        PgenHeader::read(storage_mode, &mut &bytes[..])
+    }
+
+    #[test]
+    fn header_control_byte() {
+        // bit 2 chooses 4- or 8-bit record types, and bits 0-1 are the
+        // width of the record lengths minus 1
+        for (header_ctrl, record_type_bits, record_length_bytes) in [
+            (0x00, 4, 1),
+            (0x01, 4, 2),
+            (0x03, 4, 4),
+            (0x04, 8, 1),
+            (0x06, 8, 3),
+        ] {
+            let header = read_header(StorageMode::VariableWidth, header_ctrl).unwrap();
+            assert_eq!(header.record_type_bits, record_type_bits);
+            assert_eq!(header.record_length_bytes, record_length_bytes);
+        }
+        // bits 4-5 are the width of the allele counts
+        for allele_count_bytes in 0..4 {
+            let header_ctrl = (allele_count_bytes as u8) << 4;
+            let header = read_header(StorageMode::VariableWidthWithPgi, header_ctrl).unwrap();
+            assert_eq!(header.allele_count_bytes, allele_count_bytes);
+        }
+        // bits 6-7 say how the nonref flags are stored, for either kind of
+        // storage
+        let nonref_flags = [
+            NonrefFlagsStorage::NotStored,
+            NonrefFlagsStorage::AllUnset,
+            NonrefFlagsStorage::AllSet,
+            NonrefFlagsStorage::Explicit,
+        ];
+        for (mode, expected) in nonref_flags.into_iter().enumerate() {
+            for storage_mode in [StorageMode::FixedWidth, StorageMode::VariableWidth] {
+                let header = read_header(storage_mode, (mode as u8) << 6).unwrap();
+                assert_eq!(header.nonref_flags, expected);
+                assert_eq!((header.num_variants, header.num_samples), (3, 2));
+            }
+        }
+        let header = read_header(StorageMode::FixedWidth, 0xC0).unwrap();
+        assert_eq!(
+            (
+                header.record_type_bits,
+                header.record_length_bytes,
+                header.allele_count_bytes
+            ),
+            (0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn header_errors() {
+        assert!(matches!(
+            StorageMode::from_byte(0x01),
+            Err(PgenError::UnsupportedStorageMode(0x01))
+        ));
+        // record type/length storage of 8 and up
+        assert!(matches!(
+            read_header(StorageMode::VariableWidth, 0x08),
+            Err(PgenError::InvalidHeader(_))
+        ));
+        // fixed-width pgens have no record types, lengths or allele counts
+        for header_ctrl in [0x01, 0x04, 0x10] {
+            assert!(matches!(
+                read_header(StorageMode::FixedWidth, header_ctrl),
+                Err(PgenError::InvalidHeader(_))
+            ));
+        }
+        let bytes = [3, 0, 0, 0, 2, 0, 0, 0];
+        assert!(PgenHeader::read(StorageMode::VariableWidth, &mut &bytes[..]).is_err());
+    }
+
+    #[test]
+    fn id_lists() {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-id-lists-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // 4 variants of 4 hom ref samples, in 2 families
+        let prefix = dir.join("lists").to_string_lossy().into_owned();
+        let mut pgen = vec![0x6C, 0x1B, 0x02, 4, 0, 0, 0, 4, 0, 0, 0, 0x00];
+        pgen.extend_from_slice(&[0; 4]);
+        fs::write(format!("{}.pgen", prefix), pgen).unwrap();
+        fs::write(
+            format!("{}.psam", prefix),
+            "#FID\tIID\nF1\tS1\nF1\tS2\nF2\tS3\nF2\tS4\n",
+        )
+        .unwrap();
+        fs::write(
+            format!("{}.pvar", prefix),
+            "#CHROM\tPOS\tID\tREF\tALT\n1\t10\trs1\tA\tG\n1\t20\trs2\tA\tG\n\
+             1\t30\t.\tA\tG\n2\t10\trs4\tA\tG\n",
+        )
+        .unwrap();
+        let pfile = Pfile::from_prefix(prefix).unwrap();
+        let list = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let sample_list = |name, contents| SampleList::from_file(&list(name, contents)).unwrap();
+        let variant_list = |name, contents| VariantList::from_file(&list(name, contents)).unwrap();
+
+        let samples = |keep, remove, query: Option<&str>| {
+            let sam_filter = SampleFilter {
+                query: query.map(str::to_string),
+                keep,
+                remove,
+            };
+            let samples = pfile.filter_samples(sam_filter).unwrap();
+            samples
+                .into_iter()
+                .map(|(sam_idx, _)| sam_idx)
+                .collect::<Vec<_>>()
+        };
+        let iids = sample_list("iids.txt", "S1\nS3\nS4\n");
+        let fids = sample_list("fids.txt", "F2 S1\nF2 S3\n");
+        let remove = sample_list("remove.txt", "#IID\nS2\nS4\n");
+        assert_eq!(samples(Some(iids.clone()), None, None), [0, 2, 3]);
+        assert_eq!(samples(Some(fids), None, None), [2]);
+        assert_eq!(samples(None, Some(remove.clone()), None), [0, 2]);
+        assert_eq!(
+            samples(Some(iids.clone()), Some(remove.clone()), None),
+            [0, 2]
+        );
+        // the lists and the query both have to keep a sample
+        let query = Some("IID != \"S1\"");
+        assert_eq!(samples(Some(iids), Some(remove.clone()), query), [2]);
+        assert_eq!(samples(Some(remove.clone()), Some(remove), None), []);
+
+        let variants = |extract, exclude, regions: Option<&str>| {
+            let var_filter = VariantFilter {
+                regions: regions.map(|regions| Regions::parse(regions).unwrap()),
+                extract,
+                exclude,
+                ..Default::default()
+            };
+            let mut pvar_reader = pfile.pvar_reader().unwrap();
+            let variants = pfile.filter_variants(&mut pvar_reader, var_filter).unwrap();
+            variants
+                .into_iter()
+                .map(|(var_idx, _)| var_idx)
+                .collect::<Vec<_>>()
+        };
+        let extract = variant_list("extract.txt", "rs1 rs2\nrs4 rs5\n");
+        let exclude = variant_list("exclude.txt", "rs2\n.\n");
+        assert_eq!(variants(Some(extract.clone()), None, None), [0, 1, 3]);
+        assert_eq!(variants(None, Some(exclude.clone()), None), [0, 3]);
+        assert_eq!(variants(Some(extract.clone()), Some(exclude), None), [0, 3]);
+        assert_eq!(variants(Some(extract), None, Some("1")), [0, 1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn info_typing() {
+        let meta = "##INFO=<ID=DB,Number=0,Type=Flag,Description=\"dbSNP, build=129\">\n\
+                    ##INFO=<ID=DP,Number=1,Type=Integer,Description=\"Total Depth\">\n\
+                    ##INFO=<ID=AF,Number=A,Type=Float,Description=\"Frequency, AF=AC/AN\">\n\
+                    ##INFO=<ID=NOTE,Number=.,Type=String,Description=\"Notes\">\n\
+                    ##INFO=<ID=H2,Number=0,Type=Flag,Description=\"HapMap2\">\n";
+        let info_types = PvarParser::info_types(meta);
+        let keys = ["DP", "AF", "DB", "NOTE", "H2", "UNDECLARED", "ABSENT"]
+            .map(|key| (key.to_string(), info_types.get(key).cloned()));
+        let str = |item: &str| Value::Str(item.to_string());
+
+        // the first occurrence of a key wins
+        let info = "DP=14;AF=0.5;DB;UNDECLARED=x,.;NOTE=a;DP=99";
+        assert_eq!(
+            info_values(info, &keys),
+            [
+                Value::Int(14),
+                Value::List(vec![Value::Float(0.5)]),
+                Value::Bool(true),
+                Value::List(vec![str("a")]),
+                Value::Bool(false),
+                Value::List(vec![str("x"), Value::Missing]),
+                Value::Missing,
+            ]
+        );
+        assert_eq!(
+            info_values(".", &keys),
+            [
+                Value::Missing,
+                Value::Missing,
+                Value::Bool(false),
+                Value::Missing,
+                Value::Bool(false),
+                Value::Missing,
+                Value::Missing,
+            ]
+        );
+
+        let info_value = |field, key: &str| info_value(field, info_types.get(key));
+        assert_eq!(
+            info_value(Some("0.25,.,1e-3"), "AF"),
+            Value::List(vec![Value::Float(0.25), Value::Missing, Value::Float(1e-3)])
+        );
+        // declaring one value doesn't stop a key from having several
+        assert_eq!(
+            info_value(Some("1,2"), "DP"),
+            Value::List(vec![Value::Int(1), Value::Int(2)])
+        );
+        assert_eq!(info_value(Some("."), "DP"), Value::Missing);
+        // values that aren't of the declared type are kept as strings
+        assert_eq!(info_value(Some("deep"), "DP"), str("deep"));
+        assert_eq!(info_value(Some("1"), "DB"), Value::Bool(true));
+        assert_eq!(info_value(None, "UNDECLARED"), Value::Bool(true));
+        assert_eq!(info_value(Some("1"), "UNDECLARED"), str("1"));
+    }
+
+    #[test]
+    fn pgen_from_bim() {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-from-bim-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // a variant without ALT, and one the filter drops
+        let bims = [
+            (
+                "1 rs1 0 10 G A\n1 rs2 0 20 0 C\n2\trs3\t0\t5\tT\tC\n",
+                "#CHROM\tPOS\tID\tREF\tALT\n1\t10\trs1\tA\tG\n1\t20\trs2\tC\t.\n",
+            ),
+            (
+                "1 rs1 10 G A\n1 rs2 20 0 C\n2 rs3 5 T C\n",
+                "#CHROM\tPOS\tID\tREF\tALT\n1\t10\trs1\tA\tG\n1\t20\trs2\tC\t.\n",
+            ),
+            (
+                "1 rs1 0 10 G A\n1 rs2 0.5 20 0 C\n2 rs3 0 5 T C\n",
+                "#CHROM\tPOS\tID\tREF\tALT\tCM\n1\t10\trs1\tA\tG\t0\n1\t20\trs2\tC\t.\t0.5\n",
+            ),
+        ];
+        for (bim_idx, (bim, pvar)) in bims.into_iter().enumerate() {
+            let prefix = dir
+                .join(format!("in{}", bim_idx))
+                .to_string_lossy()
+                .into_owned();
+            let out_prefix = dir
+                .join(format!("out{}", bim_idx))
+                .to_string_lossy()
+                .into_owned();
+            let mut pgen = vec![0x6C, 0x1B, 0x02, 3, 0, 0, 0, 2, 0, 0, 0, 0x00];
+            pgen.extend_from_slice(&[0b01_00, 0b10_01, 0b11_10]);
+            fs::write(format!("{}.pgen", prefix), pgen).unwrap();
+            fs::write(format!("{}.bim", prefix), bim).unwrap();
+            fs::write(
+                format!("{}.fam", prefix),
+                "F1 S1 0 0 1 -9\nF1 S2 0 0 2 -9\n",
+            )
+            .unwrap();
+
+            let pfile = Pfile::from_prefix(prefix).unwrap();
+            let var_filter = VariantFilter {
+                query: Some(r#"CHROM == "1""#.to_string()),
+                ..Default::default()
+            };
+            pfile
+                .output_pgen(SampleFilter::default(), var_filter, &out_prefix)
+                .unwrap();
+            let read = |extension: &str| {
+                fs::read_to_string(format!("{}.{}", out_prefix, extension)).unwrap()
+            };
+            assert_eq!(read("pvar"), pvar);
+            assert_eq!(
+                read("psam"),
+                "#FID\tIID\tPAT\tMAT\tSEX\tPHENO1\nF1\tS1\t0\t0\t1\t-9\nF1\tS2\t0\t0\t2\t-9\n"
+            );
+            let out = Pfile::from_prefix(out_prefix).unwrap();
+            assert!(out.pvar.synthesized_columns.is_none());
+            assert_eq!(out.num_variants, 2);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,984 @@
+//! Helpers for decoding the variable-width records of a .pgen.
+//!
+//! Genotypes are handled as "genovecs": 2 bits per sample, 4 samples per byte
+//! with the first sample in the lowest bits, exactly like the records of the
+//! fixed-width storage mode. The codes are
+//!
+//! ```text
+//! 0b00  hom ref
+//! 0b01  het
+//! 0b10  hom alt
+//! 0b11  missing
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::pfile::PgenError;
+
+/// Number of entries in each group of a difflist.
+const DIFFLIST_GROUP_SIZE: usize = 64;
+
+/// The decoded genotypes of a single variant.
+///
+/// Samples are referred to by their index in the .psam; the accessors panic
+/// if it is past the last sample.
+pub struct Genotypes {
+    /// The hardcalls of every sample, as a genovec.
+    pub(crate) hardcalls: Vec<u8>,
+    /// The actual alleles of the samples whose hardcall is a placeholder for
+    /// a genotype involving an ALT allele other than the first, i.e. whose
+    /// het (0/1) is really 0/x or whose hom alt (1/1) is really x/y.
+    pub(crate) multiallelic_patches: HashMap<usize, (u32, u32)>,
+    /// Whether the record carries phase information.
+    pub(crate) phased: bool,
+    /// One bit per sample, set for heterozygous calls whose phase is known.
+    pub(crate) phase_present: Vec<u8>,
+    /// One bit per sample, meaningful where `phase_present` is set: set
+    /// means the ALT allele is on the first haplotype (1|0), unset means it
+    /// is on the second (0|1).
+    pub(crate) phase_info: Vec<u8>,
+    /// One bit per sample, set for samples with a dosage. Empty if the
+    /// record has no dosages.
+    pub(crate) dosage_present: Vec<u8>,
+    /// The ALT dosage of each sample in units of 1/16384, meaningful where
+    /// `dosage_present` is set.
+    pub(crate) dosages: Vec<u16>,
+    /// One bit per sample, set for samples with a phased dosage. Empty if
+    /// the record has no phased dosages.
+    pub(crate) dphase_present: Vec<u8>,
+    /// The ALT dosage of the first haplotype minus that of the second, in
+    /// units of 1/16384, meaningful where `dphase_present` is set.
+    pub(crate) dphase_deltas: Vec<i16>,
+}
+
+/// A dosage of 1 in the units dosages are stored in.
+const DOSAGE_ONE: u32 = 1 << 14;
+
+/// Stored in place of a dosage to mark it as missing.
+const MISSING_DOSAGE: u16 = 0xFFFF;
+
+/// Reads the parts of a single variant record in order.
+pub struct RecordReader<'a> {
+    record: &'a [u8],
+    pos: usize,
+    /// The variant the record belongs to, for error messages.
+    var_idx: usize,
+}
+
+/// A sparse list of samples, optionally carrying a genotype code for each.
+pub struct Difflist {
+    pub sample_ids: Vec<u32>,
+    /// One code per sample id, empty if the list carries no genotypes.
+    pub genotypes: Vec<u8>,
+}
+
+impl<'a> RecordReader<'a> {
+    pub fn new(record: &'a [u8], var_idx: usize) -> RecordReader<'a> {
+        RecordReader {
+            record,
+            pos: 0,
+            var_idx,
+        }
+    }
+
+    /// An error for a record whose contents don't make sense.
+    pub fn invalid(&self, message: String) -> PgenError {
+        PgenError::InvalidRecord {
+            var_idx: self.var_idx,
+            message,
+        }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PgenError> {
+        let bytes = self
+            .record
+            .get(self.pos..self.pos + len)
+            .ok_or(PgenError::TruncatedRecord {
+                var_idx: self.var_idx,
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, PgenError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Reads a little-endian unsigned integer of `len` bytes.
+    pub fn read_le_uint(&mut self, len: usize) -> Result<u64, PgenError> {
+        Ok(read_le_uint(self.read_bytes(len)?))
+    }
+
+    /// Reads a variable-length integer: 7 bits per byte, least significant
+    /// group first, with the high bit set on every byte but the last.
+    pub fn read_vint(&mut self) -> Result<u32, PgenError> {
+        let mut value = 0u32;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7F) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift > 28 {
+                return Err(self.invalid("variable-length integer overflows".to_string()));
+            }
+        }
+    }
+
+    /// Reads a difflist.
+    ///
+    /// A difflist is laid out as
+    ///
+    /// ```text
+    /// length              (vint)
+    /// group starts        (first sample id of every group of 64 entries)
+    /// group sizes         (1 byte per group but the last, for skipping)
+    /// genotypes           (2 bits per entry, if `with_genotypes`)
+    /// sample id deltas    (vint per entry, except each group's first)
+    /// ```
+    pub fn read_difflist(
+        &mut self,
+        num_samples: u32,
+        with_genotypes: bool,
+    ) -> Result<Difflist, PgenError> {
+        let len = self.read_vint()? as usize;
+        if len == 0 {
+            return Ok(Difflist {
+                sample_ids: Vec::new(),
+                genotypes: Vec::new(),
+            });
+        }
+        if len > num_samples as usize {
+            return Err(self.invalid(format!("difflist of {} entries is too long", len)));
+        }
+        let num_groups = len.div_ceil(DIFFLIST_GROUP_SIZE);
+        let sample_id_bytes = bytes_to_represent(num_samples);
+        let group_starts = (0..num_groups)
+            .map(|_| self.read_le_uint(sample_id_bytes).map(|id| id as u32))
+            .collect::<Result<Vec<u32>, PgenError>>()?;
+        // the group sizes are only needed for random access into the deltas
+        self.read_bytes(num_groups - 1)?;
+        let genotypes = if with_genotypes {
+            let packed = self.read_bytes(len.div_ceil(4))?;
+            (0..len).map(|i| genovec_get(packed, i)).collect()
+        } else {
+            Vec::new()
+        };
+        let mut sample_ids = Vec::with_capacity(len);
+        for (group_idx, group_start) in group_starts.into_iter().enumerate() {
+            let group_len = DIFFLIST_GROUP_SIZE.min(len - group_idx * DIFFLIST_GROUP_SIZE);
+            let mut sample_id = group_start;
+            sample_ids.push(sample_id);
+            for _ in 1..group_len {
+                sample_id = sample_id.saturating_add(self.read_vint()?);
+                sample_ids.push(sample_id);
+            }
+        }
+        if sample_ids.last().is_some_and(|&sample_id| sample_id >= num_samples) {
+            return Err(self.invalid("difflist refers to a sample past the last".to_string()));
+        }
+        Ok(Difflist {
+            sample_ids,
+            genotypes,
+        })
+    }
+}
+
+impl Genotypes {
+    pub(crate) fn unphased(hardcalls: Vec<u8>) -> Genotypes {
+        Genotypes {
+            hardcalls,
+            multiallelic_patches: HashMap::new(),
+            phased: false,
+            phase_present: Vec::new(),
+            phase_info: Vec::new(),
+            dosage_present: Vec::new(),
+            dosages: Vec::new(),
+            dphase_present: Vec::new(),
+            dphase_deltas: Vec::new(),
+        }
+    }
+
+    /// Reads the multiallelic track of a record, which directly follows the
+    /// hardcalls.
+    ///
+    /// It starts with a byte whose low nibble describes the patches to hets
+    /// and whose high nibble describes the patches to hom alts: 15 means there
+    /// are none, 0 means a bitarray with one bit per het (or hom alt) marks
+    /// the patched samples, and 1 means a difflist without genotypes lists
+    /// them. Each kind of patch is followed by its allele codes, see
+    /// `read_allele_codes`.
+    pub(crate) fn read_multiallelic(
+        &mut self,
+        reader: &mut RecordReader,
+        num_samples: u32,
+        allele_count: u32,
+    ) -> Result<(), PgenError> {
+        if allele_count < 3 {
+            return Err(reader.invalid("biallelic variant has a multiallelic track".to_string()));
+        }
+        let format = reader.read_u8()?;
+        let het_samples = self.patched_samples(reader, num_samples, 0b01, format & 0x0F)?;
+        // a patched het 0/x has x in [2, allele_count)
+        let codes = read_allele_codes(reader, het_samples.len(), allele_count - 2)?;
+        for (sam_idx, code) in std::iter::zip(het_samples, codes) {
+            self.multiallelic_patches.insert(sam_idx, (0, code + 2));
+        }
+
+        let hom_samples = self.patched_samples(reader, num_samples, 0b10, format >> 4)?;
+        if allele_count == 3 {
+            // the only possibilities are 1/2 and 2/2
+            let codes = read_allele_codes(reader, hom_samples.len(), 2)?;
+            for (sam_idx, code) in std::iter::zip(hom_samples, codes) {
+                self.multiallelic_patches.insert(sam_idx, (code + 1, 2));
+            }
+        } else {
+            // a patched hom alt x/y has x <= y in [1, allele_count), stored as
+            // two codes per sample
+            let codes = read_allele_codes(reader, 2 * hom_samples.len(), allele_count - 1)?;
+            for (sam_idx, pair) in std::iter::zip(hom_samples, codes.chunks_exact(2)) {
+                self.multiallelic_patches.insert(sam_idx, (pair[0] + 1, pair[1] + 1));
+            }
+        }
+
+        let max_allele = self
+            .multiallelic_patches
+            .values()
+            .map(|&(first, second)| first.max(second))
+            .max();
+        if max_allele.is_some_and(|max_allele| max_allele >= allele_count) {
+            return Err(reader.invalid(format!(
+                "multiallelic patch refers to an allele past the last of {}",
+                allele_count
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads which samples with the given hardcall have a multiallelic patch.
+    fn patched_samples(
+        &self,
+        reader: &mut RecordReader,
+        num_samples: u32,
+        code: u8,
+        format: u8,
+    ) -> Result<Vec<usize>, PgenError> {
+        match format {
+            15 => Ok(Vec::new()),
+            0 => {
+                let candidates = (0..num_samples as usize)
+                    .filter(|&sam_idx| genovec_get(&self.hardcalls, sam_idx) == code)
+                    .collect::<Vec<usize>>();
+                let bits = reader.read_bytes(candidates.len().div_ceil(8))?;
+                Ok(candidates
+                    .into_iter()
+                    .enumerate()
+                    .filter(|&(candidate_idx, _)| get_bit(bits, candidate_idx))
+                    .map(|(_, sam_idx)| sam_idx)
+                    .collect())
+            }
+            1 => Ok(reader
+                .read_difflist(num_samples, false)?
+                .sample_ids
+                .into_iter()
+                .map(|sam_idx| sam_idx as usize)
+                .collect()),
+            other => Err(reader.invalid(format!(
+                "unsupported multiallelic patch format {}",
+                other
+            ))),
+        }
+    }
+
+    /// The alleles of a sample's hardcall, or None if it is missing.
+    pub fn alleles(&self, sam_idx: usize) -> Option<(u32, u32)> {
+        match genovec_get(&self.hardcalls, sam_idx) {
+            0b11 => None,
+            code => Some(
+                self.multiallelic_patches
+                    .get(&sam_idx)
+                    .copied()
+                    .unwrap_or(match code {
+                        0b00 => (0, 0),
+                        0b01 => (0, 1),
+                        _ => (1, 1),
+                    }),
+            ),
+        }
+    }
+
+    /// Reads the phase track of a record, which comes after the hardcalls
+    /// (and after the multiallelic track, if any).
+    ///
+    /// It starts with a bitarray with one bit more than there are hets. If
+    /// the first bit is unset, every het is phased and the remaining bits
+    /// are the phase of each het. Otherwise the remaining bits say which hets
+    /// are phased, and a second bitarray with the phase of each of those
+    /// follows.
+    pub(crate) fn read_phase(
+        &mut self,
+        reader: &mut RecordReader,
+        num_samples: u32,
+    ) -> Result<(), PgenError> {
+        let hets = (0..num_samples as usize)
+            .filter(|&sam_idx| {
+                self.alleles(sam_idx)
+                    .is_some_and(|(first, second)| first != second)
+            })
+            .collect::<Vec<usize>>();
+        let het_bits = reader.read_bytes((hets.len() + 1).div_ceil(8))?;
+        let explicit_phase_present = get_bit(het_bits, 0);
+        let bitarray_len = (num_samples as usize).div_ceil(8);
+        self.phased = true;
+        self.phase_present = vec![0u8; bitarray_len];
+        self.phase_info = vec![0u8; bitarray_len];
+        if !explicit_phase_present {
+            for (het_idx, &sam_idx) in hets.iter().enumerate() {
+                set_bit(&mut self.phase_present, sam_idx);
+                if get_bit(het_bits, het_idx + 1) {
+                    set_bit(&mut self.phase_info, sam_idx);
+                }
+            }
+            return Ok(());
+        }
+        let phased_hets = hets
+            .iter()
+            .enumerate()
+            .filter(|&(het_idx, _)| get_bit(het_bits, het_idx + 1))
+            .map(|(_, &sam_idx)| sam_idx)
+            .collect::<Vec<usize>>();
+        let info_bits = reader.read_bytes(phased_hets.len().div_ceil(8))?;
+        for (phased_idx, &sam_idx) in phased_hets.iter().enumerate() {
+            set_bit(&mut self.phase_present, sam_idx);
+            if get_bit(info_bits, phased_idx) {
+                set_bit(&mut self.phase_info, sam_idx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the dosage track of a record, which comes after the phase
+    /// track. `dosage_mode` is bits 5-6 of the record type:
+    ///
+    /// ```text
+    /// 1  a difflist of the samples with a dosage (without genotypes),
+    ///    then a 16-bit dosage for each of them
+    /// 2  a 16-bit dosage for every sample, 0xFFFF meaning missing
+    /// 3  a bitarray of the samples with a dosage, then a 16-bit dosage
+    ///    for each of them
+    /// ```
+    pub(crate) fn read_dosages(
+        &mut self,
+        reader: &mut RecordReader,
+        num_samples: u32,
+        dosage_mode: u8,
+    ) -> Result<(), PgenError> {
+        let samples_with_dosage = match dosage_mode {
+            1 => reader
+                .read_difflist(num_samples, false)?
+                .sample_ids
+                .into_iter()
+                .map(|sam_idx| sam_idx as usize)
+                .collect::<Vec<usize>>(),
+            2 => (0..num_samples as usize).collect(),
+            3 => {
+                let bits = reader.read_bytes((num_samples as usize).div_ceil(8))?;
+                (0..num_samples as usize)
+                    .filter(|&sam_idx| get_bit(bits, sam_idx))
+                    .collect()
+            }
+            other => return Err(reader.invalid(format!("unsupported dosage mode {}", other))),
+        };
+        self.dosage_present = vec![0u8; (num_samples as usize).div_ceil(8)];
+        self.dosages = vec![0u16; num_samples as usize];
+        for sam_idx in samples_with_dosage {
+            let dosage = reader.read_le_uint(2)? as u16;
+            if dosage != MISSING_DOSAGE {
+                set_bit(&mut self.dosage_present, sam_idx);
+                self.dosages[sam_idx] = dosage;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the phased dosage track of a record, which comes last. It has
+    /// one bit for each sample with a dosage, set if the dosage is phased,
+    /// followed by a signed 16-bit delta for each phased dosage.
+    pub(crate) fn read_dphase(
+        &mut self,
+        reader: &mut RecordReader,
+        num_samples: u32,
+    ) -> Result<(), PgenError> {
+        let samples_with_dosage = (0..num_samples as usize)
+            .filter(|&sam_idx| get_bit(&self.dosage_present, sam_idx))
+            .collect::<Vec<usize>>();
+        let bits = reader.read_bytes(samples_with_dosage.len().div_ceil(8))?;
+        self.dphase_present = vec![0u8; (num_samples as usize).div_ceil(8)];
+        self.dphase_deltas = vec![0i16; num_samples as usize];
+        for (dosage_idx, &sam_idx) in samples_with_dosage.iter().enumerate() {
+            if get_bit(bits, dosage_idx) {
+                set_bit(&mut self.dphase_present, sam_idx);
+            }
+        }
+        for (sam_idx, delta) in self.dphase_deltas.iter_mut().enumerate() {
+            if get_bit(&self.dphase_present, sam_idx) {
+                *delta = reader.read_le_uint(2)? as u16 as i16;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a sample's hardcall is phased.
+    ///
+    /// Hets are only phased when their phase is known, but every homozygous
+    /// call of a phased record counts as phased.
+    pub fn is_phased(&self, sam_idx: usize) -> bool {
+        match self.alleles(sam_idx) {
+            Some((first, second)) if first == second => self.phased,
+            Some(_) => self.phased && get_bit(&self.phase_present, sam_idx),
+            None => false,
+        }
+    }
+
+    /// Like `alleles`, but in haplotype order when the call is phased.
+    pub fn phased_alleles(&self, sam_idx: usize) -> Option<(u32, u32)> {
+        let (first, second) = self.alleles(sam_idx)?;
+        if self.is_phased(sam_idx) && get_bit_or_unset(&self.phase_info, sam_idx) {
+            Some((second, first))
+        } else {
+            Some((first, second))
+        }
+    }
+
+    /// The dosage of the first ALT allele of a sample, falling back to its
+    /// hardcall when it has no dosage. None if both are missing.
+    pub fn dosage(&self, sam_idx: usize) -> Option<f64> {
+        self.dosage_units(sam_idx)
+            .map(|dosage| dosage as f64 / DOSAGE_ONE as f64)
+    }
+
+    /// The dosage of the first ALT allele on each haplotype of a sample, see
+    /// `vcf_haplotype_dosages`.
+    pub fn haplotype_dosages(&self, sam_idx: usize) -> Option<(f64, f64)> {
+        self.haplotype_dosage_units(sam_idx).map(|(first, second)| {
+            (
+                first as f64 / DOSAGE_ONE as f64,
+                second as f64 / DOSAGE_ONE as f64,
+            )
+        })
+    }
+
+    /// Like `dosage`, but in the units of 1/16384 it is stored in.
+    fn dosage_units(&self, sam_idx: usize) -> Option<u32> {
+        if get_bit_or_unset(&self.dosage_present, sam_idx) {
+            return Some(self.dosages[sam_idx] as u32);
+        }
+        self.alleles(sam_idx).map(|(first, second)| {
+            ((first == 1) as u32 + (second == 1) as u32) * DOSAGE_ONE
+        })
+    }
+
+    /// The VCF DS field of a sample.
+    pub fn vcf_dosage(&self, sam_idx: usize) -> String {
+        self.dosage_units(sam_idx)
+            .map_or_else(|| ".".to_string(), format_dosage)
+    }
+
+    /// The VCF HDS field of a sample: the ALT dosage of each haplotype.
+    ///
+    /// Samples without a phased dosage split their dosage according to the
+    /// phase of their hardcall if it is phased with the first ALT allele on
+    /// one haplotype only, and evenly otherwise.
+    pub fn vcf_haplotype_dosages(&self, sam_idx: usize) -> String {
+        match self.haplotype_dosage_units(sam_idx) {
+            Some((first, second)) => format!("{},{}", format_dosage(first), format_dosage(second)),
+            None => ".,.".to_string(),
+        }
+    }
+
+    fn haplotype_dosage_units(&self, sam_idx: usize) -> Option<(u32, u32)> {
+        let dosage = self.dosage_units(sam_idx)?;
+        let haplotype_dosages = if get_bit_or_unset(&self.dphase_present, sam_idx) {
+            // keep each haplotype within [0, 1] even if the delta is off
+            let delta = self.dphase_deltas[sam_idx] as i64;
+            let first = ((dosage as i64 + delta) / 2).clamp(0, DOSAGE_ONE as i64) as u32;
+            (first, dosage.saturating_sub(first).min(DOSAGE_ONE))
+        } else {
+            // the first ALT allele's dosage goes with the haplotype carrying it
+            let alleles = self
+                .phased_alleles(sam_idx)
+                .filter(|_| self.is_phased(sam_idx));
+            match alleles {
+                Some((1, second)) if second != 1 => {
+                    (dosage.min(DOSAGE_ONE), dosage.saturating_sub(DOSAGE_ONE))
+                }
+                Some((first, 1)) if first != 1 => {
+                    (dosage.saturating_sub(DOSAGE_ONE), dosage.min(DOSAGE_ONE))
+                }
+                _ => (dosage / 2, dosage - dosage / 2),
+            }
+        };
+        Some(haplotype_dosages)
+    }
+
+    /// The VCF GT field of a sample, phased according to `is_phased`.
+    pub fn vcf_genotype(&self, sam_idx: usize) -> Cow<'static, str> {
+        let Some((first, second)) = self.phased_alleles(sam_idx) else {
+            return Cow::Borrowed("./.");
+        };
+        let phased = self.is_phased(sam_idx);
+        // avoid allocating for the biallelic genotypes
+        match (first, second, phased) {
+            (0, 0, false) => Cow::Borrowed("0/0"),
+            (0, 0, true) => Cow::Borrowed("0|0"),
+            (0, 1, false) => Cow::Borrowed("0/1"),
+            (0, 1, true) => Cow::Borrowed("0|1"),
+            (1, 0, true) => Cow::Borrowed("1|0"),
+            (1, 1, false) => Cow::Borrowed("1/1"),
+            (1, 1, true) => Cow::Borrowed("1|1"),
+            _ => Cow::Owned(format!(
+                "{}{}{}",
+                first,
+                if phased { '|' } else { '/' },
+                second
+            )),
+        }
+    }
+}
+
+/// Reads `len` allele codes able to hold `num_values` different values,
+/// packed with the smallest width among 0 (when there is only one value), 1,
+/// 2, 4 and 8 bits.
+fn read_allele_codes(
+    reader: &mut RecordReader,
+    len: usize,
+    num_values: u32,
+) -> Result<Vec<u32>, PgenError> {
+    let width = allele_code_width(num_values);
+    if width == 0 {
+        return Ok(vec![0; len]);
+    }
+    let codes = reader.read_bytes((len * width).div_ceil(8))?;
+    Ok((0..len)
+        .map(|idx| {
+            let bit_idx = idx * width;
+            ((codes[bit_idx / 8] >> (bit_idx % 8)) as u32) & ((1 << width) - 1)
+        })
+        .collect())
+}
+
+/// Width in bits of allele codes able to hold `num_values` different values.
+pub fn allele_code_width(num_values: u32) -> usize {
+    match num_values {
+        0 | 1 => 0,
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+/// Like `get_bit`, but treats an empty bitarray as all unset.
+fn get_bit_or_unset(bits: &[u8], idx: usize) -> bool {
+    !bits.is_empty() && get_bit(bits, idx)
+}
+
+/// Formats a dosage stored in units of 1/16384 with up to 3 decimals.
+fn format_dosage(dosage: u32) -> String {
+    let formatted = format!("{:.3}", dosage as f64 / DOSAGE_ONE as f64);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Reads a little-endian unsigned integer of up to 8 bytes.
+pub fn read_le_uint(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+/// Number of bytes needed to represent `value`.
+pub fn bytes_to_represent(value: u32) -> usize {
+    (4 - value.leading_zeros() as usize / 8).max(1)
+}
+
+pub fn get_bit(bits: &[u8], idx: usize) -> bool {
+    bits[idx / 8] & (1 << (idx % 8)) != 0
+}
+
+pub fn set_bit(bits: &mut [u8], idx: usize) {
+    bits[idx / 8] |= 1 << (idx % 8);
+}
+
+/// The genotype code of a sample in a genovec.
+pub fn genovec_get(genovec: &[u8], sam_idx: usize) -> u8 {
+    (genovec[sam_idx / 4] >> (2 * (sam_idx % 4))) & 0b11
+}
+
+pub fn genovec_set(genovec: &mut [u8], sam_idx: usize, code: u8) {
+    let shift = 2 * (sam_idx % 4);
+    let byte = &mut genovec[sam_idx / 4];
+    *byte = (*byte & !(0b11 << shift)) | (code << shift);
+}
+
+/// A genovec where every sample has the same genotype code.
+pub fn genovec_fill(num_samples: u32, code: u8) -> Vec<u8> {
+    let byte = code * 0b0101_0101;
+    let mut genovec = vec![byte; (num_samples as usize).div_ceil(4)];
+    clear_genovec_padding(&mut genovec, num_samples);
+    genovec
+}
+
+/// Zeroes the unused codes at the end of the last byte of a genovec.
+pub fn clear_genovec_padding(genovec: &mut [u8], num_samples: u32) {
+    let used_codes = num_samples as usize % 4;
+    if used_codes != 0 {
+        if let Some(last) = genovec.last_mut() {
+            *last &= (1 << (2 * used_codes)) - 1;
+        }
+    }
+}
+
+/// Overwrites the genotypes of the samples in the difflist.
+pub fn apply_difflist(genovec: &mut [u8], difflist: &Difflist) {
+    for (&sam_idx, &code) in std::iter::zip(&difflist.sample_ids, &difflist.genotypes) {
+        genovec_set(genovec, sam_idx as usize, code);
+    }
+}
+
+/// Swaps hom ref and hom alt, as if REF and ALT were swapped.
+pub fn invert_genovec(genovec: &mut [u8], num_samples: u32) {
+    for byte in genovec.iter_mut() {
+        // a code is 0b00 or 0b10 exactly when its low bit is unset; flip
+        // the high bit of those
+        let low_bits = *byte & 0b0101_0101;
+        *byte ^= (!low_bits & 0b0101_0101) << 1;
+    }
+    clear_genovec_padding(genovec, num_samples);
+}
+
+/// Reads the hardcalls of a record whose type is not LD-compressed.
+///
+/// Record types (bits 0-2) are
+///
+/// ```text
+/// 0  2-bit array, like the fixed-width records
+/// 1  1 bit per sample choosing between two common genotypes, followed
+///    by a difflist of the exceptions
+/// 4  difflist against all samples being hom ref
+/// 6  difflist against all samples being hom alt
+/// 7  difflist against all samples being missing
+/// ```
+///
+/// (5 is reserved.)
+pub fn read_hardcalls(
+    reader: &mut RecordReader,
+    record_type: u8,
+    num_samples: u32,
+) -> Result<Vec<u8>, PgenError> {
+    let genovec_len = (num_samples as usize).div_ceil(4);
+    match record_type & 0b111 {
+        0 => Ok(reader.read_bytes(genovec_len)?.to_vec()),
+        1 => {
+            // the genotype of an unset bit is code / 4, and that of a set bit
+            // is code / 4 + code % 4
+            let common_code = reader.read_u8()?;
+            let unset_code = common_code / 4;
+            let set_code = unset_code + common_code % 4;
+            if set_code > 3 {
+                return Err(reader.invalid(format!(
+                    "invalid 1-bit genotype pair {}",
+                    common_code
+                )));
+            }
+            let bits = reader.read_bytes((num_samples as usize).div_ceil(8))?;
+            let mut genovec = vec![0u8; genovec_len];
+            for sam_idx in 0..num_samples as usize {
+                let bit = (bits[sam_idx / 8] >> (sam_idx % 8)) & 1;
+                genovec_set(&mut genovec, sam_idx, if bit == 0 { unset_code } else { set_code });
+            }
+            let difflist = reader.read_difflist(num_samples, true)?;
+            apply_difflist(&mut genovec, &difflist);
+            Ok(genovec)
+        }
+        4 | 6 | 7 => {
+            // the genotype code all samples but the listed ones have
+            let common_code = (record_type & 0b111) - 4;
+            let mut genovec = genovec_fill(num_samples, common_code);
+            let difflist = reader.read_difflist(num_samples, true)?;
+            apply_difflist(&mut genovec, &difflist);
+            Ok(genovec)
+        }
+        other => Err(reader.invalid(format!("unsupported record type {}", other))),
+    }
+}
+
+/// Reads the hardcalls of an LD-compressed record (types 2 and 3), which
+/// are a difflist against the genotypes of the last record that was not
+/// LD-compressed. Type 3 additionally swaps REF and ALT afterwards.
+pub fn read_ld_hardcalls(
+    reader: &mut RecordReader,
+    record_type: u8,
+    num_samples: u32,
+    ld_base: &[u8],
+) -> Result<Vec<u8>, PgenError> {
+    let mut genovec = ld_base.to_vec();
+    let difflist = reader.read_difflist(num_samples, true)?;
+    apply_difflist(&mut genovec, &difflist);
+    if record_type & 0b111 == 3 {
+        invert_genovec(&mut genovec, num_samples);
+    }
+    Ok(genovec)
+}
+
+/// Whether the hardcalls of a record type are LD-compressed.
+pub fn is_ld_compressed(record_type: u8) -> bool {
+    matches!(record_type & 0b111, 2 | 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The genotype codes of the first `num_samples` samples of a genovec.
+    fn codes(genovec: &[u8], num_samples: usize) -> Vec<u8> {
+        (0..num_samples)
+            .map(|sam_idx| genovec_get(genovec, sam_idx))
+            .collect()
+    }
+
+    /// A genovec of the given codes.
+    fn genovec(codes: &[u8]) -> Vec<u8> {
+        let mut genovec = vec![0u8; codes.len().div_ceil(4)];
+        for (sam_idx, &code) in codes.iter().enumerate() {
+            genovec_set(&mut genovec, sam_idx, code);
+        }
+        genovec
+    }
+
+    /// The GT of every sample after reading `record` as the multiallelic
+    /// track of a variant with `allele_count` alleles.
+    fn patched(hardcalls: &[u8], record: &[u8], allele_count: u32) -> Vec<String> {
+        let mut genotypes = Genotypes::unphased(genovec(hardcalls));
+        let mut reader = RecordReader::new(record, 0);
+        genotypes
+            .read_multiallelic(&mut reader, hardcalls.len() as u32, allele_count)
+            .unwrap();
+        assert_eq!(reader.pos, record.len(), "the whole track is read");
+        (0..hardcalls.len())
+            .map(|sam_idx| genotypes.vcf_genotype(sam_idx).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn difflist_record_types() {
+        // samples 1 and 3 are het and missing: a difflist of 2 entries
+        // starting at sample 1, with codes 0b01 and 0b11 and a delta of 2
+        let record = [2, 1, 0b1101, 2];
+        for (record_type, common_code) in [(4, 0b00), (6, 0b10), (7, 0b11)] {
+            let mut reader = RecordReader::new(&record, 0);
+            let genovec = read_hardcalls(&mut reader, record_type, 5).unwrap();
+            assert_eq!(
+                codes(&genovec, 5),
+                [common_code, 0b01, common_code, 0b11, common_code],
+                "record type {}",
+                record_type
+            );
+        }
+        let mut reader = RecordReader::new(&record, 0);
+        assert!(read_hardcalls(&mut reader, 5, 5).is_err());
+    }
+
+    #[test]
+    fn array_and_ld_record_types() {
+        // a 2-bit array of 0b01, 0b10, 0b11, 0b00 and 0b01
+        let mut reader = RecordReader::new(&[0b00_11_10_01, 0b01], 0);
+        let genovec = read_hardcalls(&mut reader, 0, 5).unwrap();
+        assert_eq!(codes(&genovec, 5), [0b01, 0b10, 0b11, 0b00, 0b01]);
+
+        // hom ref for unset bits and hom alt for set ones (a pair of 0 and
+        // 0 + 2), with samples 0 and 2 set, then sample 4 missing
+        let record = [0b0010, 0b00101, 1, 4, 0b11];
+        let mut reader = RecordReader::new(&record, 0);
+        let base = read_hardcalls(&mut reader, 1, 5).unwrap();
+        assert_eq!(codes(&base, 5), [0b10, 0b00, 0b10, 0b00, 0b11]);
+        // a pair of hom alt and beyond is invalid
+        let mut reader = RecordReader::new(&[0b1010, 0, 0], 0);
+        assert!(read_hardcalls(&mut reader, 1, 5).is_err());
+
+        // sample 1 becomes hom alt, and type 3 then swaps REF and ALT
+        let record = [1, 1, 0b10];
+        let mut reader = RecordReader::new(&record, 0);
+        let genovec = read_ld_hardcalls(&mut reader, 2, 5, &base).unwrap();
+        assert_eq!(codes(&genovec, 5), [0b10, 0b10, 0b10, 0b00, 0b11]);
+        let mut reader = RecordReader::new(&record, 0);
+        let genovec = read_ld_hardcalls(&mut reader, 3, 5, &base).unwrap();
+        assert_eq!(codes(&genovec, 5), [0b00, 0b00, 0b00, 0b10, 0b11]);
+    }
+
+    #[test]
+    fn haplotype_dosages_follow_phase() {
+        // 1|0, 0|1, 0/1, 1|2, 2|1 and 1|1, with hardcall dosages
+        let mut hardcalls = vec![0u8; 2];
+        for (sam_idx, code) in [0b01, 0b01, 0b01, 0b10, 0b10, 0b10].into_iter().enumerate() {
+            genovec_set(&mut hardcalls, sam_idx, code);
+        }
+        let genotypes = Genotypes {
+            hardcalls,
+            multiallelic_patches: HashMap::from([(3, (1, 2)), (4, (1, 2))]),
+            phased: true,
+            phase_present: vec![0b011011],
+            phase_info: vec![0b010001],
+            dosage_present: Vec::new(),
+            dosages: Vec::new(),
+            dphase_present: Vec::new(),
+            dphase_deltas: Vec::new(),
+        };
+        let calls = (0..6)
+            .map(|sam_idx| {
+                let gt = genotypes.vcf_genotype(sam_idx);
+                format!("{} {}", gt, genotypes.vcf_haplotype_dosages(sam_idx))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            [
+                "1|0 1,0",
+                "0|1 0,1",
+                "0/1 0.5,0.5",
+                "1|2 1,0",
+                "2|1 0,1",
+                "1|1 1,1"
+            ]
+        );
+    }
+
+    #[test]
+    fn multiallelic_patches() {
+        // two hets, two hom alts and a hom ref
+        let hardcalls = [0b01, 0b01, 0b10, 0b10, 0b00];
+
+        // 3 alleles, both patch lists as bitarrays: the second het is 0/2
+        // (with no code, 2 being the only choice), and the hom alts are
+        // 1/2 and 2/2 by 1-bit codes
+        let record = [0x00, 0b10, 0b11, 0b10];
+        assert_eq!(
+            patched(&hardcalls, &record, 3),
+            ["0/1", "0/2", "1/2", "2/2", "0/0"]
+        );
+
+        // 4 alleles, both patch lists as difflists: sample 1 is 0/3 by a
+        // 1-bit code, and samples 2 and 3 are 2/2 and 3/3 by two 2-bit codes
+        // each
+        let record = [0x11, 1, 1, 0b1, 2, 2, 1, 0b10_10_01_01];
+        assert_eq!(
+            patched(&hardcalls, &record, 4),
+            ["0/1", "0/3", "2/2", "3/3", "0/0"]
+        );
+
+        // 6 alleles: the het 0/5 has a 2-bit code, and the hom alt 2/5 two
+        // 4-bit codes
+        let record = [0x00, 0b10, 0b11, 0b01, 0x41];
+        assert_eq!(
+            patched(&hardcalls, &record, 6),
+            ["0/1", "0/5", "2/5", "1/1", "0/0"]
+        );
+
+        // 18 alleles: the het 0/17 has a 4-bit code, and the hom alt 9/17
+        // two 8-bit codes
+        let record = [0x00, 0b01, 0x0F, 0b10, 8, 16];
+        assert_eq!(
+            patched(&hardcalls, &record, 18),
+            ["0/17", "0/1", "1/1", "9/17", "0/0"]
+        );
+
+        // no het patches, and a hom alt patch to an allele past the last
+        let mut genotypes = Genotypes::unphased(genovec(&hardcalls));
+        let mut reader = RecordReader::new(&[0x0F, 0b01, 0x50], 0);
+        assert!(genotypes.read_multiallelic(&mut reader, 5, 6).is_err());
+        // a biallelic variant can't have patches
+        let mut reader = RecordReader::new(&[0xFF], 0);
+        assert!(genotypes.read_multiallelic(&mut reader, 5, 2).is_err());
+    }
+
+    #[test]
+    fn dosage_tracks() {
+        // the DS of every sample after reading `record` as the dosage track
+        let dosages = |hardcalls: &[u8], record: &[u8], dosage_mode: u8| {
+            let mut genotypes = Genotypes::unphased(genovec(hardcalls));
+            let mut reader = RecordReader::new(record, 0);
+            genotypes
+                .read_dosages(&mut reader, hardcalls.len() as u32, dosage_mode)
+                .unwrap();
+            assert_eq!(reader.pos, record.len(), "the whole track is read");
+            (0..hardcalls.len())
+                .map(|sam_idx| genotypes.vcf_dosage(sam_idx))
+                .collect::<Vec<_>>()
+        };
+        // samples without a dosage fall back to their hardcall, here a
+        // difflist of sample 2 with a dosage of 0.75
+        let record = [1, 2, 0x00, 0x30];
+        assert_eq!(dosages(&[0b00, 0b11, 0b01], &record, 1), ["0", ".", "0.75"]);
+        // a dosage for every sample: 1, 65535 for missing, and 0.5
+        let record = [0x00, 0x40, 0xFF, 0xFF, 0x00, 0x20];
+        assert_eq!(dosages(&[0b01, 0b11, 0b00], &record, 2), ["1", ".", "0.5"]);
+        // a missing dosage also falls back to the hardcall
+        assert_eq!(dosages(&[0b01, 0b10, 0b00], &record, 2), ["1", "2", "0.5"]);
+        // a bitarray of samples 0 and 2, with dosages of 0.25 and 1.5
+        let record = [0b101, 0x00, 0x10, 0x00, 0x60];
+        assert_eq!(
+            dosages(&[0b00, 0b11, 0b10], &record, 3),
+            ["0.25", ".", "1.5"]
+        );
+
+        let mut genotypes = Genotypes::unphased(genovec(&[0b00]));
+        let mut reader = RecordReader::new(&[0, 0], 0);
+        assert!(genotypes.read_dosages(&mut reader, 1, 0).is_err());
+    }
+
+    #[test]
+    fn dphase_track() {
+        // dosages of 0.25 and 1.5 for samples 0 and 2, then a bit for each
+        // of them saying only the second is phased, with a delta of -0.5
+        let record = [0b101, 0x00, 0x10, 0x00, 0x60, 0b10, 0x00, 0xE0];
+        let mut genotypes = Genotypes::unphased(genovec(&[0b00, 0b11, 0b10]));
+        let mut reader = RecordReader::new(&record, 0);
+        genotypes.read_dosages(&mut reader, 3, 3).unwrap();
+        genotypes.read_dphase(&mut reader, 3).unwrap();
+        assert_eq!(reader.pos, record.len());
+        assert_eq!(genotypes.dphase_deltas[2], -(1 << 13));
+        // the unphased dosage is split evenly, and the phased one into
+        // (1.5 - 0.5) / 2 and the rest
+        let hds = (0..3)
+            .map(|sam_idx| genotypes.vcf_haplotype_dosages(sam_idx))
+            .collect::<Vec<_>>();
+        assert_eq!(hds, ["0.125,0.125", ".,.", "0.5,1"]);
+    }
+
+    #[test]
+    fn phase_tracks() {
+        // three hets around a hom ref and a hom alt
+        let hardcalls = [0b01, 0b00, 0b01, 0b01, 0b10];
+        let phased = |record: &[u8]| {
+            let mut genotypes = Genotypes::unphased(genovec(&hardcalls));
+            let mut reader = RecordReader::new(record, 0);
+            genotypes.read_phase(&mut reader, 5).unwrap();
+            assert_eq!(reader.pos, record.len(), "the whole track is read");
+            (0..5)
+                .map(|sam_idx| genotypes.vcf_genotype(sam_idx))
+                .collect::<Vec<_>>()
+        };
+        // a first bit of 0 phases every het, with the ALT on the first
+        // haplotype of the first and the third
+        assert_eq!(phased(&[0b1010]), ["1|0", "0|0", "0|1", "1|0", "1|1"]);
+        // a first bit of 1 makes the next bits say which hets are phased,
+        // the first and the third, followed by a bit for each of them
+        assert_eq!(phased(&[0b1011, 0b10]), ["0|1", "0|0", "0/1", "1|0", "1|1"]);
+    }
+}
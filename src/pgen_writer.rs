@@ -0,0 +1,364 @@
+//! Writing .pgen files, the inverse of `pgen_record`.
+//!
+//! Variable-width records always store their hardcalls as a plain 2-bit
+//! array (record type 0), followed by whichever of the multiallelic, phase
+//! and dosage tracks the genotypes need.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use crate::pfile::{NonrefFlagsStorage, PgenError, PgenHeader, StorageMode, VARIANT_BLOCK_SIZE};
+use crate::pgen_record::{allele_code_width, genovec_get, genovec_set, get_bit, set_bit, Genotypes};
+
+/// Writes the records of a subset of a pfile's samples, one variant at a
+/// time, to a new .pgen.
+pub struct PgenWriter {
+    pgen: BufWriter<File>,
+    header: PgenHeader,
+    /// One bit per variant, written if `header` says they are stored
+    /// explicitly.
+    nonref_flags: Vec<u8>,
+    /// Only filled in for variable-width pgens, whose index is written last.
+    record_types: Vec<u8>,
+    record_lengths: Vec<u32>,
+    allele_counts: Vec<u32>,
+}
+
+impl PgenWriter {
+    /// Creates the .pgen at `path`. A fixed-width `header` can only hold
+    /// biallelic hardcalls, which is all `write_variant` will write.
+    pub fn create(
+        path: &str,
+        header: PgenHeader,
+        nonref_flags: Vec<u8>,
+    ) -> Result<PgenWriter, PgenError> {
+        let mut pgen = BufWriter::new(File::create(path)?);
+        if header.storage_mode == StorageMode::FixedWidth {
+            header.write(&mut pgen)?;
+            if header.nonref_flags == NonrefFlagsStorage::Explicit {
+                pgen.write_all(&nonref_flags)?;
+            }
+        } else {
+            // the index comes before the records but needs their lengths, so
+            // leave room for it and write it in `finish`
+            pgen.seek(SeekFrom::Start(variable_width_index_len(&header)))?;
+        }
+        Ok(PgenWriter {
+            pgen,
+            header,
+            nonref_flags,
+            record_types: Vec::new(),
+            record_lengths: Vec::new(),
+            allele_counts: Vec::new(),
+        })
+    }
+
+    /// Writes the genotypes of the next variant, keeping only the samples in
+    /// `sam_idxs`.
+    pub fn write_variant(
+        &mut self,
+        genotypes: &Genotypes,
+        sam_idxs: &[usize],
+        allele_count: u32,
+    ) -> Result<(), PgenError> {
+        let mut record = subset_genovec(&genotypes.hardcalls, sam_idxs);
+        if self.header.storage_mode == StorageMode::FixedWidth {
+            self.pgen.write_all(&record)?;
+            return Ok(());
+        }
+
+        // the tracks after the hardcalls come in the order of their bits
+        let mut record_type = 0u8;
+        if write_multiallelic(&mut record, genotypes, sam_idxs, allele_count) {
+            record_type |= 0x08;
+        }
+        if write_phase(&mut record, genotypes, sam_idxs) {
+            record_type |= 0x10;
+        }
+        if write_dosages(&mut record, genotypes, sam_idxs) {
+            // dosage mode 3, a bitarray of the samples with a dosage
+            record_type |= 0x60;
+            if write_dphase(&mut record, genotypes, sam_idxs) {
+                record_type |= 0x80;
+            }
+        }
+        self.pgen.write_all(&record)?;
+        self.record_types.push(record_type);
+        self.record_lengths.push(record.len() as u32);
+        self.allele_counts.push(allele_count);
+        Ok(())
+    }
+
+    /// Writes the index of a variable-width pgen and flushes the file.
+    pub fn finish(mut self) -> Result<(), PgenError> {
+        if self.header.storage_mode != StorageMode::FixedWidth {
+            let index = self.variable_width_index()?;
+            self.pgen.seek(SeekFrom::Start(0))?;
+            self.pgen.write_all(&index)?;
+        }
+        self.pgen.flush()?;
+        Ok(())
+    }
+
+    /// The header and index of a variable-width pgen, in the layout read by
+    /// `Pfile::read_variable_width_index`.
+    fn variable_width_index(&self) -> Result<Vec<u8>, PgenError> {
+        let num_variants = self.record_lengths.len();
+        let mut index = Vec::new();
+        self.header.write(&mut index)?;
+
+        let num_blocks = num_variants.div_ceil(VARIANT_BLOCK_SIZE);
+        let mut block_offset = variable_width_index_len(&self.header);
+        for block_lengths in self.record_lengths.chunks(VARIANT_BLOCK_SIZE) {
+            index.extend_from_slice(&block_offset.to_le_bytes());
+            block_offset += block_lengths.iter().map(|&len| len as u64).sum::<u64>();
+        }
+
+        for block_idx in 0..num_blocks {
+            let block_start = block_idx * VARIANT_BLOCK_SIZE;
+            let block_end = num_variants.min(block_start + VARIANT_BLOCK_SIZE);
+            index.extend_from_slice(&self.record_types[block_start..block_end]);
+            for length in &self.record_lengths[block_start..block_end] {
+                index.extend_from_slice(&length.to_le_bytes()[..self.header.record_length_bytes]);
+            }
+            for allele_count in &self.allele_counts[block_start..block_end] {
+                index.extend_from_slice(&allele_count.to_le_bytes()[..self.header.allele_count_bytes]);
+            }
+            if self.header.nonref_flags == NonrefFlagsStorage::Explicit {
+                // blocks are a multiple of 8 variants, so start on a byte
+                index.extend_from_slice(&self.nonref_flags[block_start / 8..block_end.div_ceil(8)]);
+            }
+        }
+        Ok(index)
+    }
+}
+
+/// Length of the header and index of a variable-width pgen, which only
+/// depends on the header.
+fn variable_width_index_len(header: &PgenHeader) -> u64 {
+    let num_variants = header.num_variants as usize;
+    let num_blocks = num_variants.div_ceil(VARIANT_BLOCK_SIZE);
+    let mut len = 12 + 8 * num_blocks;
+    for block_idx in 0..num_blocks {
+        let block_len = VARIANT_BLOCK_SIZE.min(num_variants - block_idx * VARIANT_BLOCK_SIZE);
+        len += (block_len * header.record_type_bits).div_ceil(8);
+        len += block_len * (header.record_length_bytes + header.allele_count_bytes);
+        if header.nonref_flags == NonrefFlagsStorage::Explicit {
+            len += block_len.div_ceil(8);
+        }
+    }
+    len as u64
+}
+
+/// The genovec of just the samples in `sam_idxs`, in that order.
+fn subset_genovec(genovec: &[u8], sam_idxs: &[usize]) -> Vec<u8> {
+    let mut subset = vec![0u8; sam_idxs.len().div_ceil(4)];
+    for (new_idx, &sam_idx) in sam_idxs.iter().enumerate() {
+        genovec_set(&mut subset, new_idx, genovec_get(genovec, sam_idx));
+    }
+    subset
+}
+
+/// Appends a bitarray with one bit per value, set where it is true.
+fn write_bits(record: &mut Vec<u8>, values: impl IntoIterator<Item = bool>) {
+    let values = values.into_iter().collect::<Vec<bool>>();
+    let mut bits = vec![0u8; values.len().div_ceil(8)];
+    for (idx, value) in values.into_iter().enumerate() {
+        if value {
+            set_bit(&mut bits, idx);
+        }
+    }
+    record.extend_from_slice(&bits);
+}
+
+/// Appends allele codes packed like `read_allele_codes` expects them.
+fn write_allele_codes(record: &mut Vec<u8>, codes: &[u32], num_values: u32) {
+    let width = allele_code_width(num_values);
+    if width == 0 {
+        return;
+    }
+    let mut packed = vec![0u8; (codes.len() * width).div_ceil(8)];
+    for (idx, &code) in codes.iter().enumerate() {
+        let bit_idx = idx * width;
+        packed[bit_idx / 8] |= (code as u8) << (bit_idx % 8);
+    }
+    record.extend_from_slice(&packed);
+}
+
+/// Appends the multiallelic track if any of the samples has a patch,
+/// returning whether it did. Patched samples are always listed with a
+/// bitarray (format 0), see `Genotypes::read_multiallelic`.
+fn write_multiallelic(
+    record: &mut Vec<u8>,
+    genotypes: &Genotypes,
+    sam_idxs: &[usize],
+    allele_count: u32,
+) -> bool {
+    let candidates = |code: u8| {
+        sam_idxs
+            .iter()
+            .filter(move |&&sam_idx| genovec_get(&genotypes.hardcalls, sam_idx) == code)
+            .map(|sam_idx| genotypes.multiallelic_patches.get(sam_idx))
+    };
+    let het_codes = candidates(0b01)
+        .flatten()
+        .map(|&(_, second)| second - 2)
+        .collect::<Vec<u32>>();
+    let hom_codes = candidates(0b10)
+        .flatten()
+        .flat_map(|&(first, second)| {
+            if allele_count == 3 {
+                // 1/2 or 2/2
+                vec![first - 1]
+            } else {
+                vec![first - 1, second - 1]
+            }
+        })
+        .collect::<Vec<u32>>();
+    if het_codes.is_empty() && hom_codes.is_empty() {
+        return false;
+    }
+
+    let het_format = if het_codes.is_empty() { 15 } else { 0 };
+    let hom_format = if hom_codes.is_empty() { 15 } else { 0 };
+    record.push(het_format | (hom_format << 4));
+    if !het_codes.is_empty() {
+        write_bits(record, candidates(0b01).map(|patch| patch.is_some()));
+        write_allele_codes(record, &het_codes, allele_count - 2);
+    }
+    if !hom_codes.is_empty() {
+        write_bits(record, candidates(0b10).map(|patch| patch.is_some()));
+        let num_values = if allele_count == 3 { 2 } else { allele_count - 1 };
+        write_allele_codes(record, &hom_codes, num_values);
+    }
+    true
+}
+
+/// Appends the phase track if any of the samples' calls is phased, returning
+/// whether it did, see `Genotypes::read_phase`. Homs are phased whenever
+/// their record has a phase track, so one is written for them even without
+/// a phased het.
+fn write_phase(record: &mut Vec<u8>, genotypes: &Genotypes, sam_idxs: &[usize]) -> bool {
+    if !genotypes.phased {
+        return false;
+    }
+    let hets = sam_idxs
+        .iter()
+        .copied()
+        .filter(|&sam_idx| {
+            genotypes
+                .alleles(sam_idx)
+                .is_some_and(|(first, second)| first != second)
+        })
+        .collect::<Vec<usize>>();
+    let phased_hets = hets
+        .iter()
+        .copied()
+        .filter(|&sam_idx| get_bit(&genotypes.phase_present, sam_idx))
+        .collect::<Vec<usize>>();
+    let phased_homs = || {
+        sam_idxs.iter().any(|&sam_idx| {
+            genotypes
+                .alleles(sam_idx)
+                .is_some_and(|(first, second)| first == second)
+        })
+    };
+    if phased_hets.is_empty() && !phased_homs() {
+        return false;
+    }
+
+    let phase_info = |sam_idx: &usize| get_bit(&genotypes.phase_info, *sam_idx);
+    if phased_hets.len() == hets.len() {
+        // every het is phased, so the first bit is unset and the phases
+        // follow directly
+        write_bits(record, std::iter::once(false).chain(hets.iter().map(phase_info)));
+    } else {
+        let phase_present = |sam_idx: &usize| get_bit(&genotypes.phase_present, *sam_idx);
+        write_bits(record, std::iter::once(true).chain(hets.iter().map(phase_present)));
+        write_bits(record, phased_hets.iter().map(phase_info));
+    }
+    true
+}
+
+/// Appends the dosage track in mode 3 if any of the samples has a dosage,
+/// returning whether it did, see `Genotypes::read_dosages`.
+fn write_dosages(record: &mut Vec<u8>, genotypes: &Genotypes, sam_idxs: &[usize]) -> bool {
+    if genotypes.dosage_present.is_empty() {
+        return false;
+    }
+    let has_dosage = |sam_idx: &usize| get_bit(&genotypes.dosage_present, *sam_idx);
+    if !sam_idxs.iter().any(has_dosage) {
+        return false;
+    }
+    write_bits(record, sam_idxs.iter().map(has_dosage));
+    for sam_idx in sam_idxs.iter().filter(|sam_idx| has_dosage(sam_idx)) {
+        record.extend_from_slice(&genotypes.dosages[*sam_idx].to_le_bytes());
+    }
+    true
+}
+
+/// Appends the phased dosage track if any of the samples has a phased
+/// dosage, returning whether it did, see `Genotypes::read_dphase`.
+fn write_dphase(record: &mut Vec<u8>, genotypes: &Genotypes, sam_idxs: &[usize]) -> bool {
+    if genotypes.dphase_present.is_empty() {
+        return false;
+    }
+    let with_dosage = sam_idxs
+        .iter()
+        .copied()
+        .filter(|&sam_idx| get_bit(&genotypes.dosage_present, sam_idx))
+        .collect::<Vec<usize>>();
+    let has_dphase = |sam_idx: &usize| get_bit(&genotypes.dphase_present, *sam_idx);
+    if !with_dosage.iter().any(has_dphase) {
+        return false;
+    }
+    write_bits(record, with_dosage.iter().map(has_dphase));
+    for sam_idx in with_dosage.iter().filter(|sam_idx| has_dphase(sam_idx)) {
+        record.extend_from_slice(&genotypes.dphase_deltas[*sam_idx].to_le_bytes());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pgen_record::RecordReader;
+
+    /// The GTs of `sam_idxs` after writing their hardcalls and phase track
+    /// and reading them back.
+    fn round_trip_phase(genotypes: &Genotypes, sam_idxs: &[usize]) -> Vec<String> {
+        let hardcalls = subset_genovec(&genotypes.hardcalls, sam_idxs);
+        let mut subset = Genotypes::unphased(hardcalls);
+        let mut record = Vec::new();
+        if write_phase(&mut record, genotypes, sam_idxs) {
+            let mut reader = RecordReader::new(&record, 0);
+            subset
+                .read_phase(&mut reader, sam_idxs.len() as u32)
+                .unwrap();
+        }
+        (0..sam_idxs.len())
+            .map(|sam_idx| subset.vcf_genotype(sam_idx).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn phased_homs_keep_their_phase() {
+        // 1|0, 1|1 and 0/1
+        let mut hardcalls = vec![0u8];
+        for (sam_idx, code) in [0b01, 0b10, 0b01].into_iter().enumerate() {
+            genovec_set(&mut hardcalls, sam_idx, code);
+        }
+        let mut genotypes = Genotypes::unphased(hardcalls);
+        genotypes.phased = true;
+        genotypes.phase_present = vec![0b001];
+        genotypes.phase_info = vec![0b001];
+
+        assert_eq!(
+            round_trip_phase(&genotypes, &[0, 1, 2]),
+            ["1|0", "1|1", "0/1"]
+        );
+        assert_eq!(round_trip_phase(&genotypes, &[1, 2]), ["1|1", "0/1"]);
+        assert_eq!(round_trip_phase(&genotypes, &[1]), ["1|1"]);
+        assert_eq!(round_trip_phase(&genotypes, &[2]), ["0/1"]);
+    }
+}
@@ -0,0 +1,766 @@
+//! A sidecar index of a .pvar (or .bim), PVAR.pgrsi, for finding the
+//! variants in some regions or with some IDs without reading the whole .pvar.
+//!
+//! All numbers are little-endian. The index starts with a fixed header
+//!
+//! ```text
+//! magic           "PGRSI\0\0\x01"
+//! pvar_size       u64  the size of the .pvar when indexed
+//! pvar_mtime      u64  and its modification time, in ns since the epoch
+//! num_variants    u64
+//! positions_start u64
+//! num_positions   u64
+//! ids_start       u64
+//! num_ids         u64
+//! contigs_start   u64
+//! num_contigs     u64
+//! ```
+//!
+//! followed by the byte offset of each variant's line in the .pvar (a u64
+//! each). The positions section has an entry (POS u64, variant index u32)
+//! per variant with a numeric POS, sorted by contig and then position, and
+//! the IDs section an entry (FNV-1a hash of the ID u64, variant index u32)
+//! per variant with an ID, sorted by hash. Both are binary searched on disk.
+//! Last, each contig has its name (a u32 length and its bytes) and the first
+//! and number of its position entries (u64 each).
+//!
+//! An index is only used while the .pvar has the size and modification
+//! time it was built from, and the pfile the same number of variants.
+
+use csv::{ReaderBuilder, StringRecord};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::time::UNIX_EPOCH;
+
+use crate::metadata_file::MetadataFile;
+use crate::pfile::PgenError;
+use crate::regions::Regions;
+
+const MAGIC: [u8; 8] = *b"PGRSI\0\0\x01";
+
+/// The length of the header, after which come the line offsets.
+const HEADER_LEN: u64 = 8 + 9 * 8;
+
+/// The length of an entry of the positions and IDs sections.
+const ENTRY_LEN: u64 = 12;
+
+/// An open, fresh index of a .pvar.
+pub(crate) struct PvarIndex {
+    index: BufReader<File>,
+    positions_start: u64,
+    ids_start: u64,
+    num_ids: u64,
+    /// Each contig's name, and the first and number of its position entries.
+    contigs: Vec<(String, u64, u64)>,
+}
+
+/// Where the index of a .pvar goes.
+pub(crate) fn index_path(pvar: &MetadataFile) -> String {
+    format!("{}.pgrsi", pvar.path)
+}
+
+/// Indexes a .pvar, returning the path of the index. Compressed .pvar files
+/// can't be indexed, since their lines can't be seeked to.
+pub(crate) fn write(pvar: &MetadataFile) -> Result<String, PgenError> {
+    let invalid = |message: String| PgenError::InvalidInput {
+        path: pvar.path.clone(),
+        message,
+    };
+    if pvar.path.ends_with(".zst") {
+        return Err(invalid(
+            "a compressed .pvar can't be indexed, decompress it with `zstd -d` first".to_string(),
+        ));
+    }
+    let metadata = fs::metadata(&pvar.path)?;
+    let mut reader = BufReader::new(File::open(&pvar.path)?);
+    let path = index_path(pvar);
+    let mut writer = BufWriter::new(File::create(&path)?);
+    // the header is written once the sections are
+    writer.write_all(&[0; HEADER_LEN as usize])?;
+
+    let mut columns: Option<Vec<String>> = pvar
+        .synthesized_columns
+        .as_ref()
+        .map(|columns| columns.iter().map(|column| column.to_string()).collect());
+    let mut column_idxs = None;
+    let mut contig_idxs: HashMap<String, u32> = HashMap::new();
+    let mut contigs: Vec<String> = Vec::new();
+    let mut positions: Vec<(u32, u64, u32)> = Vec::new();
+    let mut ids: Vec<(u64, u32)> = Vec::new();
+    let mut num_variants: u32 = 0;
+    let mut line_start = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = reader.read_until(b'\n', &mut line)?;
+        if len == 0 {
+            break;
+        }
+        let offset = line_start;
+        line_start += len as u64;
+        let line = std::str::from_utf8(&line)
+            .map_err(|_| invalid(format!("line at byte {} is not UTF-8", offset)))?
+            .trim_end_matches(['\n', '\r']);
+        // like the csv reader, which skips empty lines
+        if line.is_empty() || pvar.synthesized_columns.is_some() && line.trim().is_empty() {
+            continue;
+        }
+        if pvar.synthesized_columns.is_none() && num_variants == 0 && line.starts_with('#') {
+            if !line.starts_with("##") {
+                columns = Some(line[1..].split('\t').map(str::to_string).collect());
+            }
+            continue;
+        }
+        let (chrom_idx, pos_idx, id_idx) = match column_idxs {
+            Some(column_idxs) => column_idxs,
+            None => {
+                let columns = columns
+                    .as_ref()
+                    .ok_or_else(|| PgenError::MissingHeaderLine {
+                        path: pvar.path.clone(),
+                    })?;
+                let column = |name: &str| columns.iter().position(|column| column == name);
+                let required = |name: &str| {
+                    column(name).ok_or_else(|| PgenError::MissingColumn {
+                        path: pvar.path.clone(),
+                        column: name.to_string(),
+                    })
+                };
+                *column_idxs.insert((required("CHROM")?, required("POS")?, column("ID")))
+            }
+        };
+        let fields: Vec<&str> = match pvar.synthesized_columns {
+            Some(_) => line.split_whitespace().collect(),
+            None => line.split('\t').collect(),
+        };
+        let var_idx = num_variants;
+        num_variants += 1;
+        writer.write_all(&offset.to_le_bytes())?;
+        let chrom = fields.get(chrom_idx).copied().unwrap_or_default();
+        let contig_idx = match contig_idxs.get(chrom) {
+            Some(&contig_idx) => contig_idx,
+            None => {
+                let contig_idx = contigs.len() as u32;
+                contigs.push(chrom.to_string());
+                contig_idxs.insert(chrom.to_string(), contig_idx);
+                contig_idx
+            }
+        };
+        if let Some(pos) = fields.get(pos_idx).and_then(|pos| pos.parse().ok()) {
+            positions.push((contig_idx, pos, var_idx));
+        }
+        if let Some(id) = id_idx.and_then(|id_idx| fields.get(id_idx)) {
+            if *id != "." {
+                ids.push((fnv1a(id), var_idx));
+            }
+        }
+    }
+
+    positions.sort_unstable();
+    let positions_start = HEADER_LEN + 8 * num_variants as u64;
+    let mut contig_entries = vec![(0u64, 0u64); contigs.len()];
+    for (entry_idx, &(contig_idx, pos, var_idx)) in positions.iter().enumerate() {
+        let (first, count) = &mut contig_entries[contig_idx as usize];
+        if *count == 0 {
+            *first = entry_idx as u64;
+        }
+        *count += 1;
+        writer.write_all(&pos.to_le_bytes())?;
+        writer.write_all(&var_idx.to_le_bytes())?;
+    }
+    ids.sort_unstable();
+    let ids_start = positions_start + ENTRY_LEN * positions.len() as u64;
+    for &(hash, var_idx) in &ids {
+        writer.write_all(&hash.to_le_bytes())?;
+        writer.write_all(&var_idx.to_le_bytes())?;
+    }
+    let contigs_start = ids_start + ENTRY_LEN * ids.len() as u64;
+    for (contig, (first, count)) in contigs.iter().zip(contig_entries) {
+        writer.write_all(&(contig.len() as u32).to_le_bytes())?;
+        writer.write_all(contig.as_bytes())?;
+        writer.write_all(&first.to_le_bytes())?;
+        writer.write_all(&count.to_le_bytes())?;
+    }
+
+    writer.seek(SeekFrom::Start(0))?;
+    writer.write_all(&MAGIC)?;
+    let header = [
+        metadata.len(),
+        modified_nanos(&metadata),
+        num_variants as u64,
+        positions_start,
+        positions.len() as u64,
+        ids_start,
+        ids.len() as u64,
+        contigs_start,
+        contigs.len() as u64,
+    ];
+    for value in header {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.flush()?;
+    Ok(path)
+}
+
+impl PvarIndex {
+    /// Opens the index of a .pvar, or None if there is none or it is stale:
+    /// the .pvar changed since it was indexed, or doesn't have the pfile's
+    /// `num_variants`.
+    pub(crate) fn open(
+        pvar: &MetadataFile,
+        num_variants: usize,
+    ) -> Result<Option<PvarIndex>, PgenError> {
+        let path = index_path(pvar);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let invalid = |message: &str| PgenError::InvalidInput {
+            path: path.clone(),
+            message: message.to_string(),
+        };
+        let truncated = |error: io::Error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("the index is truncated"),
+            _ => error.into(),
+        };
+        let index_len = file.metadata()?.len();
+        let mut index = BufReader::new(file);
+        let mut magic = [0; 8];
+        index.read_exact(&mut magic).map_err(truncated)?;
+        if magic != MAGIC {
+            return Err(invalid("not a pgen-rs index"));
+        }
+        let mut header = [0; 9];
+        for value in &mut header {
+            *value = read_u64(&mut index).map_err(truncated)?;
+        }
+        let [pvar_size, pvar_mtime, indexed_variants, positions_start, num_positions, ids_start, num_ids, contigs_start, num_contigs] =
+            header;
+        let metadata = fs::metadata(&pvar.path)?;
+        if pvar_size != metadata.len()
+            || pvar_mtime != modified_nanos(&metadata)
+            || indexed_variants != num_variants as u64
+        {
+            return Ok(None);
+        }
+
+        // the line offsets, the positions and the IDs come one after the
+        // other, and then the contigs until the end
+        let section_end = |start: u64, len: u64, entry_len: u64| {
+            len.checked_mul(entry_len)
+                .and_then(|len| start.checked_add(len))
+        };
+        let sections = [
+            (HEADER_LEN, indexed_variants, 8, positions_start),
+            (positions_start, num_positions, ENTRY_LEN, ids_start),
+            (ids_start, num_ids, ENTRY_LEN, contigs_start),
+        ];
+        let fits = sections
+            .into_iter()
+            .all(|(start, len, entry_len, next_start)| {
+                section_end(start, len, entry_len).is_some_and(|end| end <= next_start)
+            });
+        if !fits || contigs_start > index_len {
+            return Err(invalid(
+                "the sections of the index overlap or run past its end",
+            ));
+        }
+        index.seek(SeekFrom::Start(contigs_start))?;
+        let mut contigs = Vec::new();
+        for _ in 0..num_contigs {
+            let mut len = [0; 4];
+            index.read_exact(&mut len).map_err(truncated)?;
+            let len = u32::from_le_bytes(len) as u64;
+            if len > index_len - contigs_start {
+                return Err(invalid("a contig name runs past the end of the index"));
+            }
+            let mut name = vec![0; len as usize];
+            index.read_exact(&mut name).map_err(truncated)?;
+            let name =
+                String::from_utf8(name).map_err(|_| invalid("a contig name is not UTF-8"))?;
+            let first = read_u64(&mut index).map_err(truncated)?;
+            let count = read_u64(&mut index).map_err(truncated)?;
+            let end = first.checked_add(count);
+            if end.is_none_or(|end| end > num_positions) {
+                return Err(invalid("a contig has positions past the last"));
+            }
+            contigs.push((name, first, count));
+        }
+        Ok(Some(PvarIndex {
+            index,
+            positions_start,
+            ids_start,
+            num_ids,
+            contigs,
+        }))
+    }
+
+    /// The indices of the variants in `regions`, in order.
+    pub(crate) fn variants_in(&mut self, regions: &Regions) -> Result<Vec<usize>, PgenError> {
+        let mut var_idxs = Vec::new();
+        for contig_idx in 0..self.contigs.len() {
+            let (first, count) = (self.contigs[contig_idx].1, self.contigs[contig_idx].2);
+            let intervals = regions.intervals(&self.contigs[contig_idx].0);
+            for &(beg, end) in intervals {
+                let start = self.lower_bound(self.positions_start, first, first + count, beg)?;
+                self.index
+                    .seek(SeekFrom::Start(self.positions_start + ENTRY_LEN * start))?;
+                for _ in start..first + count {
+                    let (pos, var_idx) = read_entry(&mut self.index)?;
+                    if pos > end {
+                        break;
+                    }
+                    var_idxs.push(var_idx as usize);
+                }
+            }
+        }
+        var_idxs.sort_unstable();
+        var_idxs.dedup();
+        Ok(var_idxs)
+    }
+
+    /// The indices of the variants whose ID may be one of `ids`, in order.
+    /// IDs are found by hash, so a few others can come along.
+    pub(crate) fn variants_with_ids(&mut self, ids: &[&str]) -> Result<Vec<usize>, PgenError> {
+        let mut var_idxs = Vec::new();
+        for id in ids {
+            let hash = fnv1a(id);
+            let start = self.lower_bound(self.ids_start, 0, self.num_ids, hash)?;
+            self.index
+                .seek(SeekFrom::Start(self.ids_start + ENTRY_LEN * start))?;
+            for _ in start..self.num_ids {
+                let (entry_hash, var_idx) = read_entry(&mut self.index)?;
+                if entry_hash != hash {
+                    break;
+                }
+                var_idxs.push(var_idx as usize);
+            }
+        }
+        var_idxs.sort_unstable();
+        var_idxs.dedup();
+        Ok(var_idxs)
+    }
+
+    /// The first entry in `lo..hi` of the section at `section_start` whose
+    /// key is at least `key`, or `hi`.
+    fn lower_bound(
+        &mut self,
+        section_start: u64,
+        mut lo: u64,
+        mut hi: u64,
+        key: u64,
+    ) -> Result<u64, PgenError> {
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.index
+                .seek(SeekFrom::Start(section_start + ENTRY_LEN * mid))?;
+            if read_entry(&mut self.index)?.0 < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    /// Reads the .pvar records of the variants `var_idxs`, seeking to each
+    /// of their lines.
+    pub(crate) fn records(
+        self,
+        pvar: &MetadataFile,
+        var_idxs: Vec<usize>,
+    ) -> Result<IndexedRecords, PgenError> {
+        Ok(IndexedRecords {
+            index: self.index,
+            pvar: BufReader::new(File::open(&pvar.path)?),
+            whitespace: pvar.synthesized_columns.is_some(),
+            var_idxs: var_idxs.into_iter(),
+            line: String::new(),
+        })
+    }
+}
+
+/// The records of some variants of a .pvar, along with their indices.
+pub(crate) struct IndexedRecords {
+    index: BufReader<File>,
+    pvar: BufReader<File>,
+    /// Whether the fields are separated by any whitespace rather than tabs.
+    whitespace: bool,
+    var_idxs: std::vec::IntoIter<usize>,
+    line: String,
+}
+
+impl IndexedRecords {
+    fn read(&mut self, var_idx: usize) -> Result<StringRecord, PgenError> {
+        self.index
+            .seek(SeekFrom::Start(HEADER_LEN + 8 * var_idx as u64))?;
+        let offset = read_u64(&mut self.index)?;
+        self.pvar.seek(SeekFrom::Start(offset))?;
+        self.line.clear();
+        self.pvar.read_line(&mut self.line)?;
+        let line = self.line.trim_end_matches(['\n', '\r']);
+        if self.whitespace {
+            return Ok(line.split_whitespace().collect());
+        }
+        // the same parsing as the records of the whole .pvar
+        let mut reader = ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .from_reader(line.as_bytes());
+        Ok(reader.records().next().transpose()?.unwrap_or_default())
+    }
+}
+
+impl Iterator for IndexedRecords {
+    type Item = Result<(usize, StringRecord), PgenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let var_idx = self.var_idxs.next()?;
+        Some(self.read(var_idx).map(|rcd| (var_idx, rcd)))
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads an entry of the positions or IDs section.
+fn read_entry(reader: &mut impl Read) -> io::Result<(u64, u32)> {
+    let key = read_u64(reader)?;
+    let mut var_idx = [0; 4];
+    reader.read_exact(&mut var_idx)?;
+    Ok((key, u32::from_le_bytes(var_idx)))
+}
+
+/// The modification time of a file in nanoseconds since the epoch, or 0 if
+/// the platform doesn't have it.
+fn modified_nanos(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+}
+
+/// The 64-bit FNV-1a hash, which unlike the standard library's hashers is
+/// the same on every platform and version.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id_lists::VariantList;
+    use crate::pfile::{Pfile, SampleFilter, VariantFilter};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// Variants of 3 contigs, the first one's out of order, and one without
+    /// an ID.
+    const PVAR: &str = "##fileformat=VCFv4.2\n\
+                        #CHROM\tPOS\tID\tREF\tALT\n\
+                        1\t100\trs1\tA\tG\n\
+                        1\t50\trs2\tC\tT\n\
+                        2\t100\t.\tG\tA\n\
+                        1\t300\trs4\tT\tC\n\
+                        X\t7\trs5\tA\tC\n";
+
+    /// A fresh directory for the fixtures of a test.
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes PREFIX.EXTENSION and finds it as the pfile's .pvar.
+    fn write_pvar(prefix: &str, extension: &str, contents: &str) -> MetadataFile {
+        fs::write(format!("{}.{}", prefix, extension), contents).unwrap();
+        MetadataFile::find_pvar(prefix)
+    }
+
+    fn u64_at(bytes: &[u8], offset: u64) -> u64 {
+        let offset = offset as usize;
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// The `len` entries of the section at `start`.
+    fn entries(bytes: &[u8], start: u64, len: u64) -> Vec<(u64, u32)> {
+        (0..len)
+            .map(|entry_idx| {
+                let mut entry = &bytes[(start + ENTRY_LEN * entry_idx) as usize..];
+                read_entry(&mut entry).unwrap()
+            })
+            .collect()
+    }
+
+    /// Replaces the IDs section of an index with `ids`, by ID.
+    fn rewrite_ids(path: &str, ids: &[(&str, u32)]) {
+        let mut bytes = fs::read(path).unwrap();
+        let ids_start = u64_at(&bytes, 8 + 5 * 8) as usize;
+        let mut entries = ids
+            .iter()
+            .map(|&(id, var_idx)| (fnv1a(id), var_idx))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        for (entry_idx, (hash, var_idx)) in entries.into_iter().enumerate() {
+            let entry_start = ids_start + ENTRY_LEN as usize * entry_idx;
+            bytes[entry_start..entry_start + 8].copy_from_slice(&hash.to_le_bytes());
+            bytes[entry_start + 8..entry_start + 12].copy_from_slice(&var_idx.to_le_bytes());
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn layout() {
+        let dir = fixture_dir("index-layout");
+        let pvar = write_pvar(&dir.join("layout").to_string_lossy(), "pvar", PVAR);
+        let bytes = fs::read(write(&pvar).unwrap()).unwrap();
+        assert_eq!(bytes[..8], MAGIC);
+        let header = (0..9)
+            .map(|value_idx| u64_at(&bytes, 8 + 8 * value_idx))
+            .collect::<Vec<_>>();
+        let positions_start = HEADER_LEN + 8 * 5;
+        let ids_start = positions_start + ENTRY_LEN * 5;
+        let contigs_start = ids_start + ENTRY_LEN * 4;
+        assert_eq!(header[0], PVAR.len() as u64);
+        assert_eq!(
+            header[2..],
+            [5, positions_start, 5, ids_start, 4, contigs_start, 3]
+        );
+
+        // the offset of each variant's line
+        let line_starts = PVAR
+            .match_indices('\n')
+            .map(|(idx, _)| idx as u64 + 1)
+            .collect::<Vec<_>>();
+        let offsets = (0..5)
+            .map(|var_idx| u64_at(&bytes, HEADER_LEN + 8 * var_idx))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, line_starts[1..6]);
+        // the positions by contig, in the order they first appear, and
+        // then by position
+        assert_eq!(
+            entries(&bytes, positions_start, 5),
+            [(50, 1), (100, 0), (300, 3), (100, 2), (7, 4)]
+        );
+        let mut ids = [("rs1", 0), ("rs2", 1), ("rs4", 3), ("rs5", 4)]
+            .map(|(id, var_idx)| (fnv1a(id), var_idx));
+        ids.sort_unstable();
+        assert_eq!(entries(&bytes, ids_start, 4), ids);
+        let mut contigs = Vec::new();
+        for (name, first, count) in [("1", 0u64, 3u64), ("2", 3, 1), ("X", 4, 1)] {
+            contigs.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            contigs.extend_from_slice(name.as_bytes());
+            contigs.extend_from_slice(&first.to_le_bytes());
+            contigs.extend_from_slice(&count.to_le_bytes());
+        }
+        assert_eq!(bytes[contigs_start as usize..], contigs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn contigs_and_lower_bound() {
+        let dir = fixture_dir("index-bounds");
+        let pvar = write_pvar(&dir.join("bounds").to_string_lossy(), "pvar", PVAR);
+        write(&pvar).unwrap();
+        let mut index = PvarIndex::open(&pvar, 5).unwrap().unwrap();
+        assert_eq!(
+            index.contigs,
+            [
+                ("1".to_string(), 0, 3),
+                ("2".to_string(), 3, 1),
+                ("X".to_string(), 4, 1)
+            ]
+        );
+        // contig 1 has positions 50, 100 and 300
+        let positions_start = index.positions_start;
+        let bounds = [0, 50, 51, 100, 300, 301]
+            .map(|pos| index.lower_bound(positions_start, 0, 3, pos).unwrap());
+        assert_eq!(bounds, [0, 0, 1, 1, 2, 3]);
+        // and the other two one each
+        assert_eq!(index.lower_bound(positions_start, 3, 4, 100).unwrap(), 3);
+        assert_eq!(index.lower_bound(positions_start, 4, 5, 8).unwrap(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_indexes_are_not_used() {
+        let dir = fixture_dir("index-stale");
+        let pvar = write_pvar(&dir.join("stale").to_string_lossy(), "pvar", PVAR);
+        assert!(PvarIndex::open(&pvar, 5).unwrap().is_none());
+        write(&pvar).unwrap();
+        assert!(PvarIndex::open(&pvar, 5).unwrap().is_some());
+        // for a pfile with another number of variants
+        assert!(PvarIndex::open(&pvar, 4).unwrap().is_none());
+
+        // once the .pvar has another size
+        let modified = fs::metadata(&pvar.path).unwrap().modified().unwrap();
+        fs::write(&pvar.path, format!("{}Y\t1\trs6\tA\tC\n", PVAR)).unwrap();
+        assert!(PvarIndex::open(&pvar, 5).unwrap().is_none());
+        // or the same size, but another modification time
+        let set_modified = |modified| {
+            let pvar_file = File::options().write(true).open(&pvar.path).unwrap();
+            pvar_file.set_modified(modified).unwrap();
+        };
+        fs::write(&pvar.path, PVAR.replace("rs5", "rs6")).unwrap();
+        set_modified(modified + Duration::from_secs(1));
+        assert!(PvarIndex::open(&pvar, 5).unwrap().is_none());
+        set_modified(modified);
+        assert!(PvarIndex::open(&pvar, 5).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_indexes_are_rejected() {
+        let dir = fixture_dir("index-corrupt");
+        let pvar = write_pvar(&dir.join("corrupt").to_string_lossy(), "pvar", PVAR);
+        let path = write(&pvar).unwrap();
+        let fresh = fs::read(&path).unwrap();
+        let contigs_start = u64_at(&fresh, 8 + 7 * 8) as usize;
+        let corruptions: [(usize, &[u8]); 4] = [
+            // more positions than fit before the IDs
+            (8 + 4 * 8, &6u64.to_le_bytes()),
+            // IDs past the end of the index
+            (8 + 6 * 8, &u64::MAX.to_le_bytes()),
+            // a contig name of 4 GiB
+            (contigs_start, &u32::MAX.to_le_bytes()),
+            // the first contig's positions past the last
+            (contigs_start + 4 + 1, &4u64.to_le_bytes()),
+        ];
+        for (offset, bytes) in corruptions {
+            let mut corrupt = fresh.clone();
+            corrupt[offset..offset + bytes.len()].copy_from_slice(bytes);
+            fs::write(&path, corrupt).unwrap();
+            assert!(
+                matches!(
+                    PvarIndex::open(&pvar, 5),
+                    Err(PgenError::InvalidInput { .. })
+                ),
+                "corrupt at {}",
+                offset
+            );
+        }
+        fs::write(&path, &fresh[..fresh.len() - 1]).unwrap();
+        assert!(PvarIndex::open(&pvar, 5).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn matches_a_full_scan() {
+        let dir = fixture_dir("index-scan");
+        // variants of 3 contigs in no order, some at the same position or
+        // with the same ID, and some without one
+        let variants = (0..300u64)
+            .map(|var_idx| {
+                let chrom = ["1", "chr2", "X"][(var_idx % 7 % 3) as usize];
+                let pos = var_idx * 7919 % 1000 + 1;
+                let id = match var_idx % 10 {
+                    0 => ".".to_string(),
+                    _ => format!("rs{}", var_idx % 50),
+                };
+                (chrom, pos, id)
+            })
+            .collect::<Vec<_>>();
+        let regions = Regions::parse("1:100-300,2:1-50,2:900-,X:500").unwrap();
+        let ids = ["rs1", "rs7", "rs49", "rs99"];
+        let in_regions = (0..variants.len())
+            .filter(|&var_idx| regions.contains(variants[var_idx].0, variants[var_idx].1))
+            .collect::<Vec<_>>();
+        let with_ids = (0..variants.len())
+            .filter(|&var_idx| ids.contains(&variants[var_idx].2.as_str()))
+            .collect::<Vec<_>>();
+        assert!(!in_regions.is_empty() && !with_ids.is_empty());
+
+        // a .pvar, and a headerless .bim separated by any whitespace
+        let mut pvar_contents = "#CHROM\tPOS\tID\tREF\tALT\n".to_string();
+        let mut bim_contents = String::new();
+        for (chrom, pos, id) in &variants {
+            pvar_contents.push_str(&format!("{}\t{}\t{}\tA\tG\n", chrom, pos, id));
+            bim_contents.push_str(&format!("{}\t{}  0\t{} G A\n", chrom, id, pos));
+        }
+        for (extension, contents, pos_idx) in [("pvar", pvar_contents, 1), ("bim", bim_contents, 3)]
+        {
+            let prefix = dir.join(extension).to_string_lossy().into_owned();
+            let pvar = write_pvar(&prefix, extension, &contents);
+            write(&pvar).unwrap();
+            let mut index = PvarIndex::open(&pvar, variants.len()).unwrap().unwrap();
+            assert_eq!(
+                index.variants_in(&regions).unwrap(),
+                in_regions,
+                "{}",
+                extension
+            );
+            assert_eq!(
+                index.variants_with_ids(&ids).unwrap(),
+                with_ids,
+                "{}",
+                extension
+            );
+            let positions = index
+                .records(&pvar, in_regions.clone())
+                .unwrap()
+                .map(|record| {
+                    let (var_idx, rcd) = record.unwrap();
+                    (var_idx, rcd[0].to_string(), rcd[pos_idx].parse().unwrap())
+                })
+                .collect::<Vec<(usize, String, u64)>>();
+            let expected = in_regions
+                .iter()
+                .map(|&var_idx| {
+                    (
+                        var_idx,
+                        variants[var_idx].0.to_string(),
+                        variants[var_idx].1,
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(positions, expected, "{}", extension);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_collisions_are_filtered_by_id() {
+        let dir = fixture_dir("index-collisions");
+        let prefix = dir.join("collisions").to_string_lossy().into_owned();
+        // the 5 variants of PVAR for a single hom ref sample
+        let mut pgen = vec![0x6C, 0x1B, 0x02, 5, 0, 0, 0, 1, 0, 0, 0, 0x00];
+        pgen.extend_from_slice(&[0; 5]);
+        fs::write(format!("{}.pgen", prefix), pgen).unwrap();
+        fs::write(format!("{}.psam", prefix), "#IID\nS1\n").unwrap();
+        let pvar = write_pvar(&prefix, "pvar", PVAR);
+        let pfile = Pfile::from_prefix(prefix.clone()).unwrap();
+        let path = pfile.index_pvar().unwrap();
+
+        // as if the IDs of the first two variants had the same hash
+        rewrite_ids(&path, &[("rs1", 0), ("rs1", 1), ("rs4", 3), ("rs5", 4)]);
+        let mut index = PvarIndex::open(&pvar, 5).unwrap().unwrap();
+        assert_eq!(index.variants_with_ids(&["rs1"]).unwrap(), [0, 1]);
+
+        let extract = dir.join("extract.txt");
+        fs::write(&extract, "rs1\n").unwrap();
+        let var_filter = VariantFilter {
+            extract: Some(VariantList::from_file(&extract.to_string_lossy()).unwrap()),
+            ..Default::default()
+        };
+        let vcf = dir.join("out.vcf");
+        pfile
+            .output_vcf(SampleFilter::default(), var_filter, vcf.clone(), false)
+            .unwrap();
+        let vcf = fs::read_to_string(&vcf).unwrap();
+        let ids = vcf
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split('\t').nth(2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["rs1"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
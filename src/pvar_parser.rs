@@ -1,6 +1,4 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::hash::Hash;
 use std::io::{self, BufRead};
 
 use pest::Parser;
@@ -10,32 +8,21 @@ use pest_derive::Parser;
 #[grammar = "pvar_parser.pest"]
 pub struct PvarParser;
 
-impl PvarParser {
-    fn get_substring_from_index(input: &str, start_index: usize) -> Option<&str> {
-        if start_index < input.len() {
-            Some(&input[start_index..])
-        } else {
-            None
-        }
-    }
-    
-    fn get_meta_idname(input: &str) -> Option<&str> {
-        let pairs = PvarParser::parse(Rule::infoheader, input).ok().unwrap();
-        for pair in pairs {
-            for inner_pair in pair.into_inner() {
-                if inner_pair.as_rule() == Rule::idname {
-                    return Some(inner_pair.as_str())
-                }
-            }
-        }
-        return None
-    }
+/// The Number and Type an `##INFO` line declares for its key.
+#[derive(Debug, Clone)]
+pub struct InfoType {
+    /// A count, `A`, `R`, `G` or `.`.
+    pub number: String,
+    /// `Integer`, `Float`, `Flag`, `Character` or `String`.
+    pub value_type: String,
+}
 
+impl PvarParser {
     fn get_meta_descs(input: &str) -> (String, String) {
         // let mut kv_pairs = HashMap::new();
         let pairs_opt = PvarParser::parse(Rule::infoheader, input).ok();
         match pairs_opt {
-            Some (pairs) => {
+            Some(pairs) => {
                 let mut idname = "";
                 let mut desc = "a";
                 for pair in pairs {
@@ -49,16 +36,13 @@ impl PvarParser {
                         }
                     }
                 }
-                return (idname.to_string(), desc.to_string())
-            },
-            None => return ("".to_string(), "".to_string()),
-        };
+                (idname.to_string(), desc.to_string())
+            }
+            None => ("".to_string(), "".to_string()),
+        }
     }
 
-    pub fn format_descriptions(filepath: &str) -> io::Result<Vec<String>> {
-        let file_path = filepath;
-        let file = File::open(file_path)?;
-        let reader = io::BufReader::new(file);
+    pub fn format_descriptions(reader: impl BufRead) -> io::Result<Vec<String>> {
         let mut parsed_descriptions = Vec::new();
     
         for line in reader.lines() {
@@ -72,179 +56,113 @@ impl PvarParser {
         }
         Ok(parsed_descriptions)
     }
-    
-    pub fn get_meta_idnames(filepath: &str) -> io::Result<Vec<String>> {
-        let file_path = filepath;
-        let file = File::open(file_path)?;
-        let reader = io::BufReader::new(file);
-        let mut continue_parsing = true;
-        let mut parsed_idnames = Vec::new();
-    
-        for line in reader.lines() {
-            let line = line?;
-            match Self::get_meta_idname(&line) {
-                Some(substr) => parsed_idnames.push(substr.to_string()),
-                None => {
-                    if line.contains("#CHROM") { // stop once we get to the real header
-                        continue_parsing = false;
-                        break;
-                    }
-                },
-            }
-            if !continue_parsing {
-                break;
-            }
-        }
-        Ok(parsed_idnames)
-    }
 
-    fn get_info_line(input: &str) -> Option<&str> {
-        let pairs = PvarParser::parse(Rule::line, input).ok()?;
-        for pair in pairs {
-            for inner_pair in pair.into_inner() {
-                if inner_pair.as_rule() == Rule::INFO {
-                    return Some(inner_pair.as_str());
-                }
+    /// The declared Number and Type of each INFO key, from the `##` lines of
+    /// a .pvar header.
+    pub fn info_types(meta: &str) -> HashMap<String, InfoType> {
+        let mut info_types = HashMap::new();
+        for line in meta.lines() {
+            let Some(("INFO", attributes)) = structured_line(line) else {
+                continue;
+            };
+            if let Some(id) = attribute(attributes, "ID") {
+                let info_type = InfoType {
+                    number: attribute(attributes, "Number").unwrap_or(".").to_string(),
+                    value_type: attribute(attributes, "Type")
+                        .unwrap_or("String")
+                        .to_string(),
+                };
+                info_types.insert(id.to_string(), info_type);
             }
         }
-        None
+        info_types
     }
+}
 
-    pub fn get_info_kv_pairs(input: &str) -> HashMap<String, String> {
-        let mut kv_pairs = HashMap::new();
-        let pairs_opt = PvarParser::parse(Rule::INFO, input).ok();
-        match pairs_opt {
-            Some (pairs) => {
-                for pair in pairs {
-                    for inner_pair in pair.into_inner() {
-                        if inner_pair.as_rule() == Rule::key_val_pair {
-                            let mut keyname = "";
-                            let mut val = "";
-                            for kv in inner_pair.into_inner() {
-                                if kv.as_rule() == Rule::key_name {
-                                    keyname = kv.as_str();
-                                }
-                                if kv.as_rule() == Rule::value {
-                                    val = kv.as_str();
-                                }
-                            }
-                            kv_pairs.insert(keyname.to_string(), val.to_string());
-                        } else if inner_pair.as_rule() == Rule::key_name {
-                            kv_pairs.insert(inner_pair.as_str().to_string(), "".to_string());
-                        }
-                    }
-                }
-            },
-            None => (),
-        }
-        return kv_pairs
-    }
+/// Splits a structured header line like `##INFO=<ID=DP,...>` into its kind
+/// and its attributes.
+pub(crate) fn structured_line(line: &str) -> Option<(&str, &str)> {
+    let (kind, attributes) = line.strip_prefix("##")?.split_once("=<")?;
+    Some((kind, attributes.strip_suffix('>')?))
+}
 
-    fn get_line_entry(input: &str, rule: Rule) -> Option<&str> {
-        let pairs = PvarParser::parse(Rule::line, input).ok()?;
-        for pair in pairs {
-            for inner_pair in pair.into_inner() {
-                if inner_pair.as_rule() == rule {
-                    return Some(inner_pair.as_str());
+/// The value of one of the comma-separated attributes of a structured header
+/// line, without its quotes.
+pub(crate) fn attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (idx, c) in attributes.char_indices().chain([(attributes.len(), ',')]) {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == ',' && !in_quotes {
+            if let Some((name, value)) = attributes[start..idx].split_once('=') {
+                if name == key {
+                    return Some(value.trim_matches('"'));
                 }
             }
+            start = idx + 1;
         }
-        None
     }
+    None
+}
 
-    // fn get_alt_info(altinput: &str) -> Vec<String> {
-    //     let alleles = altinput.split(",").collect();
-    //     return alleles;
-    // }
-    
-    fn get_info_lines(filepath: &str) -> io::Result<Vec<String>> {
-        let file_path = filepath;
-        let file = File::open(file_path)?;
-        let reader = io::BufReader::new(file);
-        let mut parsed_kvpairs = Vec::new();
-    
-        for line in reader.lines() {
-            let line = line?;
-            if !line.starts_with("#") {
-                if let Some(kvpairs) = Self::get_info_line(&line) {
-                    parsed_kvpairs.push(kvpairs.to_string());
-                }
-            }
-        }
-        Ok(parsed_kvpairs)
-    }
-    
-    fn find_kv_pair<'a>(input: &'a str, keyname: &str) -> Option<&'a str> {
-        if let Some(start_index) = input.find(keyname) {
-            let start_index = start_index + keyname.len();
-            let end_index = input[start_index..].find(';')
-                .map(|index| start_index + index)
-                .unwrap_or_else(|| input.len());
-            return Some(&input[start_index..end_index]);
-        }
-        None
-    }
-    
-    fn find_key<'a>(input: &'a str, keyname: &str) -> bool {
-        if let Some(start_index) = input.find(keyname) {
-            let start_index = start_index + keyname.len();
-            let end_index = input[start_index..].find(';')
-                .map(|index| start_index + index)
-                .unwrap_or_else(|| input.len());
-            let substr = &input[start_index..end_index];
-            // if we've consumed the prefix and there's nothing else then we've found a single key
-            return substr == "";
-        } else {
-            return false;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn structured_lines() {
+        assert_eq!(
+            structured_line("##INFO=<ID=DP,Number=1>"),
+            Some(("INFO", "ID=DP,Number=1"))
+        );
+        assert_eq!(
+            structured_line("##contig=<ID=1,length=249250621>"),
+            Some(("contig", "ID=1,length=249250621"))
+        );
+        assert_eq!(structured_line("##fileformat=VCFv4.2"), None);
+        assert_eq!(structured_line("##INFO=<ID=DP,Number=1"), None);
+        assert_eq!(structured_line("#CHROM\tPOS"), None);
+
+        let attributes = r#"ID=AF,Description="a, b=c, ID=DP",Type=Float"#;
+        assert_eq!(attribute(attributes, "ID"), Some("AF"));
+        assert_eq!(attribute(attributes, "Description"), Some("a, b=c, ID=DP"));
+        assert_eq!(attribute(attributes, "Type"), Some("Float"));
+        assert_eq!(attribute(attributes, "Typ"), None);
+        assert_eq!(attribute(attributes, "Number"), None);
+        assert_eq!(attribute("ID=X,Number=", "Number"), Some(""));
     }
 
+    #[test]
+    fn info_types() {
+        let meta = "##fileformat=VCFv4.2\n\
+                    ##INFO=<ID=DB,Number=0,Type=Flag,Description=\"dbSNP membership, build 129\">\n\
+                    ##INFO=<ID=AF,Number=A,Type=Float,Description=\"Frequency, AF=AC/AN\">\n\
+                    ##INFO=<ID=NOTE,Description=\"Type=Integer, or not\",Number=.,Type=String>\n\
+                    ##INFO=<ID=BARE,Description=\"no Number or Type\">\n\
+                    ##INFO=<Number=1,Type=Integer,Description=\"no ID\">\n\
+                    ##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">\n\
+                    ##contig=<ID=1,length=249250621>\n";
+        let info_types = PvarParser::info_types(meta);
+        let mut types = info_types
+            .iter()
+            .map(|(id, info_type)| {
+                (
+                    id.as_str(),
+                    info_type.number.as_str(),
+                    info_type.value_type.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        types.sort_unstable();
+        assert_eq!(
+            types,
+            [
+                ("AF", "A", "Float"),
+                ("BARE", ".", "String"),
+                ("DB", "0", "Flag"),
+                ("NOTE", ".", "String"),
+            ]
+        );
+    }
 }
-
-
-fn main() -> io::Result<()> {
-    // let parsed_idnames = get_idnames("data/basic0.pvar");
-
-    // for idname in &parsed_idnames {
-    //     println!("{:?}", idname);
-    // }
-
-    // let ids = PvarParser::get_meta_descs("##INFO=<ID=AA,Number=1,Type=String,Description=\"Ancestral Allele. Format: AA|REF|ALT|IndelType. AA: Ancestral allele, REF:Reference Allele, ALT:Alternate Allele, IndelType:Type of Indel (REF, ALT and IndelType are only defined for indels)\">");
-    // println!("{:?}", ids);
-
-    // let info: HashMap<String, String> = PvarParser::get_info_kv_pairs("AC=2731;AF=0.545327;AN=5008;NS=2504;DP=19168;EAS_AF=0.124;EX_TARGET;AMR_AF=0.5072;AFR_AF=0.8835;EUR_AF=0.6252;SAS_AF=0.4673;AA=.|||;VT=SNP");
-    // let info = PvarParser::get_info_kv_pairs("AC=2731;AF=0.545327");
-    // println!("{:?}", info);
-
-
-    // let info = get_info_info("data/basic0.pvar");
-    // for inf in &info {
-    //     for kv in inf {
-    //         println!("{:?}", kv);
-    //     }
-    //     // println!("{:?}", inf)
-
-    // }
-
-    // if let Some(info) = PvarParser::get_info_lines("data/basic1/basic1.pvar").ok() {
-    //     let mut i = 0;
-    //     for ln in info {
-    //         // println!("{:?}", ln);
-    //         // let prefix = format!("{}{}", "EAS_AF", "=");
-    //         let prefix = "EX_TARGET";
-    //         // if let Some(pair) = PvarParser::find_kv_pair(&ln, &prefix) {
-    //         if PvarParser::find_key(&ln, &prefix) {
-    //             println!("{}: {:?}", i, ln);
-    //             // println!("{:?}", has);
-    //             println!();
-    //         }
-    //         i += 1;
-    //         // if i > 10 {
-    //         //     break;
-    //         // }
-    //     }
-    // }
-
-
-    Ok(())
-}
\ No newline at end of file
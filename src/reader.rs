@@ -0,0 +1,125 @@
+use csv::StringRecordsIntoIter;
+use std::fs::File;
+use std::io::Read;
+
+use crate::pfile::{PgenError, Pfile};
+use crate::pgen_record::Genotypes;
+
+/// Reads the genotypes of a pfile, one variant at a time.
+///
+/// Variants are cheapest to read in increasing order, since LD-compressed
+/// records are stored relative to an earlier record that is then already
+/// decoded.
+pub struct PgenReader {
+    pfile: Pfile,
+    pgen: File,
+    /// The hardcalls of the last record that wasn't LD-compressed, along with
+    /// its variant index.
+    ld_base: Option<(usize, Vec<u8>)>,
+}
+
+impl PgenReader {
+    /// Opens the pfile with the given prefix, i.e. PREFIX.pgen, PREFIX.pvar
+    /// (or PREFIX.bim) and PREFIX.psam (or PREFIX.fam).
+    pub fn open(pfile_prefix: impl Into<String>) -> Result<PgenReader, PgenError> {
+        let pfile = Pfile::from_prefix(pfile_prefix.into())?;
+        let pgen = File::open(pfile.pgen_path())?;
+        Ok(PgenReader {
+            pfile,
+            pgen,
+            ld_base: None,
+        })
+    }
+
+    /// The pfile being read, for its header and metadata.
+    pub fn pfile(&self) -> &Pfile {
+        &self.pfile
+    }
+
+    pub fn num_variants(&self) -> usize {
+        self.pfile.num_variants as usize
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.pfile.num_samples as usize
+    }
+
+    /// Reads the genotypes of the variant with the given index in the .pvar.
+    pub fn read_variant(&mut self, var_idx: usize) -> Result<Genotypes, PgenError> {
+        if var_idx >= self.num_variants() {
+            return Err(PgenError::VariantOutOfRange {
+                var_idx,
+                num_variants: self.num_variants(),
+            });
+        }
+        self.pfile
+            .read_genotypes(&mut self.pgen, var_idx, &mut self.ld_base)
+    }
+
+    /// Iterates over the genotypes of every variant, in .pvar order.
+    pub fn variants(&mut self) -> Variants<'_> {
+        Variants {
+            reader: self,
+            var_idx: 0,
+        }
+    }
+
+    /// Iterates over the rows of the .psam, in the same order as the samples
+    /// of each variant's genotypes.
+    pub fn samples(&self) -> Result<Samples, PgenError> {
+        let mut psam_reader = self.pfile.psam_reader()?;
+        Ok(Samples {
+            headers: psam_reader.headers()?.iter().map(str::to_string).collect(),
+            records: psam_reader.into_records(),
+        })
+    }
+}
+
+/// Iterator over the genotypes of every variant, see `PgenReader::variants`.
+pub struct Variants<'a> {
+    reader: &'a mut PgenReader,
+    var_idx: usize,
+}
+
+impl Iterator for Variants<'_> {
+    type Item = Result<Genotypes, PgenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.var_idx >= self.reader.num_variants() {
+            return None;
+        }
+        let genotypes = self.reader.read_variant(self.var_idx);
+        self.var_idx += 1;
+        Some(genotypes)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.reader.num_variants() - self.var_idx;
+        (remaining, Some(remaining))
+    }
+}
+
+/// Iterator over the rows of the .psam, each as its fields in the order of
+/// `headers`, see `PgenReader::samples`.
+pub struct Samples {
+    headers: Vec<String>,
+    records: StringRecordsIntoIter<Box<dyn Read>>,
+}
+
+impl Samples {
+    /// The column names of the .psam, without the leading `#`.
+    pub fn headers(&self) -> &[String] {
+        &self.headers
+    }
+}
+
+impl Iterator for Samples {
+    type Item = Result<Vec<String>, PgenError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.records.next().map(|record| {
+            let record = record?;
+            Ok(record.iter().map(str::to_string).collect())
+        })
+    }
+}
@@ -0,0 +1,289 @@
+//! Genomic regions to restrict variants to, given like in bcftools: either
+//! as a comma-separated list like `chr1:1000-2000,2` or as a file.
+//!
+//! Positions are 1-based and inclusive, except in .bed files which are
+//! 0-based and half-open. Contig names are matched regardless of a `chr`
+//! prefix, so that `chr1` finds the variants of contig `1` and the other way
+//! around, and `chrM` matches `MT`.
+
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::pfile::PgenError;
+
+/// A set of regions, as the sorted, non-overlapping 1-based inclusive
+/// intervals of each contig.
+#[derive(Debug, Clone, Default)]
+pub struct Regions {
+    /// By the contig name without `chr` prefix, see `contig_key`.
+    contigs: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Regions {
+    /// Parses a comma-separated list of regions, each `CHROM` for a whole
+    /// contig, `CHROM:POS` for one position, `CHROM:BEG-END`, or `CHROM:BEG-`
+    /// for the rest of the contig.
+    ///
+    /// Contig names can contain colons, like `HLA-A*01:01:01:01`, which
+    /// without the names of the contigs reads the same as position 1 of
+    /// `HLA-A*01:01:01`. So a region with a range also stands for the whole
+    /// contig named like all of it.
+    pub fn parse(regions: &str) -> Result<Regions, PgenError> {
+        let mut intervals = Vec::new();
+        for region in regions.split(',') {
+            let invalid = |message: &str| PgenError::InvalidRegion {
+                region: region.to_string(),
+                message: message.to_string(),
+            };
+            let (chrom, range) = match region.rsplit_once(':') {
+                Some((chrom, range)) => (chrom, Some(range)),
+                None => (region, None),
+            };
+            if chrom.is_empty() {
+                return Err(invalid("expected a contig name"));
+            }
+            let (beg, end) = match range {
+                None => (1, u64::MAX),
+                Some(range) => {
+                    let (beg, end) = match range.split_once('-') {
+                        Some((beg, "")) => (beg, None),
+                        Some((beg, end)) => (beg, Some(end)),
+                        None => (range, Some(range)),
+                    };
+                    let position = |position: &str| match position.parse() {
+                        Ok(position) if position > 0 => Ok(position),
+                        _ => Err(invalid("positions must be numbers from 1")),
+                    };
+                    let beg = position(beg)?;
+                    let end = end.map_or(Ok(u64::MAX), position)?;
+                    if beg > end {
+                        return Err(invalid("the region ends before it begins"));
+                    }
+                    (beg, end)
+                }
+            };
+            intervals.push((chrom.to_string(), beg, end));
+            if range.is_some() {
+                intervals.push((region.to_string(), 1, u64::MAX));
+            }
+        }
+        Ok(Regions::from_intervals(intervals))
+    }
+
+    /// Reads the regions of a file, plain or gzipped, with a region per line
+    /// and its columns separated by tabs. In a .bed (or .bed.gz) they are
+    /// CHROM, BEG and END, 0-based and half-open. Otherwise they are CHROM
+    /// and POS, or CHROM, BEG and END, 1-based and inclusive. Lines starting
+    /// with `#`, the `track` and `browser` lines of BED files, and empty BED
+    /// intervals are skipped.
+    pub fn from_file(path: &str) -> Result<Regions, PgenError> {
+        let bed = path.ends_with(".bed") || path.ends_with(".bed.gz");
+        let mut file = BufReader::new(File::open(path)?);
+        let reader: Box<dyn BufRead> = if file.fill_buf()?.starts_with(&[0x1F, 0x8B]) {
+            Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        } else {
+            Box::new(file)
+        };
+        let mut intervals = Vec::new();
+        for (line_idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            let invalid = |message: &str| PgenError::InvalidInput {
+                path: path.to_string(),
+                message: format!("line {}: {}", line_idx + 1, message),
+            };
+            if line.trim().is_empty()
+                || line.starts_with('#')
+                || bed && (line.starts_with("track") || line.starts_with("browser"))
+            {
+                continue;
+            }
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let position = |field_idx: usize| {
+                fields[field_idx]
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| invalid("positions must be numbers"))
+            };
+            let (beg, end) = match (bed, fields.len()) {
+                (true, 3..) => (position(1)? + 1, position(2)?),
+                (true, _) => return Err(invalid("expected CHROM, BEG and END")),
+                (false, 2) => (position(1)?, position(1)?),
+                (false, 3..) => (position(1)?, position(2)?),
+                (false, _) => return Err(invalid("expected CHROM and POS, or CHROM, BEG and END")),
+            };
+            if bed && end.checked_add(1) == Some(beg) {
+                continue;
+            }
+            if beg > end {
+                return Err(invalid("the region ends before it begins"));
+            }
+            intervals.push((fields[0].to_string(), beg, end));
+        }
+        Ok(Regions::from_intervals(intervals))
+    }
+
+    fn from_intervals(intervals: Vec<(String, u64, u64)>) -> Regions {
+        let mut contigs: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for (chrom, beg, end) in intervals {
+            contigs
+                .entry(contig_key(&chrom).to_string())
+                .or_default()
+                .push((beg, end));
+        }
+        for intervals in contigs.values_mut() {
+            intervals.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+            for &(beg, end) in intervals.iter() {
+                match merged.last_mut() {
+                    Some(last) if beg <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                    _ => merged.push((beg, end)),
+                }
+            }
+            *intervals = merged;
+        }
+        Regions { contigs }
+    }
+
+    /// The sorted, non-overlapping 1-based inclusive intervals of the
+    /// contig `chrom`.
+    pub(crate) fn intervals(&self, chrom: &str) -> &[(u64, u64)] {
+        self.contigs
+            .get(contig_key(chrom))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether the 1-based position `pos` of the contig `chrom` is in one
+    /// of the regions.
+    pub fn contains(&self, chrom: &str, pos: u64) -> bool {
+        let intervals = self.intervals(chrom);
+        // the last interval beginning at or before pos
+        let idx = intervals.partition_point(|&(beg, _)| beg <= pos);
+        idx > 0 && pos <= intervals[idx - 1].1
+    }
+}
+
+/// The name contigs are matched by: without a `chr` prefix, which can be in
+/// any case, and with the mitochondrial `M` as `MT`. The rest of the name
+/// has to match exactly.
+fn contig_key(chrom: &str) -> &str {
+    let key = match chrom.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("chr") && chrom.len() > 3 => &chrom[3..],
+        _ => chrom,
+    };
+    if key == "M" {
+        "MT"
+    } else {
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn parse() {
+        let regions = Regions::parse("1:1000-2000,1:1500-2500,2,3:7,4:100-").unwrap();
+        assert_eq!(regions.intervals("1"), [(1000, 2500)]);
+        assert_eq!(regions.intervals("2"), [(1, u64::MAX)]);
+        assert_eq!(regions.intervals("3"), [(7, 7)]);
+        assert_eq!(regions.intervals("4"), [(100, u64::MAX)]);
+        assert!(regions.intervals("5").is_empty());
+        // adjacent intervals are merged too
+        let regions = Regions::parse("1:1-10,1:30-40,1:11-20").unwrap();
+        assert_eq!(regions.intervals("1"), [(1, 20), (30, 40)]);
+        for invalid in ["", ":5", "1:0", "1:x", "1:20-10", "1:-10", "1:5,"] {
+            assert!(Regions::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn contigs_with_colons() {
+        let regions = Regions::parse("HLA-A*01:01:01:01").unwrap();
+        assert!(regions.contains("HLA-A*01:01:01:01", 1234));
+        // which can't be told apart from position 1 of another contig
+        assert!(regions.contains("HLA-A*01:01:01", 1));
+        assert!(!regions.contains("HLA-A*01:01:01", 2));
+
+        let regions = Regions::parse("HLA-A*01:01:01:01:100-200").unwrap();
+        assert!(regions.contains("HLA-A*01:01:01:01", 100));
+        assert!(!regions.contains("HLA-A*01:01:01:01", 99));
+    }
+
+    #[test]
+    fn chr_prefixes() {
+        let regions = Regions::parse("chr1:10-20,2:5,chrM:1-100,X").unwrap();
+        assert!(regions.contains("1", 10));
+        assert!(regions.contains("chr1", 20));
+        assert!(regions.contains("CHR1", 15));
+        assert!(regions.contains("chr2", 5));
+        for mitochondrial in ["M", "MT", "chrM", "chrMT"] {
+            assert!(regions.contains(mitochondrial, 50), "{}", mitochondrial);
+        }
+        assert!(regions.contains("chrX", 1));
+        // only the prefix can be in any case
+        assert!(!regions.contains("x", 1));
+        assert!(!regions.contains("chrx", 1));
+    }
+
+    #[test]
+    fn boundaries_are_inclusive() {
+        let regions = Regions::parse("1:100-200").unwrap();
+        let kept = [99, 100, 200, 201].map(|pos| regions.contains("1", pos));
+        assert_eq!(kept, [false, true, true, false]);
+    }
+
+    #[test]
+    fn from_file() {
+        let dir = std::env::temp_dir().join(format!("pgen-rs-regions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        // 0-based and half-open, skipping an empty interval
+        let bed = "track name=genes\nbrowser position 1:1-2\n# comment\n\
+                   1\t99\t200\tgene\n1\t300\t300\nchr2\t0\t1\n";
+        fs::write(path("regions.bed"), bed).unwrap();
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(bed.as_bytes()).unwrap();
+        fs::write(path("regions.bed.gz"), gz.finish().unwrap()).unwrap();
+        for name in ["regions.bed", "regions.bed.gz"] {
+            let regions = Regions::from_file(&path(name)).unwrap();
+            assert_eq!(regions.intervals("1"), [(100, 200)], "{}", name);
+            assert_eq!(regions.intervals("2"), [(1, 1)], "{}", name);
+        }
+
+        // 1-based and inclusive, with a position or a range per line
+        fs::write(
+            path("regions.txt"),
+            "#CHROM\tPOS\n1\t150\n1\t300\t400\n\n2\t7\n",
+        )
+        .unwrap();
+        let regions = Regions::from_file(&path("regions.txt")).unwrap();
+        assert_eq!(regions.intervals("1"), [(150, 150), (300, 400)]);
+        assert_eq!(regions.intervals("2"), [(7, 7)]);
+
+        for (name, contents) in [
+            ("short.bed", "1\t10\n"),
+            ("backwards.bed", "1\t10\t5\n"),
+            ("position.txt", "1\tx\n"),
+            ("short.txt", "1\n"),
+        ] {
+            fs::write(path(name), contents).unwrap();
+            assert!(
+                matches!(
+                    Regions::from_file(&path(name)),
+                    Err(PgenError::InvalidInput { .. })
+                ),
+                "{}",
+                name
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -35,8 +35,8 @@ document.addEventListener('DOMContentLoaded', () => {
 
   submitQueryButton.addEventListener('click', () => {
     let queryText = "";
-    const vformatStr = 'CHROM + " " + POS';
-    const sformatStr = 'IID + " " + SEX';
+    const vformatStr = '%CHROM %POS\\n';
+    const sformatStr = '%IID %SEX\\n';
     if (sampleQueryCheckbox.checked && sampleQueryInput.value) {
       // if the user formats the string then we assume they know what they're doing and don't add any quotes etc. 
       if (sampleQueryInput.value.includes('-f')) {
@@ -0,0 +1,504 @@
+//! Tabix (.tbi) and CSI indexes of a .vcf.gz, in the layouts of htslib.
+//!
+//! Both split each chromosome into a hierarchy of bins: bin 0 spans the
+//! whole chromosome, and each bin has 8 children spanning an eighth of it,
+//! down to bins of 2^14 positions. A record goes in the smallest bin that
+//! contains it, and each bin lists the chunks of the file (as BGZF virtual
+//! offsets) holding its records. A .tbi also keeps a linear index with the
+//! first record overlapping each 2^14 window, which a .csi instead keeps per
+//! bin.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::bgzf::BgzfWriter;
+use crate::pfile::PgenError;
+
+/// The kinds of index that can accompany a .vcf.gz.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// A tabix index, for positions below 2^29.
+    Tbi,
+    /// A CSI index, for larger positions.
+    Csi,
+}
+
+impl IndexFormat {
+    /// The extension appended to the .vcf.gz path.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            IndexFormat::Tbi => "tbi",
+            IndexFormat::Csi => "csi",
+        }
+    }
+
+    /// Number of levels of bins below bin 0.
+    fn depth(&self) -> u32 {
+        match self {
+            IndexFormat::Tbi => 5,
+            // htslib's default for indexing VCFs with CSI
+            IndexFormat::Csi => 6,
+        }
+    }
+}
+
+/// log2 of the span of the smallest bins and of the linear index windows.
+const MIN_SHIFT: u32 = 14;
+
+/// The index of one chromosome.
+struct RefIndex {
+    name: String,
+    /// The chunks of each bin, as (start, end) virtual offsets.
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    /// Virtual offset of the first record overlapping each window, u64::MAX
+    /// if there is none.
+    linear: Vec<u64>,
+    /// Virtual offsets of the start of the first record and the end of the
+    /// last one.
+    offsets: (u64, u64),
+    num_records: u64,
+}
+
+/// Builds an index from the records of a .vcf.gz as they are written, which
+/// must be sorted by position within each chromosome, with each chromosome's
+/// records together.
+pub struct IndexBuilder {
+    format: IndexFormat,
+    refs: Vec<RefIndex>,
+    last_start: u64,
+}
+
+impl IndexBuilder {
+    pub fn new(format: IndexFormat) -> IndexBuilder {
+        IndexBuilder {
+            format,
+            refs: Vec::new(),
+            last_start: 0,
+        }
+    }
+
+    /// Adds a record spanning positions [start, end) (0-based) whose line
+    /// spans virtual offsets [line_start, line_end).
+    pub fn push(
+        &mut self,
+        chrom: &str,
+        start: u64,
+        end: u64,
+        line_start: u64,
+        line_end: u64,
+    ) -> Result<(), PgenError> {
+        let max_end = 1u64 << (MIN_SHIFT + 3 * self.format.depth());
+        if end > max_end {
+            return Err(PgenError::Unindexable(format!(
+                "position {} on {} is too large for a .{} index",
+                end,
+                chrom,
+                self.format.extension()
+            )));
+        }
+        if self.refs.last().is_none_or(|ref_index| ref_index.name != chrom) {
+            if self.refs.iter().any(|ref_index| ref_index.name == chrom) {
+                return Err(PgenError::Unindexable(format!(
+                    "the variants of {} are not all together",
+                    chrom
+                )));
+            }
+            self.refs.push(RefIndex {
+                name: chrom.to_string(),
+                bins: BTreeMap::new(),
+                linear: Vec::new(),
+                offsets: (line_start, line_end),
+                num_records: 0,
+            });
+        } else if start < self.last_start {
+            return Err(PgenError::Unindexable(format!(
+                "the variants of {} are not sorted by position",
+                chrom
+            )));
+        }
+        self.last_start = start;
+
+        let depth = self.format.depth();
+        let last_ref = self.refs.len() - 1;
+        let ref_index = &mut self.refs[last_ref];
+        let chunks = ref_index
+            .bins
+            .entry(region_to_bin(start, end, depth))
+            .or_default();
+        match chunks.last_mut() {
+            Some(chunk) if chunk.1 == line_start => chunk.1 = line_end,
+            _ => chunks.push((line_start, line_end)),
+        }
+
+        let last_window = (end.max(start + 1) - 1) as usize >> MIN_SHIFT;
+        if ref_index.linear.len() <= last_window {
+            ref_index.linear.resize(last_window + 1, u64::MAX);
+        }
+        for window in &mut ref_index.linear[start as usize >> MIN_SHIFT..=last_window] {
+            if *window == u64::MAX {
+                *window = line_start;
+            }
+        }
+        ref_index.offsets.1 = line_end;
+        ref_index.num_records += 1;
+        Ok(())
+    }
+
+    /// Writes the index, BGZF-compressed like htslib expects.
+    pub fn write(mut self, path: String) -> Result<(), PgenError> {
+        for ref_index in &mut self.refs {
+            // windows without records of their own start where the previous
+            // window does
+            let mut prev = 0;
+            for window in &mut ref_index.linear {
+                if *window == u64::MAX {
+                    *window = prev;
+                }
+                prev = *window;
+            }
+        }
+
+        let mut index = Vec::new();
+        match self.format {
+            IndexFormat::Tbi => {
+                index.extend_from_slice(b"TBI\x01");
+                index.extend_from_slice(&(self.refs.len() as i32).to_le_bytes());
+                self.write_tabix_conf(&mut index);
+                for ref_index in &self.refs {
+                    self.write_bins(&mut index, ref_index);
+                    index.extend_from_slice(&(ref_index.linear.len() as i32).to_le_bytes());
+                    for offset in &ref_index.linear {
+                        index.extend_from_slice(&offset.to_le_bytes());
+                    }
+                }
+            }
+            IndexFormat::Csi => {
+                let mut aux = Vec::new();
+                self.write_tabix_conf(&mut aux);
+                index.extend_from_slice(b"CSI\x01");
+                index.extend_from_slice(&(MIN_SHIFT as i32).to_le_bytes());
+                index.extend_from_slice(&(self.format.depth() as i32).to_le_bytes());
+                index.extend_from_slice(&(aux.len() as i32).to_le_bytes());
+                index.extend_from_slice(&aux);
+                index.extend_from_slice(&(self.refs.len() as i32).to_le_bytes());
+                for ref_index in &self.refs {
+                    self.write_bins(&mut index, ref_index);
+                }
+            }
+        }
+        // no records lack coordinates
+        index.extend_from_slice(&0u64.to_le_bytes());
+
+        let mut bgzf_writer = BgzfWriter::new(BufWriter::new(File::create(path)?));
+        bgzf_writer.write_all(&index)?;
+        bgzf_writer.finish()?;
+        Ok(())
+    }
+
+    /// Writes how to find the coordinates in the lines of a VCF, followed by
+    /// the chromosome names.
+    fn write_tabix_conf(&self, index: &mut Vec<u8>) {
+        // format (2 for VCF), the CHROM, POS and end columns (0 since the
+        // end comes from REF), the comment character and the lines to skip
+        for value in [2, 1, 2, 0, b'#' as i32, 0] {
+            index.extend_from_slice(&value.to_le_bytes());
+        }
+        let names = self
+            .refs
+            .iter()
+            .flat_map(|ref_index| ref_index.name.bytes().chain([0]))
+            .collect::<Vec<u8>>();
+        index.extend_from_slice(&(names.len() as i32).to_le_bytes());
+        index.extend_from_slice(&names);
+    }
+
+    /// Writes the bins of a chromosome, followed by htslib's pseudo-bin with
+    /// its span of the file and its number of records.
+    fn write_bins(&self, index: &mut Vec<u8>, ref_index: &RefIndex) {
+        let depth = self.format.depth();
+        index.extend_from_slice(&(ref_index.bins.len() as i32 + 1).to_le_bytes());
+        let write_bin = |index: &mut Vec<u8>, bin: u32, loffset: u64, chunks: &[(u64, u64)]| {
+            index.extend_from_slice(&bin.to_le_bytes());
+            if self.format == IndexFormat::Csi {
+                index.extend_from_slice(&loffset.to_le_bytes());
+            }
+            index.extend_from_slice(&(chunks.len() as i32).to_le_bytes());
+            for (start, end) in chunks {
+                index.extend_from_slice(&start.to_le_bytes());
+                index.extend_from_slice(&end.to_le_bytes());
+            }
+        };
+        for (&bin, chunks) in &ref_index.bins {
+            // the first record overlapping the start of the bin
+            let first_window = (bin_start(bin, depth) >> MIN_SHIFT) as usize;
+            let loffset = ref_index
+                .linear
+                .get(first_window)
+                .copied()
+                .unwrap_or(chunks[0].0);
+            write_bin(index, bin, loffset, chunks);
+        }
+        let pseudo_bin = first_bin_of_level(depth + 1) + 1;
+        write_bin(
+            index,
+            pseudo_bin,
+            0,
+            &[ref_index.offsets, (ref_index.num_records, 0)],
+        );
+    }
+}
+
+/// The first bin of a level, level 0 being the single bin spanning
+/// everything.
+fn first_bin_of_level(level: u32) -> u32 {
+    ((1 << (3 * level)) - 1) / 7
+}
+
+/// The smallest bin containing positions [start, end), like htslib's
+/// `hts_reg2bin`.
+fn region_to_bin(start: u64, end: u64, depth: u32) -> u32 {
+    let last = end.max(start + 1) - 1;
+    let mut shift = MIN_SHIFT;
+    for level in (1..=depth).rev() {
+        if start >> shift == last >> shift {
+            return first_bin_of_level(level) + (start >> shift) as u32;
+        }
+        shift += 3;
+    }
+    0
+}
+
+/// The first position spanned by a bin.
+fn bin_start(bin: u32, depth: u32) -> u64 {
+    let mut level = 0;
+    while level < depth && bin >= first_bin_of_level(level + 1) {
+        level += 1;
+    }
+    let offset = (bin - first_bin_of_level(level)) as u64;
+    offset << (MIN_SHIFT + 3 * (depth - level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    /// A decoded index: each chromosome's name, its bins with their loffset
+    /// (0 in a .tbi) and chunks, and its linear index (empty in a .csi).
+    type Decoded = Vec<(String, Vec<(u32, u64, Vec<(u64, u64)>)>, Vec<u64>)>;
+
+    fn take<'a>(rest: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (taken, after) = rest.split_at(len);
+        *rest = after;
+        taken
+    }
+
+    fn read_i32(rest: &mut &[u8]) -> i32 {
+        i32::from_le_bytes(take(rest, 4).try_into().unwrap())
+    }
+
+    fn read_u64(rest: &mut &[u8]) -> u64 {
+        u64::from_le_bytes(take(rest, 8).try_into().unwrap())
+    }
+
+    /// Reads the tabix configuration of a VCF and the chromosome names.
+    fn read_conf(rest: &mut &[u8]) -> Vec<String> {
+        for value in [2, 1, 2, 0, b'#' as i32, 0] {
+            assert_eq!(read_i32(rest), value);
+        }
+        let names_len = read_i32(rest) as usize;
+        let names = take(rest, names_len).strip_suffix(&[0]).unwrap();
+        names
+            .split(|&byte| byte == 0)
+            .map(|name| String::from_utf8(name.to_vec()).unwrap())
+            .collect()
+    }
+
+    fn decode(path: &str, format: IndexFormat) -> Decoded {
+        let mut bytes = Vec::new();
+        MultiGzDecoder::new(File::open(path).unwrap())
+            .read_to_end(&mut bytes)
+            .unwrap();
+        let mut rest = &bytes[..];
+        let names = match format {
+            IndexFormat::Tbi => {
+                assert_eq!(take(&mut rest, 4), b"TBI\x01");
+                let num_refs = read_i32(&mut rest);
+                let names = read_conf(&mut rest);
+                assert_eq!(names.len(), num_refs as usize);
+                names
+            }
+            IndexFormat::Csi => {
+                assert_eq!(take(&mut rest, 4), b"CSI\x01");
+                assert_eq!(read_i32(&mut rest), 14);
+                assert_eq!(read_i32(&mut rest), 6);
+                let aux_len = read_i32(&mut rest) as usize;
+                let mut aux = take(&mut rest, aux_len);
+                let names = read_conf(&mut aux);
+                assert!(aux.is_empty());
+                assert_eq!(read_i32(&mut rest), names.len() as i32);
+                names
+            }
+        };
+        let mut decoded = Vec::new();
+        for name in names {
+            let mut bins = Vec::new();
+            for _ in 0..read_i32(&mut rest) {
+                let bin = u32::from_le_bytes(take(&mut rest, 4).try_into().unwrap());
+                let loffset = match format {
+                    IndexFormat::Tbi => 0,
+                    IndexFormat::Csi => read_u64(&mut rest),
+                };
+                let chunks = (0..read_i32(&mut rest))
+                    .map(|_| (read_u64(&mut rest), read_u64(&mut rest)))
+                    .collect();
+                bins.push((bin, loffset, chunks));
+            }
+            let linear = match format {
+                IndexFormat::Tbi => (0..read_i32(&mut rest))
+                    .map(|_| read_u64(&mut rest))
+                    .collect(),
+                IndexFormat::Csi => Vec::new(),
+            };
+            decoded.push((name, bins, linear));
+        }
+        assert_eq!(read_u64(&mut rest), 0);
+        assert!(rest.is_empty());
+        decoded
+    }
+
+    /// Indexes records of 2 chromosomes, the first with empty windows
+    /// between its records, and the second with two records in a single
+    /// chunk.
+    fn build(format: IndexFormat, path: &str) {
+        let mut builder = IndexBuilder::new(format);
+        builder.push("1", 0, 1, 0, 10).unwrap();
+        builder.push("1", 20000, 20001, 10, 20).unwrap();
+        builder.push("1", 70000, 70001, 20, 30).unwrap();
+        builder.push("2", 100, 200, 30, 40).unwrap();
+        builder.push("2", 150, 151, 40, 50).unwrap();
+        builder.write(path.to_string()).unwrap();
+    }
+
+    #[test]
+    fn bins() {
+        assert_eq!(region_to_bin(0, 1, 5), 4681);
+        assert_eq!(region_to_bin(0, 0, 5), 4681);
+        assert_eq!(region_to_bin(100000, 100001, 5), 4687);
+        assert_eq!(region_to_bin(16383, 16385, 5), 585);
+        assert_eq!(region_to_bin(0, 1 << 26, 5), 1);
+        assert_eq!(region_to_bin(0, (1 << 26) + 1, 5), 0);
+        assert_eq!(region_to_bin(0, 1, 6), 37449);
+        assert_eq!(region_to_bin(1 << 29, (1 << 29) + 1, 6), 37449 + (1 << 15));
+
+        for (bin, start) in [
+            (0, 0),
+            (1, 0),
+            (2, 1 << 26),
+            (585, 0),
+            (586, 1 << 17),
+            (4687, 98304),
+        ] {
+            assert_eq!(bin_start(bin, 5), start);
+        }
+        assert_eq!(bin_start(37449 + 3, 6), 3 << 14);
+        // each bin contains its start
+        for pos in [0, 16383, 16384, 100000, (1 << 29) - 1] {
+            for depth in [5, 6] {
+                let bin = region_to_bin(pos, pos + 1, depth);
+                assert_eq!(bin_start(bin, depth), pos >> 14 << 14);
+            }
+        }
+    }
+
+    #[test]
+    fn tbi() {
+        let path = std::env::temp_dir()
+            .join(format!("pgen-rs-tabix-{}.tbi", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        build(IndexFormat::Tbi, &path);
+        let expected: Decoded = vec![
+            (
+                "1".to_string(),
+                vec![
+                    (4681, 0, vec![(0, 10)]),
+                    (4682, 0, vec![(10, 20)]),
+                    (4685, 0, vec![(20, 30)]),
+                    (37450, 0, vec![(0, 30), (3, 0)]),
+                ],
+                // the empty windows 2 and 3 start where window 1 does
+                vec![0, 10, 10, 10, 20],
+            ),
+            (
+                "2".to_string(),
+                vec![
+                    (4681, 0, vec![(30, 50)]),
+                    (37450, 0, vec![(30, 50), (2, 0)]),
+                ],
+                vec![30],
+            ),
+        ];
+        assert_eq!(decode(&path, IndexFormat::Tbi), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn csi() {
+        let path = std::env::temp_dir()
+            .join(format!("pgen-rs-tabix-{}.csi", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        build(IndexFormat::Csi, &path);
+        let expected: Decoded = vec![
+            (
+                "1".to_string(),
+                vec![
+                    (37449, 0, vec![(0, 10)]),
+                    (37450, 10, vec![(10, 20)]),
+                    (37453, 20, vec![(20, 30)]),
+                    (299594, 0, vec![(0, 30), (3, 0)]),
+                ],
+                vec![],
+            ),
+            (
+                "2".to_string(),
+                vec![
+                    (37449, 30, vec![(30, 50)]),
+                    (299594, 0, vec![(30, 50), (2, 0)]),
+                ],
+                vec![],
+            ),
+        ];
+        assert_eq!(decode(&path, IndexFormat::Csi), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unindexable_records() {
+        let unindexable = |records: &[(&str, u64)], format| {
+            let mut builder = IndexBuilder::new(format);
+            let mut result = Ok(());
+            for (rcd_idx, &(chrom, start)) in records.iter().enumerate() {
+                let offset = rcd_idx as u64 * 10;
+                result = result.and(builder.push(chrom, start, start + 1, offset, offset + 10));
+            }
+            matches!(result, Err(PgenError::Unindexable(_)))
+        };
+        assert!(!unindexable(
+            &[("1", 5), ("1", 5), ("2", 1)],
+            IndexFormat::Tbi
+        ));
+        assert!(unindexable(&[("1", 5), ("1", 4)], IndexFormat::Tbi));
+        assert!(unindexable(
+            &[("1", 5), ("2", 1), ("1", 6)],
+            IndexFormat::Tbi
+        ));
+        assert!(!unindexable(&[("1", (1 << 29) - 1)], IndexFormat::Tbi));
+        assert!(unindexable(&[("1", 1 << 29)], IndexFormat::Tbi));
+        assert!(!unindexable(&[("1", 1 << 29)], IndexFormat::Csi));
+        assert!(unindexable(&[("1", 1 << 32)], IndexFormat::Csi));
+    }
+}